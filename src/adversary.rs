@@ -0,0 +1,347 @@
+use async_trait::async_trait;
+use c_kzg::BYTES_PER_FIELD_ELEMENT;
+use discv5::{enr::NodeId, TalkRequest};
+use discv5_overlay::portalnet::types::messages::{Content, Message, Request, Response};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ssz::Decode;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+};
+
+use crate::{
+    content_key::{DASContentKey, SampleKey},
+    erasure::SAMPLES_PER_BLOB,
+    network::SimNetwork,
+    router::TalkRequestHandler,
+    sample::DASSample,
+};
+
+/*
+    Byzantine behaviour a DASNode can be switched to.  Honest nodes have no policy and behave as usual.
+
+    Withholding:
+        The node answers FindContent with "not found" (an empty ENR list) for some of the samples it
+        holds.  It still accepts Offers and answers everything else, so it looks healthy to its peers.
+            - Random:      `fraction` of all samples
+            - Blobs:       `fraction` of the samples of the listed (slot, blob index) blobs only
+            - Requesters:  `fraction` of all samples, but only towards the listed nodes
+        Which samples are withheld is a hash of the policy's seed and the content id, so a node
+        withholds the same samples from everyone it targets, every time.
+        Where it applies:
+            - Udp:      the node's router passes TalkReqs through GuardedHandler before the overlay
+            - Virtual:  the virtual network asks the node's VirtualOverlay before answering a FindContent
+
+    Corrupting:
+        The node's stores hand out `rate` of the samples they hold with one bit of the cell flipped.
+        The sample still decodes, with the right commitment and cell index, but fails the KZG check.
+        This happens in DASContentStore::get, so it reaches requesters over uTP and over the virtual
+        network alike, and towards everyone.  Picked the same way as withheld samples.
+*/
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum WithholdTarget {
+    Random,
+    Blobs { blobs: Vec<(u64, u64)> },
+    /// Node indices
+    Requesters { nodes: Vec<usize> },
+}
+
+impl Default for WithholdTarget {
+    fn default() -> Self {
+        WithholdTarget::Random
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WithholdPolicy {
+    pub fraction: f64,
+    /// None withholds from every blob
+    pub blobs: Option<HashSet<(u64, u64)>>,
+    /// None withholds from every requester
+    pub requesters: Option<HashSet<NodeId>>,
+    pub seed: u64,
+}
+
+impl WithholdPolicy {
+    pub fn withholds(&self, requester: &NodeId, raw_key: &[u8]) -> bool {
+        match DASContentKey::from_ssz_bytes(raw_key) {
+            Ok(DASContentKey::Cell(key)) => self.withholds_sample(requester, &key),
+            _ => false,
+        }
+    }
+
+    pub fn withholds_sample(&self, requester: &NodeId, key: &SampleKey) -> bool {
+        if matches!(&self.requesters, Some(requesters) if !requesters.contains(requester)) {
+            return false;
+        }
+        if matches!(&self.blobs, Some(blobs) if !blobs.contains(&(key.slot, key.blob_index))) {
+            return false;
+        }
+        picked(self.seed, &key.content_id(), self.fraction)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CorruptPolicy {
+    pub rate: f64,
+    pub seed: u64,
+}
+
+impl CorruptPolicy {
+    pub fn corrupts(&self, content_id: &[u8; 32]) -> bool {
+        picked(self.seed, content_id, self.rate)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AdversaryPolicy {
+    pub withhold: Option<WithholdPolicy>,
+    pub corrupt: Option<CorruptPolicy>,
+}
+
+impl AdversaryPolicy {
+    /// Whether `requester` gets a valid copy of the sample if this node holds it.
+    pub fn serves(&self, requester: &NodeId, key: &SampleKey) -> bool {
+        let withheld = matches!(&self.withhold, Some(withhold) if withhold.withholds_sample(requester, key));
+        let corrupted = matches!(&self.corrupt, Some(corrupt) if corrupt.corrupts(&key.content_id()));
+        !withheld && !corrupted
+    }
+}
+
+/// A node's current policy, shared by everything that serves content for it.
+#[derive(Clone, Default)]
+pub struct Adversary(Arc<RwLock<Option<AdversaryPolicy>>>);
+
+impl Adversary {
+    pub fn set(&self, policy: Option<AdversaryPolicy>) {
+        *self.0.write() = policy;
+    }
+
+    pub fn policy(&self) -> Option<AdversaryPolicy> {
+        self.0.read().clone()
+    }
+
+    pub fn is_honest(&self) -> bool {
+        self.0.read().is_none()
+    }
+
+    /// Whether a FindContent for `raw_key` from `requester` gets "not found" regardless of the store.
+    pub fn withholds(&self, requester: &NodeId, raw_key: &[u8]) -> bool {
+        match &*self.0.read() {
+            Some(AdversaryPolicy { withhold: Some(withhold), .. }) => withhold.withholds(requester, raw_key),
+            _ => false,
+        }
+    }
+}
+
+/// Applies the node's policy before handing a TalkReq to the overlay.
+pub struct GuardedHandler {
+    inner: Arc<dyn TalkRequestHandler>,
+    adversary: Adversary,
+}
+
+impl GuardedHandler {
+    pub fn new(inner: Arc<dyn TalkRequestHandler>, adversary: Adversary) -> Self {
+        Self { inner, adversary }
+    }
+}
+
+#[async_trait]
+impl TalkRequestHandler for GuardedHandler {
+    async fn handle(&self, request: &TalkRequest) -> anyhow::Result<Vec<u8>> {
+        if !self.adversary.is_honest() {
+            if let Ok(Message::Request(Request::FindContent(find))) = Message::try_from(request.body().to_vec()) {
+                if self.adversary.withholds(request.node_id(), &find.content_key) {
+                    return Ok(Message::from(Response::Content(Content::Enrs(Vec::new()))).into());
+                }
+            }
+        }
+        self.inner.handle(request).await
+    }
+}
+
+/// How many samples of a blob `requester` could actually get: held by some running node, on either
+/// overlay, that serves it to `requester` uncorrupted.  Every row of a 2D extended matrix counts.
+/// Below ExtensionMode::samples_needed, an "available" verdict from `requester` is a false one.
+pub fn servable(network: &SimNetwork, slot: u64, blob_index: u64, requester: usize) -> usize {
+    let requester = network.nodes[requester].overlay.local_enr().node_id();
+    let running = network.running();
+    let rows = network.commitments.rows(slot, blob_index).len() as u64;
+    (0..rows)
+        .flat_map(|row| (0..SAMPLES_PER_BLOB as u64).map(move |column| SampleKey::new(slot, blob_index, row, column)))
+        .filter(|key| {
+            let id = key.content_id();
+            running.iter().any(|&i| {
+                let node = &network.nodes[i];
+                let held = node.overlay.store.read().index().contains_key(&id) || node.secure_overlay.store.read().index().contains_key(&id);
+                held && node.adversary.policy().map_or(true, |policy| policy.serves(&requester, key))
+            })
+        })
+        .count()
+}
+
+/// Same length, commitment and cell index, with the lowest bit of the first field element flipped
+/// so the cell stays canonical.  Content that isn't a sample gets its last byte flipped.
+pub fn corrupt(mut content: Vec<u8>) -> Vec<u8> {
+    match DASSample::from_bytes(&content) {
+        Ok(mut sample) => {
+            sample.cell[BYTES_PER_FIELD_ELEMENT - 1] ^= 1;
+            sample.to_bytes()
+        }
+        Err(_) => {
+            if let Some(last) = content.last_mut() {
+                *last ^= 1;
+            }
+            content
+        }
+    }
+}
+
+/// Deterministically picks `fraction` of all content ids.
+fn picked(seed: u64, content_id: &[u8; 32], fraction: f64) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_be_bytes());
+    hasher.update(content_id);
+    let digest = hasher.finalize();
+    let point = u64::from_be_bytes(digest[..8].try_into().unwrap()) as f64 / u64::MAX as f64;
+    point < fraction
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn withhold(fraction: f64) -> WithholdPolicy {
+        WithholdPolicy {
+            fraction,
+            blobs: None,
+            requesters: None,
+            seed: 7,
+        }
+    }
+
+    fn keys(slot: u64, blob_index: u64) -> Vec<SampleKey> {
+        (0..SAMPLES_PER_BLOB as u64).map(|column| SampleKey::new(slot, blob_index, 0, column)).collect()
+    }
+
+    fn withheld(policy: &WithholdPolicy, requester: &NodeId, keys: &[SampleKey]) -> usize {
+        keys.iter().filter(|key| policy.withholds_sample(requester, key)).count()
+    }
+
+    #[test]
+    fn withholds_about_the_given_fraction() {
+        let requester = NodeId::random();
+        let keys = keys(1, 0);
+        assert_eq!(withheld(&withhold(0.0), &requester, &keys), 0);
+        assert_eq!(withheld(&withhold(1.0), &requester, &keys), keys.len());
+        let half = withheld(&withhold(0.5), &requester, &keys);
+        assert!(half > keys.len() / 4 && half < keys.len() * 3 / 4, "{} of {}", half, keys.len());
+    }
+
+    #[test]
+    fn everyone_is_refused_the_same_samples() {
+        let policy = withhold(0.5);
+        let (a, b) = (NodeId::random(), NodeId::random());
+        for key in keys(1, 0) {
+            assert_eq!(policy.withholds_sample(&a, &key), policy.withholds_sample(&b, &key));
+        }
+        // Another seed picks others
+        let other = WithholdPolicy { seed: 8, ..withhold(0.5) };
+        assert!(keys(1, 0).iter().any(|key| policy.withholds_sample(&a, key) != other.withholds_sample(&a, key)));
+    }
+
+    #[test]
+    fn targets_limit_who_and_what_is_withheld() {
+        let (target, bystander) = (NodeId::random(), NodeId::random());
+        let requesters = WithholdPolicy { requesters: Some(HashSet::from([target])), ..withhold(1.0) };
+        assert_eq!(withheld(&requesters, &target, &keys(1, 0)), SAMPLES_PER_BLOB);
+        assert_eq!(withheld(&requesters, &bystander, &keys(1, 0)), 0);
+
+        let blobs = WithholdPolicy { blobs: Some(HashSet::from([(1, 1)])), ..withhold(1.0) };
+        assert_eq!(withheld(&blobs, &target, &keys(1, 1)), SAMPLES_PER_BLOB);
+        assert_eq!(withheld(&blobs, &target, &keys(1, 0)), 0);
+        assert_eq!(withheld(&blobs, &target, &keys(2, 1)), 0);
+    }
+
+    #[test]
+    fn only_cell_keys_are_withheld() {
+        let policy = withhold(1.0);
+        let requester = NodeId::random();
+        let cell: Vec<u8> = DASContentKey::Cell(SampleKey::new(1, 0, 0, 3)).into();
+        let opaque: Vec<u8> = DASContentKey::Sample([7; 32]).into();
+        assert!(policy.withholds(&requester, &cell));
+        assert!(!policy.withholds(&requester, &opaque));
+        assert!(!policy.withholds(&requester, &[0xff]));
+    }
+
+    #[test]
+    fn honest_nodes_withhold_nothing() {
+        let adversary = Adversary::default();
+        let cell: Vec<u8> = DASContentKey::Cell(SampleKey::new(1, 0, 0, 3)).into();
+        assert!(adversary.is_honest());
+        assert!(!adversary.withholds(&NodeId::random(), &cell));
+
+        adversary.set(Some(AdversaryPolicy { withhold: Some(withhold(1.0)), ..Default::default() }));
+        assert!(!adversary.is_honest());
+        assert!(adversary.withholds(&NodeId::random(), &cell));
+    }
+
+    fn extended_samples() -> (c_kzg::KzgSettings, Vec<DASSample>) {
+        let kzg = crate::sample::load_trusted_setup(crate::sample::TRUSTED_SETUP_PATH).unwrap();
+        let blob = crate::erasure::blob_from_data(b"corrupt me").unwrap();
+        let samples = crate::erasure::extend_blob(&kzg, &blob, 1, 0).unwrap().samples;
+        (kzg, samples)
+    }
+
+    #[test]
+    fn corrupted_samples_decode_but_fail_verification() {
+        let (kzg, samples) = extended_samples();
+        let sample = &samples[3];
+        let corrupted = DASSample::from_bytes(&corrupt(sample.to_bytes())).unwrap();
+
+        assert_eq!(corrupted.commitment, sample.commitment);
+        assert_eq!(corrupted.cell_index, sample.cell_index);
+        assert_ne!(corrupted.cell, sample.cell);
+        sample.verify(&kzg).unwrap();
+        assert!(corrupted.verify(&kzg).is_err());
+    }
+
+    #[test]
+    fn other_content_gets_its_last_byte_flipped() {
+        assert_eq!(corrupt(vec![1, 2, 3]), vec![1, 2, 2]);
+        assert_eq!(corrupt(Vec::new()), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn corrupting_stores_serve_bad_copies() {
+        use crate::storage::{DASContentStore, StoreConfig};
+        use discv5_overlay::portalnet::storage::ContentStore;
+
+        let (_, samples) = extended_samples();
+        let key = DASContentKey::Cell(SampleKey::new(1, 0, 0, 3));
+        let mut store = DASContentStore::new(&StoreConfig::default(), NodeId::random(), "DAS").unwrap();
+        store.put(key.clone(), samples[3].to_bytes()).unwrap();
+
+        store.serve_corrupted(Some(CorruptPolicy { rate: 1.0, seed: 7 }));
+        assert_eq!(store.get(&key).unwrap(), Some(corrupt(samples[3].to_bytes())));
+        store.serve_corrupted(Some(CorruptPolicy { rate: 0.0, seed: 7 }));
+        assert_eq!(store.get(&key).unwrap(), Some(samples[3].to_bytes()));
+    }
+
+    #[test]
+    fn corrupted_or_withheld_samples_are_not_served() {
+        let requester = NodeId::random();
+        let key = SampleKey::new(1, 0, 0, 3);
+        assert!(AdversaryPolicy::default().serves(&requester, &key));
+
+        let corrupting = AdversaryPolicy { corrupt: Some(CorruptPolicy { rate: 1.0, seed: 7 }), ..Default::default() };
+        assert!(!corrupting.serves(&requester, &key));
+        let withholding = AdversaryPolicy { withhold: Some(withhold(1.0)), ..Default::default() };
+        assert!(!withholding.serves(&requester, &key));
+    }
+}
@@ -0,0 +1,327 @@
+use rand::{seq::SliceRandom, Rng};
+use rand_distr::{Distribution, Exp};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    content_key::SampleKey,
+    erasure::SAMPLES_PER_BLOB,
+    network::{RoutingTableStats, SimNetwork},
+    node_struct::DASNode,
+    sampling::{SamplingConfig, Verdict},
+};
+
+/*
+    Nodes leaving and joining during a run.
+
+    Each churning node alternates between online sessions and downtime, both exponentially
+    distributed.  A node's rejoins are then a Poisson process with rate 1 / mean_downtime, and so are
+    all rejoins together.  The whole schedule is drawn up front from the run's rng.
+
+        - Leave:  the node's runtime is stopped (SimNetwork::stop_node)
+        - Join:   the node comes back with the same node id (SimNetwork::restart_node)
+
+    Around every event the driver measures availability twice: right before the event, and again
+    `settle_ms` after it.  A measurement is
+        - the share of published samples some running node still holds, on each overlay
+        - routing table sizes across running nodes
+        - optionally a sampling run on the latest blob, from a random running prober (an honest node)
+
+    Only on the virtual transport.  Over UDP a stopped node's discv5 server keeps answering, since
+    the Discovery is shared and can't be shut down, so a node that "left" would still be discoverable.
+*/
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChurnConfig {
+    pub mean_session_secs: f64,
+    pub mean_downtime_secs: f64,
+    /// Share of the eligible nodes that churn at all
+    pub fraction: f64,
+    /// Wait between an event and the measurement after it
+    pub settle_ms: u64,
+    /// Sample the latest blob around every event
+    pub probe: bool,
+}
+
+impl Default for ChurnConfig {
+    fn default() -> Self {
+        Self {
+            mean_session_secs: 120.0,
+            mean_downtime_secs: 60.0,
+            fraction: 1.0,
+            settle_ms: 1000,
+            probe: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChurnKind {
+    Leave,
+    Join,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChurnEvent {
+    /// Since the start of the run
+    pub at: Duration,
+    pub node: usize,
+    pub kind: ChurnKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Probe {
+    pub node: usize,
+    pub slot: u64,
+    pub blob_index: u64,
+    pub verdict: Verdict,
+    pub failed_lookups: usize,
+    pub rescued_by_secure_overlay: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Availability {
+    pub online: usize,
+    /// Share of published samples held by at least one running node
+    pub das_held: f64,
+    pub secure_das_held: f64,
+    pub routing_tables: RoutingTableStats,
+    pub probe: Option<Probe>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChurnRecord {
+    pub at_ms: u64,
+    pub node: usize,
+    pub kind: ChurnKind,
+    pub before: Availability,
+    pub after: Availability,
+}
+
+/// Draws a leave/join schedule for `eligible` nodes over `duration`.  Every node starts online.
+pub fn schedule<R: Rng>(eligible: &[usize], duration: Duration, config: &ChurnConfig, rng: &mut R) -> Vec<ChurnEvent> {
+    let (session, downtime) = match (Exp::new(1.0 / config.mean_session_secs), Exp::new(1.0 / config.mean_downtime_secs)) {
+        (Ok(session), Ok(downtime)) => (session, downtime),
+        _ => return Vec::new(),
+    };
+    let churning = (eligible.len() as f64 * config.fraction.clamp(0.0, 1.0)).round() as usize;
+
+    let mut events = Vec::new();
+    for &node in eligible.choose_multiple(rng, churning) {
+        let mut at = 0.0;
+        let mut online = true;
+        loop {
+            at += if online { session.sample(rng) } else { downtime.sample(rng) };
+            if at >= duration.as_secs_f64() {
+                break;
+            }
+            events.push(ChurnEvent {
+                at: Duration::from_secs_f64(at),
+                node,
+                kind: if online { ChurnKind::Leave } else { ChurnKind::Join },
+            });
+            online = !online;
+        }
+    }
+
+    events.sort_by(|a, b| a.at.cmp(&b.at).then(a.node.cmp(&b.node)));
+    events
+}
+
+/// Share of the samples of `blobs` held by some running node among `nodes`, on the DAS and SecureDAS overlays.
+/// Every row of a 2D extended matrix counts.
+pub fn held(network: &SimNetwork, nodes: &[usize], blobs: &[(u64, u64)]) -> (f64, f64) {
+    let ids: Vec<[u8; 32]> = blobs
+        .iter()
+        .flat_map(|&(slot, blob_index)| {
+            let rows = network.commitments.rows(slot, blob_index).len() as u64;
+            (0..rows).flat_map(move |row| {
+                (0..SAMPLES_PER_BLOB as u64).map(move |column| SampleKey::new(slot, blob_index, row, column).content_id())
+            })
+        })
+        .collect();
+    if ids.is_empty() {
+        return (1.0, 1.0);
+    }
+
+    let running: Vec<usize> = nodes.iter().copied().filter(|&i| network.is_running(i)).collect();
+    let share = |held: &dyn Fn(&DASNode, &[u8; 32]) -> bool| {
+        let found = ids
+            .iter()
+            .filter(|id| running.iter().any(|&i| held(&network.nodes[i], id)))
+            .count();
+        found as f64 / ids.len() as f64
+    };
+
+    (
+        share(&|node, id| node.overlay.store.read().index().contains_key(id)),
+        share(&|node, id| node.secure_overlay.store.read().index().contains_key(id)),
+    )
+}
+
+/// Measures availability of `blobs` right now.  The probe samples the last blob from one of the running `probers`.
+pub async fn measure<R: Rng>(
+    network: &SimNetwork,
+    blobs: &[(u64, u64)],
+    probe: bool,
+    probers: &[usize],
+    sampling_config: &SamplingConfig,
+    rng: &mut R,
+) -> Availability {
+    let running = network.running();
+    let (das_held, secure_das_held) = held(network, &running, blobs);
+    let candidates: Vec<usize> = probers.iter().copied().filter(|&i| network.is_running(i)).collect();
+
+    let probe = match (probe, blobs.last(), candidates.choose(rng)) {
+        (true, Some(&(slot, blob_index)), Some(&node)) => {
+            let indices = network.nodes[node].choose_samples(slot, blob_index, sampling_config, rng);
+            let result = network.nodes[node].sample_blob_at(slot, blob_index, &indices, sampling_config).await;
+            Some(Probe {
+                node,
+                slot,
+                blob_index,
+                failed_lookups: result.failed().len(),
+                rescued_by_secure_overlay: result.rescued_by_secure_overlay(),
+                verdict: result.verdict,
+            })
+        }
+        _ => None,
+    };
+
+    Availability {
+        online: running.len(),
+        das_held,
+        secure_das_held,
+        routing_tables: network.routing_tables(),
+        probe,
+    }
+}
+
+pub struct ChurnDriver {
+    config: ChurnConfig,
+    start: Instant,
+    events: VecDeque<ChurnEvent>,
+    /// Nodes the probe may sample from.  Never adversaries
+    probers: Vec<usize>,
+}
+
+impl ChurnDriver {
+    pub fn new(config: ChurnConfig, start: Instant, events: Vec<ChurnEvent>, probers: Vec<usize>) -> Self {
+        Self {
+            config,
+            start,
+            events: events.into(),
+            probers,
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Applies every event due before `until`, measuring around each one.
+    pub async fn run_until<R: Rng>(
+        &mut self,
+        network: &mut SimNetwork,
+        until: Instant,
+        blobs: &[(u64, u64)],
+        sampling_config: &SamplingConfig,
+        rng: &mut R,
+    ) -> Vec<ChurnRecord> {
+        let mut records = Vec::new();
+
+        while let Some(event) = self.events.front().copied() {
+            let due = self.start + event.at;
+            if due >= until {
+                break;
+            }
+            self.events.pop_front();
+            tokio::time::sleep_until(due.into()).await;
+
+            let before = measure(network, blobs, self.config.probe, &self.probers, sampling_config, rng).await;
+            match event.kind {
+                ChurnKind::Leave => network.stop_node(event.node).await,
+                ChurnKind::Join => network.restart_node(event.node).await,
+            }
+            tokio::time::sleep(Duration::from_millis(self.config.settle_ms)).await;
+            let after = measure(network, blobs, self.config.probe, &self.probers, sampling_config, rng).await;
+
+            println!("Churn: node {} {:?}, {} -> {} online", event.node, event.kind, before.online, after.online);
+            records.push(ChurnRecord {
+                at_ms: event.at.as_millis() as u64,
+                node: event.node,
+                kind: event.kind,
+                before,
+                after,
+            });
+        }
+
+        records
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::collections::{BTreeSet, HashMap};
+
+    fn config(fraction: f64) -> ChurnConfig {
+        ChurnConfig {
+            mean_session_secs: 10.0,
+            mean_downtime_secs: 5.0,
+            fraction,
+            ..Default::default()
+        }
+    }
+
+    fn draw(eligible: &[usize], fraction: f64, seed: u64) -> Vec<ChurnEvent> {
+        schedule(eligible, Duration::from_secs(300), &config(fraction), &mut StdRng::seed_from_u64(seed))
+    }
+
+    #[test]
+    fn events_are_in_order_and_within_the_run() {
+        let events = draw(&[1, 2, 3, 4], 1.0, 1);
+        assert!(!events.is_empty());
+        assert!(events.windows(2).all(|pair| pair[0].at <= pair[1].at));
+        assert!(events.iter().all(|event| event.at < Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn every_node_alternates_starting_with_a_leave() {
+        let mut online: HashMap<usize, bool> = HashMap::new();
+        for event in draw(&[1, 2, 3, 4], 1.0, 2) {
+            let was_online = online.entry(event.node).or_insert(true);
+            assert_eq!(event.kind, if *was_online { ChurnKind::Leave } else { ChurnKind::Join });
+            *was_online = !*was_online;
+        }
+    }
+
+    #[test]
+    fn only_eligible_nodes_churn() {
+        let events = draw(&[3, 5, 7, 9], 0.5, 3);
+        let churning: BTreeSet<usize> = events.iter().map(|event| event.node).collect();
+        assert_eq!(churning.len(), 2);
+        assert!(churning.iter().all(|node| [3, 5, 7, 9].contains(node)));
+
+        assert!(draw(&[3, 5, 7, 9], 0.0, 3).is_empty());
+        assert!(draw(&[], 1.0, 3).is_empty());
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_schedule() {
+        assert_eq!(draw(&[1, 2, 3], 1.0, 4), draw(&[1, 2, 3], 1.0, 4));
+    }
+
+    #[test]
+    fn unusable_means_give_no_churn() {
+        let config = ChurnConfig { mean_session_secs: -1.0, ..config(1.0) };
+        assert!(schedule(&[1, 2], Duration::from_secs(300), &config, &mut StdRng::seed_from_u64(1)).is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+use clap::{Parser, ValueEnum};
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    discovery,
+    links::{Latency, LinkModel, LinkProfile},
+    pruning::{PruningConfig, DEFAULT_RETENTION_EPOCHS, SECONDS_PER_SLOT, SLOTS_PER_EPOCH},
+    storage::{StorageBackend, StorageConfig, StoreConfig},
+    topology::Topology,
+    transport::{TransportConfig, VirtualConfig},
+};
+
+/*
+    Command-line configuration for the simulation binary, so runs at different scales
+    don't need a recompile.
+
+        cargo run -- --nodes 50 --peers 5 --base-port 10000 --seed 7 --scenario sampling
+        cargo run -- --nodes 10000 --transport virtual --latency 80 --jitter 20 --loss 0.01
+
+    Per region latencies need a scenario file, see links.rs.  --latency, --jitter and --loss apply to
+    either transport.  Without any of them the virtual transport defaults to 50 ms links and UDP
+    leaves messages to loopback.
+
+    A run described by a scenario file (see scenario.rs) ignores every other flag but --results and --seed.
+
+    Every random choice (node keys, routing table peers, samplers, sample indices) comes from one
+    StdRng seeded with --seed.  The seed is printed at startup, so a failing run can be repeated exactly.
+*/
+
+#[derive(Clone, Debug, Parser)]
+#[command(name = "das_playground", about = "Simulates DAS and SecureDAS overlay networks on discv5")]
+pub struct Args {
+    /// Number of nodes in the simulation
+    #[arg(long, default_value_t = 10)]
+    pub nodes: usize,
+
+    /// Peers each node adds to its discv5 routing table at startup.  The degree for regular,
+    /// ring and small-world (even, half on each side for the last two) and the in-cluster peers
+    /// for clustered
+    #[arg(long, default_value_t = 3)]
+    pub peers: usize,
+
+    /// How the discv5 routing tables are populated
+    #[arg(long, value_enum, default_value_t = TopologyKind::Random)]
+    pub topology: TopologyKind,
+
+    /// Small-world: probability each ring edge is rewired
+    #[arg(long, default_value_t = 0.1, value_parser = parse_probability)]
+    pub rewire: f64,
+
+    /// Clustered: number of clusters
+    #[arg(long, default_value_t = 4)]
+    pub clusters: usize,
+
+    /// Clustered: peers each node picks outside its cluster
+    #[arg(long, default_value_t = 1)]
+    pub inter_peers: usize,
+
+    /// Bootnode: number of bootnodes everyone knows
+    #[arg(long, default_value_t = 1)]
+    pub bootnodes: usize,
+
+    /// UDP port of node 0.  Node i listens on base_port + i
+    #[arg(long, default_value_t = 9000)]
+    pub base_port: u16,
+
+    /// Discv5 request and query timeout, in seconds
+    #[arg(long, default_value_t = 60)]
+    pub request_timeout: u64,
+
+    /// Timeout of a single sample lookup, in milliseconds
+    #[arg(long, default_value_t = 4000)]
+    pub lookup_timeout: u64,
+
+    /// Seed for every random choice the simulation makes.  Random if not given
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// What to run once the network is up
+    #[arg(long, value_enum, default_value_t = Scenario::Sampling)]
+    pub scenario: Scenario,
+
+    /// Udp binds a socket per node.  Virtual keeps all traffic in the process
+    #[arg(long, value_enum, default_value_t = TransportKind::Udp)]
+    pub transport: TransportKind,
+
+    /// Mean one way delay of every message, in milliseconds.  50 if not given on the virtual transport,
+    /// UDP is left to loopback unless one of --latency, --jitter or --loss is given
+    #[arg(long)]
+    pub latency: Option<u64>,
+
+    /// Standard deviation of the delay, in milliseconds
+    #[arg(long)]
+    pub jitter: Option<u64>,
+
+    /// Probability a message is lost
+    #[arg(long, value_parser = parse_probability)]
+    pub loss: Option<f64>,
+
+    /// Bytes of content each overlay store is sized for.  Its data radius shrinks as it fills.
+    /// Unlimited, with a radius covering the whole keyspace, if not given
+    #[arg(long)]
+    pub storage_capacity: Option<usize>,
+
+    /// Hard limit in bytes on each overlay store.  Content farthest from the node id is evicted past it.
+    /// Never evicts if not given
+    #[arg(long)]
+    pub max_storage_bytes: Option<usize>,
+
+    /// Keep every overlay store on disk under this directory instead of in memory
+    #[arg(long)]
+    pub storage_dir: Option<PathBuf>,
+
+    /// Samples are pruned once their slot is this many epochs old
+    #[arg(long, default_value_t = DEFAULT_RETENTION_EPOCHS)]
+    pub retention_epochs: u64,
+
+    /// Retention window in slots, for runs shorter than an epoch.  Overrides --retention-epochs
+    #[arg(long)]
+    pub retention_slots: Option<u64>,
+
+    /// How often stores are pruned, in seconds
+    #[arg(long, default_value_t = SECONDS_PER_SLOT * SLOTS_PER_EPOCH, value_parser = clap::value_parser!(u64).range(1..))]
+    pub prune_interval: u64,
+
+    /// Keep every sample forever
+    #[arg(long)]
+    pub no_pruning: bool,
+
+    /// TOML or JSON file describing a full simulation run
+    #[arg(long)]
+    pub scenario_file: Option<PathBuf>,
+
+    /// Where to write a scenario file's results.  Overrides the path in the file
+    #[arg(long)]
+    pub results: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Scenario {
+    /// Ping between two nodes on both overlays
+    Ping,
+    /// Ping, then publish a blob
+    Publish,
+    /// Ping, publish a blob, then sample it from another node
+    Sampling,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum TopologyKind {
+    Random,
+    Regular,
+    Ring,
+    SmallWorld,
+    Clustered,
+    Bootnode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum TransportKind {
+    Udp,
+    Virtual,
+}
+
+/// A float in [0, 1].  Rejects NaN too.
+fn parse_probability(value: &str) -> Result<f64, String> {
+    let p: f64 = value.parse().map_err(|err| format!("{}", err))?;
+    if !(0.0..=1.0).contains(&p) {
+        return Err(format!("{} is not a probability between 0 and 1", p));
+    }
+    Ok(p)
+}
+
+impl Args {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout)
+    }
+
+    pub fn lookup_timeout(&self) -> Duration {
+        Duration::from_millis(self.lookup_timeout)
+    }
+
+    /// Can't have more peers than other nodes
+    pub fn peers_per_node(&self) -> usize {
+        self.peers.min(self.nodes.saturating_sub(1))
+    }
+
+    pub fn topology(&self) -> Topology {
+        let peers = self.peers_per_node();
+        match self.topology {
+            TopologyKind::Random => Topology::Random { peers },
+            TopologyKind::Regular => Topology::RandomRegular { degree: peers },
+            TopologyKind::Ring => Topology::Ring { neighbors: peers },
+            TopologyKind::SmallWorld => Topology::SmallWorld { neighbors: peers, rewire: self.rewire },
+            TopologyKind::Clustered => Topology::Clustered {
+                clusters: self.clusters,
+                intra_peers: peers,
+                inter_peers: self.inter_peers,
+            },
+            TopologyKind::Bootnode => Topology::Bootnode { bootnodes: self.bootnodes },
+        }
+    }
+
+    /// The same store settings for both overlays.
+    pub fn storage(&self) -> StorageConfig {
+        let store = StoreConfig {
+            capacity: self.storage_capacity,
+            max_bytes: self.max_storage_bytes,
+            backend: match &self.storage_dir {
+                Some(dir) => StorageBackend::Disk(dir.clone()),
+                None => StorageBackend::Memory,
+            },
+        };
+        StorageConfig {
+            das: store.clone(),
+            secure_das: store,
+        }
+    }
+
+    pub fn pruning(&self) -> Option<PruningConfig> {
+        (!self.no_pruning).then(|| PruningConfig {
+            retention_epochs: self.retention_epochs,
+            retention_slots: self.retention_slots,
+            interval: Duration::from_secs(self.prune_interval),
+        })
+    }
+
+    /// Fails if link settings were given for a transport that can't apply them.
+    pub fn transport(&self) -> anyhow::Result<TransportConfig> {
+        match self.transport {
+            TransportKind::Udp => {
+                discovery::check_ports(self.base_port, self.nodes)?;
+                let shaped = self.latency.is_some() || self.jitter.is_some() || self.loss.is_some();
+                Ok(TransportConfig::Udp(shaped.then(|| self.links())))
+            }
+            TransportKind::Virtual => Ok(TransportConfig::Virtual(VirtualConfig {
+                links: self.links(),
+                timeout: self.request_timeout(),
+            })),
+        }
+    }
+
+    /// Every link the same: --latency (50 ms if not given), --jitter and --loss.
+    fn links(&self) -> LinkModel {
+        let mean = self.latency.unwrap_or(50);
+        let latency = match self.jitter.unwrap_or(0) {
+            0 => Latency::Constant { ms: mean },
+            jitter => Latency::Normal {
+                mean_ms: mean as f64,
+                jitter_ms: jitter as f64,
+            },
+        };
+        LinkModel {
+            default: LinkProfile::new(latency, self.loss.unwrap_or(0.0)),
+            ..Default::default()
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed.unwrap_or_else(rand::random)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("das_playground").chain(flags.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn udp_is_only_shaped_with_link_flags() {
+        assert!(matches!(args(&[]).transport(), Ok(TransportConfig::Udp(None))));
+        match args(&["--latency", "80", "--loss", "0.1"]).transport() {
+            Ok(TransportConfig::Udp(Some(links))) => {
+                assert_eq!(links.default, LinkProfile::new(Latency::Constant { ms: 80 }, 0.1));
+            }
+            other => panic!("Expected shaped UDP, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn virtual_links_default_to_50_ms() {
+        match args(&["--transport", "virtual", "--jitter", "10"]).transport() {
+            Ok(TransportConfig::Virtual(config)) => {
+                assert_eq!(config.links.default.latency, Latency::Normal { mean_ms: 50.0, jitter_ms: 10.0 });
+                assert_eq!(config.links.default.loss, 0.0);
+            }
+            other => panic!("Expected the virtual transport, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn udp_rejects_nodes_past_the_last_port() {
+        assert!(args(&["--base-port", "65000", "--nodes", "536"]).transport().is_ok());
+        assert!(args(&["--base-port", "65000", "--nodes", "537"]).transport().is_err());
+        // Virtual nodes never bind their port
+        assert!(args(&["--base-port", "65000", "--nodes", "1000", "--transport", "virtual"]).transport().is_ok());
+    }
+
+    #[test]
+    fn retention_slots_override_epochs() {
+        let pruning = args(&["--retention-epochs", "2"]).pruning().unwrap();
+        assert_eq!(pruning.retention_slots(), 2 * SLOTS_PER_EPOCH);
+        let pruning = args(&["--retention-epochs", "2", "--retention-slots", "5"]).pruning().unwrap();
+        assert_eq!(pruning.retention_slots(), 5);
+        assert!(args(&["--no-pruning"]).pruning().is_none());
+    }
+
+    #[test]
+    fn a_given_seed_is_used_as_is() {
+        assert_eq!(args(&["--seed", "42"]).seed(), 42);
+    }
+}
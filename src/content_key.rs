@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use c_kzg::KzgSettings;
 use discv5::ConnectionDirection;
 use discv5_overlay::{
     portalnet::types::content_key::OverlayContentKey,
@@ -7,9 +8,13 @@ use discv5_overlay::{
 use std::{
     fmt,
     fmt::Display,
+    sync::Arc,
 };
 use ssz::{Decode, Encode};
 use ssz_derive::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+use crate::sample::{self, BlobCommitments};
 
 
 
@@ -19,11 +24,79 @@ use ssz_derive::{Decode, Encode};
 // To Do:
 //      Consolidate logic
 
+/// Location of a sample within the extended data of a slot.
+/// For 1D extended blobs `row` is always 0 and `column` is the cell index.
+#[derive(Clone, Copy, Debug, Decode, Encode, Eq, Hash, PartialEq)]
+pub struct SampleKey {
+    pub slot: u64,
+    pub blob_index: u64,
+    pub row: u64,
+    pub column: u64,
+}
+
+impl SampleKey {
+    pub fn new(slot: u64, blob_index: u64, row: u64, column: u64) -> Self {
+        Self { slot, blob_index, row, column }
+    }
+
+    /// sha256 over the SSZ encoded coordinates.  Anyone can compute where a sample lives in the DHT.
+    pub fn content_id(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.as_ssz_bytes());
+        hasher.finalize().into()
+    }
+}
+
+impl Display for SampleKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slot: {}, blob: {}, row: {}, column: {}", self.slot, self.blob_index, self.row, self.column)
+    }
+}
+
+/// A whole row or column of a 2D extended matrix.  Whether it's a row or a column is the key variant.
+#[derive(Clone, Copy, Debug, Decode, Encode, Eq, Hash, PartialEq)]
+pub struct LineKey {
+    pub slot: u64,
+    pub blob_index: u64,
+    pub index: u64,
+}
+
+impl LineKey {
+    pub fn new(slot: u64, blob_index: u64, index: u64) -> Self {
+        Self { slot, blob_index, index }
+    }
+
+    // Rows and columns with the same coordinates must not share a content id
+    pub fn row_id(&self) -> [u8; 32] {
+        self.content_id(b"row")
+    }
+
+    pub fn column_id(&self) -> [u8; 32] {
+        self.content_id(b"column")
+    }
+
+    fn content_id(&self, domain: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(domain);
+        hasher.update(self.as_ssz_bytes());
+        hasher.finalize().into()
+    }
+}
+
+impl Display for LineKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slot: {}, blob: {}, index: {}", self.slot, self.blob_index, self.index)
+    }
+}
+
 /// This is a content key in the DAS overlay network.
 #[derive(Clone, Debug, Decode, Encode, PartialEq)]
 #[ssz(enum_behaviour = "union")]
 pub enum DASContentKey {
     Sample([u8; 32]),
+    Cell(SampleKey),
+    Row(LineKey),
+    Column(LineKey),
 }
 
 #[allow(clippy::from_over_into)]
@@ -51,6 +124,9 @@ impl Display for DASContentKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
             Self::Sample(b) => format!("sample: {}", hex::encode(b)),
+            Self::Cell(key) => format!("cell: {}", key),
+            Self::Row(key) => format!("row: {}", key),
+            Self::Column(key) => format!("column: {}", key),
         };
 
         write!(f, "{}", s)
@@ -61,11 +137,23 @@ impl OverlayContentKey for DASContentKey {
     fn content_id(&self) -> [u8; 32] {
         match self {
             DASContentKey::Sample(b) => b.clone(),
+            DASContentKey::Cell(key) => key.content_id(),
+            DASContentKey::Row(key) => key.row_id(),
+            DASContentKey::Column(key) => key.column_id(),
         }
     }
 }
 
-pub struct DASValidator;
+pub struct DASValidator {
+    pub kzg: Arc<KzgSettings>,
+    pub commitments: BlobCommitments,
+}
+
+impl DASValidator {
+    pub fn new(kzg: Arc<KzgSettings>, commitments: BlobCommitments) -> Self {
+        Self { kzg, commitments }
+    }
+}
 
 // Implementing a trait on a type
 #[async_trait]
@@ -79,7 +167,22 @@ impl Validator<DASContentKey> for DASValidator {
         //     DASContentKey: 'async_trait,
     {
         match content_key {
-            DASContentKey::Sample(_) => Ok(()),
+            DASContentKey::Sample(id) => {
+                sample::verify_sample(&self.kzg, id, content)?;
+                Ok(())
+            }
+            DASContentKey::Cell(key) => {
+                sample::verify_cell(&self.kzg, &self.commitments, key, content)?;
+                Ok(())
+            }
+            DASContentKey::Row(key) => {
+                sample::verify_row(&self.kzg, &self.commitments, key, content)?;
+                Ok(())
+            }
+            DASContentKey::Column(key) => {
+                sample::verify_column(&self.kzg, &self.commitments, key, content)?;
+                Ok(())
+            }
         }
     }
 }
@@ -93,6 +196,9 @@ impl Validator<DASContentKey> for DASValidator {
 #[ssz(enum_behaviour = "union")]
 pub enum SecureDASContentKey {
     Sample([u8; 32]),
+    Cell(SampleKey),
+    Row(LineKey),
+    Column(LineKey),
 }
 
 #[allow(clippy::from_over_into)]
@@ -120,6 +226,9 @@ impl Display for SecureDASContentKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
             Self::Sample(b) => format!("sample: {}", hex::encode(b)),
+            Self::Cell(key) => format!("cell: {}", key),
+            Self::Row(key) => format!("row: {}", key),
+            Self::Column(key) => format!("column: {}", key),
         };
 
         write!(f, "{}", s)
@@ -130,11 +239,23 @@ impl OverlayContentKey for SecureDASContentKey {
     fn content_id(&self) -> [u8; 32] {
         match self {
             SecureDASContentKey::Sample(b) => b.clone(),
+            SecureDASContentKey::Cell(key) => key.content_id(),
+            SecureDASContentKey::Row(key) => key.row_id(),
+            SecureDASContentKey::Column(key) => key.column_id(),
         }
     }
 }
 
-pub struct SecureDASValidator;
+pub struct SecureDASValidator {
+    pub kzg: Arc<KzgSettings>,
+    pub commitments: BlobCommitments,
+}
+
+impl SecureDASValidator {
+    pub fn new(kzg: Arc<KzgSettings>, commitments: BlobCommitments) -> Self {
+        Self { kzg, commitments }
+    }
+}
 
 #[async_trait]
 impl Validator<SecureDASContentKey> for SecureDASValidator {
@@ -147,7 +268,112 @@ impl Validator<SecureDASContentKey> for SecureDASValidator {
         //     SecureDASContentKey: 'async_trait,
     {
         match content_key {
-            SecureDASContentKey::Sample(_) => Ok(()),
+            SecureDASContentKey::Sample(id) => {
+                sample::verify_sample(&self.kzg, id, content)?;
+                Ok(())
+            }
+            SecureDASContentKey::Cell(key) => {
+                sample::verify_cell(&self.kzg, &self.commitments, key, content)?;
+                Ok(())
+            }
+            SecureDASContentKey::Row(key) => {
+                sample::verify_row(&self.kzg, &self.commitments, key, content)?;
+                Ok(())
+            }
+            SecureDASContentKey::Column(key) => {
+                sample::verify_column(&self.kzg, &self.commitments, key, content)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn das_keys() -> Vec<DASContentKey> {
+        vec![
+            DASContentKey::Sample([7; 32]),
+            DASContentKey::Cell(SampleKey::new(1, 2, 3, 4)),
+            DASContentKey::Row(LineKey::new(1, 2, 3)),
+            DASContentKey::Column(LineKey::new(1, 2, 3)),
+        ]
+    }
+
+    fn secure_das_keys() -> Vec<SecureDASContentKey> {
+        vec![
+            SecureDASContentKey::Sample([7; 32]),
+            SecureDASContentKey::Cell(SampleKey::new(1, 2, 3, 4)),
+            SecureDASContentKey::Row(LineKey::new(1, 2, 3)),
+            SecureDASContentKey::Column(LineKey::new(1, 2, 3)),
+        ]
+    }
+
+    #[test]
+    fn das_keys_round_trip() {
+        for key in das_keys() {
+            let bytes: Vec<u8> = key.clone().into();
+            assert_eq!(DASContentKey::try_from(bytes).unwrap(), key);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn secure_das_keys_round_trip() {
+        for key in secure_das_keys() {
+            let bytes: Vec<u8> = key.clone().into();
+            assert_eq!(SecureDASContentKey::try_from(bytes).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn union_selector_tells_variants_apart() {
+        // Row and Column carry the same LineKey.  Only the selector byte differs
+        let encoded: Vec<Vec<u8>> = das_keys().into_iter().map(Into::into).collect();
+        for (i, a) in encoded.iter().enumerate() {
+            assert_eq!(a[0], i as u8);
+            for b in encoded.iter().skip(i + 1) {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn both_overlays_encode_keys_the_same() {
+        for (das, secure) in das_keys().into_iter().zip(secure_das_keys()) {
+            let das: Vec<u8> = das.into();
+            let secure: Vec<u8> = secure.into();
+            assert_eq!(das, secure);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_selector() {
+        let mut bytes: Vec<u8> = DASContentKey::Row(LineKey::new(1, 2, 3)).into();
+        bytes[0] = 4;
+        assert!(DASContentKey::try_from(bytes.clone()).is_err());
+        assert!(SecureDASContentKey::try_from(bytes).is_err());
+    }
+
+    #[test]
+    fn row_and_column_content_ids_are_domain_separated() {
+        let line = LineKey::new(1, 2, 3);
+        let row = DASContentKey::Row(line).content_id();
+        let column = DASContentKey::Column(line).content_id();
+        assert_ne!(row, column);
+        assert_eq!(row, line.row_id());
+        assert_eq!(column, line.column_id());
+
+        // Same coordinates as a cell doesn't collide either
+        let cell = DASContentKey::Cell(SampleKey::new(1, 2, 3, 0)).content_id();
+        assert_ne!(row, cell);
+        assert_ne!(column, cell);
+    }
+
+    #[test]
+    fn content_ids_match_across_overlays() {
+        for (das, secure) in das_keys().into_iter().zip(secure_das_keys()) {
+            assert_eq!(das.content_id(), secure.content_id());
+        }
+    }
+}
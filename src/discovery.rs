@@ -1,12 +1,14 @@
+use anyhow::bail;
 use discv5::{
     Discv5,
     Discv5ConfigBuilder,
     Discv5Event, 
     enr,
     Enr, 
-    enr::CombinedKey,
+    enr::{CombinedKey, NodeId},
 };
 use discv5_overlay::portalnet::discovery::Discovery;
+use rand::Rng;
 use std::{
     net::Ipv4Addr,
     str::FromStr,
@@ -14,6 +16,8 @@ use std::{
     time::Duration,
 };
 
+use crate::overlay;
+
 /*
     The Node Discovery Protocol v5 (discv5) is the UDP-based p2p network that Ethereum Nodes use
     to establish network connections with other nodes.  It acts as a database of all live nodes
@@ -33,16 +37,29 @@ use std::{
 // Questions:
 //      Why does our discv5 struct have no table entries?
 
+// Node i listens on base_port + i.  Fails if the last of `nodes` ports is past 65535
+pub fn check_ports(base_port: u16, nodes: usize) -> anyhow::Result<()> {
+    if base_port as usize + nodes > 1 << 16 {
+        bail!("{} nodes starting at port {} run past port 65535", nodes, base_port);
+    }
+    Ok(())
+}
+
 // Creates discovery protocol struct + service for a node! 
-pub async fn create_discovery(i: u16) -> Arc<Discovery> {
+// `listen` false leaves the server unstarted (no socket), for the virtual transport
+pub async fn create_discovery(i: usize, base_port: u16, timeout: Duration, enr_key: CombinedKey, listen: bool) -> Arc<Discovery> {
     // UDP port to find peers  +  IP address to connect to peers to have its record relayed in the DHT
     // I believe this is a client-side (ephemeral) port 
-    let port_start = 9000 + i;
+    // Over UDP check_ports has made sure it fits.  Virtual nodes never bind theirs, it only fills the ENR
+    let port_start = match u16::try_from(base_port as usize + i) {
+        Ok(port) => port,
+        Err(_) if !listen => ((base_port as usize + i) % (1 << 16)) as u16,
+        Err(_) => panic!("Node {} has no port left after {}, see check_ports", i, base_port),
+    };
     let listen_ip = String::from("127.0.0.1").parse::<Ipv4Addr>().unwrap(); 
 
-    // Generates local node's random enr key and new enr.  *Base the secp256k1 on our node's public key*
+    // New enr from the node's key.  *Base the secp256k1 on our node's public key*
     // There's a lot to talk about wrt ENR things.  Create a summary here soon 
-    let enr_key = CombinedKey::generate_secp256k1();
     let enr = {
         let mut builder = enr::EnrBuilder::new("v4");
         builder.ip4(listen_ip);
@@ -54,8 +71,8 @@ pub async fn create_discovery(i: u16) -> Arc<Discovery> {
     let mut config_builder = Discv5ConfigBuilder::default();
     config_builder.request_retries(10);
     config_builder.filter_max_nodes_per_ip(None);
-    config_builder.request_timeout(Duration::from_secs(60));
-    config_builder.query_timeout(Duration::from_secs(60)); 
+    config_builder.request_timeout(timeout);
+    config_builder.query_timeout(timeout); 
     let config = config_builder.build();
    
     // Construct the discv5 server
@@ -64,12 +81,76 @@ pub async fn create_discovery(i: u16) -> Arc<Discovery> {
     let udp4 = discv5.local_enr().udp4().unwrap();
 
     // Initializes the discv5 service.  Starts the required tasks and begins listening on a given UDP SocketAddr
-    discv5.start(format!("{}:{}", ip4, udp4).parse().unwrap())
-        .await
-        .unwrap();
+    if listen {
+        discv5.start(format!("{}:{}", ip4, udp4).parse().unwrap())
+            .await
+            .unwrap();
+    }
    
     // Initializes our protocol struct 
     let discovery = Arc::new(Discovery::new_raw(discv5, Default::default())); 
      
     discovery
-}
\ No newline at end of file
+}
+
+// Derives a node's secp256k1 key from the simulation's rng, so the same seed gives the same node ids
+pub fn node_key<R: Rng>(rng: &mut R) -> CombinedKey {
+    loop {
+        let mut secret = [0u8; 32];
+        rng.fill(&mut secret);
+        // Only fails for the ~2^-128 of values that aren't a valid scalar.  Draw again
+        if let Ok(key) = CombinedKey::secp256k1_from_bytes(&mut secret) {
+            return key;
+        }
+    }
+}
+
+// Grinds node keys until the node id shares at least `prefix_bits` leading bits with `target`.
+// Takes 2^prefix_bits draws on average, so keep it well under 20
+pub fn node_key_near<R: Rng>(rng: &mut R, target: &NodeId, prefix_bits: u32) -> CombinedKey {
+    loop {
+        let key = node_key(rng);
+        let node_id = NodeId::from(key.public());
+        if overlay::log_distance(&node_id.raw(), &target.raw()).map_or(false, |d| d <= 256 - prefix_bits.min(256)) {
+            return key;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn ports_must_fit_in_a_u16() {
+        assert!(check_ports(9000, 10).is_ok());
+        assert!(check_ports(65535, 1).is_ok());
+        assert!(check_ports(65526, 10).is_ok());
+        assert!(check_ports(65526, 11).is_err());
+        assert!(check_ports(0, 70000).is_err());
+    }
+
+    fn node_ids(seed: u64, n: usize) -> Vec<NodeId> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        (0..n).map(|_| NodeId::from(node_key(&mut rng).public())).collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_node_ids() {
+        assert_eq!(node_ids(7, 5), node_ids(7, 5));
+        assert_ne!(node_ids(7, 5), node_ids(8, 5));
+    }
+
+    #[test]
+    fn ground_keys_share_the_prefix() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let target = NodeId::random();
+        for prefix_bits in [0, 4, 8] {
+            let node_id = NodeId::from(node_key_near(&mut rng, &target, prefix_bits).public());
+            let distance = overlay::log_distance(&node_id.raw(), &target.raw()).unwrap();
+            assert!(distance <= 256 - prefix_bits);
+        }
+    }
+}
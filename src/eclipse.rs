@@ -0,0 +1,307 @@
+use anyhow::bail;
+use discv5::enr::NodeId;
+use futures::future::join_all;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
+
+use crate::{
+    adversary::{AdversaryPolicy, WithholdPolicy},
+    discovery,
+    network::SimNetwork,
+    overlay,
+    sampling::{SamplingConfig, Verdict},
+};
+
+/*
+    Eclipse attack: a coalition of attackers tries to take over one victim's routing tables.
+
+    Every attacker grinds node keys until its node id shares `prefix_bits` leading bits with the
+    victim's, so it sits closer to the victim than any honest node is likely to.  The attackers join
+    the running network as extra nodes (indices from `nodes` on) and then flood the victim:
+        - add_enr puts each attacker in the victim's discv5 table, like answering its discv5 pings would
+        - `flood_rounds` rounds of pings from every attacker on both overlays, `flood_interval_ms` apart.
+          The victim's overlay services add whoever pings them to their routing tables
+    With `withhold`, attackers answer "not found" to everything the victim asks (see adversary.rs),
+    so a captured lookup fails instead of just being slow.
+
+    The victim's tables are measured right before the attack, then at the end of every slot:
+        - the share of adversarial entries in discv5, overlay.table_entries_id() and
+          secure_overlay.table_entries_id()
+        - the same per bucket (log distance from the victim), and how many buckets are fully adversarial
+        - optionally a sampling run on the latest blob, from the victim
+
+        [eclipse]
+        victim = 0
+        attackers = 16
+        prefix_bits = 12           # 2^12 key draws per attacker
+        at_slot = 1
+*/
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EclipseSpec {
+    /// Index of the victim.  It is never an adversary and never churns
+    pub victim: usize,
+    pub attackers: usize,
+    /// Leading bits every attacker's node id shares with the victim's
+    #[serde(default = "default_prefix_bits")]
+    pub prefix_bits: u32,
+    #[serde(default)]
+    pub at_slot: u64,
+    #[serde(default = "default_flood_rounds")]
+    pub flood_rounds: usize,
+    #[serde(default = "default_flood_interval_ms")]
+    pub flood_interval_ms: u64,
+    #[serde(default = "default_withhold")]
+    pub withhold: bool,
+    #[serde(default = "default_probe")]
+    pub probe: bool,
+}
+
+fn default_prefix_bits() -> u32 {
+    8
+}
+
+fn default_flood_rounds() -> usize {
+    3
+}
+
+fn default_flood_interval_ms() -> u64 {
+    500
+}
+
+fn default_withhold() -> bool {
+    true
+}
+
+fn default_probe() -> bool {
+    true
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Before,
+    Attack,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BucketShare {
+    /// Log distance from the victim
+    pub bucket: u32,
+    pub entries: usize,
+    pub adversarial: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TableShare {
+    pub entries: usize,
+    pub adversarial: usize,
+    /// adversarial / entries
+    pub share: f64,
+    /// Non-empty buckets holding nothing but attackers
+    pub eclipsed_buckets: usize,
+    pub buckets: Vec<BucketShare>,
+}
+
+impl TableShare {
+    fn of(local: &NodeId, entries: &[NodeId], attackers: &HashSet<NodeId>) -> Self {
+        let mut buckets: BTreeMap<u32, BucketShare> = BTreeMap::new();
+        for id in entries {
+            let bucket = match overlay::log_distance(&local.raw(), &id.raw()) {
+                Some(bucket) => bucket,
+                None => continue,
+            };
+            let share = buckets.entry(bucket).or_insert_with(|| BucketShare {
+                bucket,
+                ..Default::default()
+            });
+            share.entries += 1;
+            if attackers.contains(id) {
+                share.adversarial += 1;
+            }
+        }
+
+        let adversarial = entries.iter().filter(|id| attackers.contains(id)).count();
+        Self {
+            entries: entries.len(),
+            adversarial,
+            share: if entries.is_empty() { 0.0 } else { adversarial as f64 / entries.len() as f64 },
+            eclipsed_buckets: buckets.values().filter(|b| b.adversarial == b.entries).count(),
+            buckets: buckets.into_values().collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EclipseRecord {
+    pub slot: u64,
+    pub phase: Phase,
+    pub discv5: TableShare,
+    pub overlay: TableShare,
+    pub secure_overlay: TableShare,
+    pub probe: Option<Verdict>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EclipseResults {
+    pub victim: usize,
+    pub attackers: Vec<usize>,
+    pub prefix_bits: u32,
+    /// Attackers the victim's discv5 table had no room for
+    pub failed_discv5_inserts: usize,
+    pub records: Vec<EclipseRecord>,
+    #[serde(skip)]
+    probe: bool,
+}
+
+impl EclipseSpec {
+    pub fn check(&self, nodes: usize) -> anyhow::Result<()> {
+        if self.victim >= nodes {
+            bail!("Eclipse victim {} doesn't exist, there are {} nodes", self.victim, nodes);
+        }
+        if self.attackers == 0 {
+            bail!("An eclipse needs at least one attacker");
+        }
+        if self.prefix_bits > 24 {
+            bail!("Grinding {} prefix bits per attacker takes too long, 24 at most", self.prefix_bits);
+        }
+        Ok(())
+    }
+}
+
+impl EclipseResults {
+    pub fn new(spec: &EclipseSpec) -> Self {
+        Self {
+            victim: spec.victim,
+            attackers: Vec::new(),
+            prefix_bits: spec.prefix_bits,
+            failed_discv5_inserts: 0,
+            records: Vec::new(),
+            probe: spec.probe,
+        }
+    }
+
+    /// Grinds the attackers' keys, starts them and floods the victim.
+    pub async fn launch<R: Rng>(&mut self, network: &mut SimNetwork, spec: &EclipseSpec, rng: &mut R) {
+        let victim_enr = network.nodes[spec.victim].discovery.discv5.local_enr();
+        let victim_id = victim_enr.node_id();
+        let policy = spec.withhold.then(|| AdversaryPolicy {
+            withhold: Some(WithholdPolicy {
+                fraction: 1.0,
+                blobs: None,
+                requesters: Some(HashSet::from([victim_id])),
+                seed: 0,
+            }),
+            ..Default::default()
+        });
+
+        for _ in 0..spec.attackers {
+            let key = discovery::node_key_near(rng, &victim_id, spec.prefix_bits);
+            let i = network.add_node(key).await;
+            network.set_policy(i, policy.clone());
+
+            let attacker_enr = network.nodes[i].discovery.discv5.local_enr();
+            let _ = network.nodes[i].discovery.discv5.add_enr(victim_enr.clone());
+            if network.nodes[spec.victim].discovery.discv5.add_enr(attacker_enr).is_err() {
+                self.failed_discv5_inserts += 1;
+            }
+            self.attackers.push(i);
+        }
+
+        for round in 0..spec.flood_rounds {
+            if round > 0 {
+                tokio::time::sleep(Duration::from_millis(spec.flood_interval_ms)).await;
+            }
+            join_all(self.attackers.iter().map(|&i| {
+                let node = &network.nodes[i];
+                let enr = victim_enr.clone();
+                async move {
+                    futures::join!(node.overlay.send_ping(enr.clone()), node.secure_overlay.send_ping(enr));
+                }
+            }))
+            .await;
+        }
+    }
+
+    /// Measures the victim's routing tables against the attackers.  The probe samples the last blob.
+    pub async fn measure<R: Rng>(
+        &mut self,
+        network: &SimNetwork,
+        slot: u64,
+        phase: Phase,
+        blobs: &[(u64, u64)],
+        sampling_config: &SamplingConfig,
+        rng: &mut R,
+    ) {
+        let node = &network.nodes[self.victim];
+        let local = node.overlay.local_enr().node_id();
+        let attacker_ids: HashSet<NodeId> = self.attackers.iter().map(|&i| network.nodes[i].overlay.local_enr().node_id()).collect();
+
+        let probe = match (self.probe && network.is_running(self.victim), blobs.last()) {
+            (true, Some(&(slot, blob_index))) => {
+                let indices = node.choose_samples(slot, blob_index, sampling_config, rng);
+                Some(node.sample_blob_at(slot, blob_index, &indices, sampling_config).await.verdict)
+            }
+            _ => None,
+        };
+
+        self.records.push(EclipseRecord {
+            slot,
+            phase,
+            discv5: TableShare::of(&local, &node.discovery.discv5.table_entries_id(), &attacker_ids),
+            overlay: TableShare::of(&local, &node.overlay.table_entries_id(), &attacker_ids),
+            secure_overlay: TableShare::of(&local, &node.secure_overlay.table_entries_id(), &attacker_ids),
+            probe,
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first_byte: u8, last_byte: u8) -> NodeId {
+        let mut raw = [0u8; 32];
+        raw[0] = first_byte;
+        raw[31] = last_byte;
+        NodeId::new(&raw)
+    }
+
+    #[test]
+    fn shares_per_table_and_per_bucket() {
+        let victim = id(0, 0);
+        // Bucket 256: one honest node.  Bucket 255: two attackers
+        let (honest, near, nearer) = (id(0x80, 1), id(0x40, 1), id(0x41, 2));
+        let attackers = HashSet::from([near, nearer]);
+
+        let share = TableShare::of(&victim, &[honest, near, nearer], &attackers);
+        assert_eq!((share.entries, share.adversarial), (3, 2));
+        assert!((share.share - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(share.eclipsed_buckets, 1);
+        let buckets: Vec<(u32, usize, usize)> = share.buckets.iter().map(|b| (b.bucket, b.entries, b.adversarial)).collect();
+        assert_eq!(buckets, [(255, 2, 2), (256, 1, 0)]);
+    }
+
+    #[test]
+    fn an_empty_table_has_no_share() {
+        let share = TableShare::of(&id(0, 0), &[], &HashSet::from([id(1, 0)]));
+        assert_eq!(share.share, 0.0);
+        assert_eq!(share.eclipsed_buckets, 0);
+        assert!(share.buckets.is_empty());
+    }
+
+    #[test]
+    fn check_rejects_eclipses_that_cant_run() {
+        let spec: EclipseSpec = toml::from_str("victim = 0\nattackers = 4").unwrap();
+        assert!(spec.check(10).is_ok());
+        assert!(EclipseSpec { victim: 10, ..spec.clone() }.check(10).is_err());
+        assert!(EclipseSpec { attackers: 0, ..spec.clone() }.check(10).is_err());
+        assert!(EclipseSpec { prefix_bits: 25, ..spec }.check(10).is_err());
+    }
+}
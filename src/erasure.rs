@@ -0,0 +1,450 @@
+use anyhow::{anyhow, ensure};
+use c_kzg::{
+    Blob,
+    Cell,
+    KzgSettings,
+    BYTES_PER_BLOB,
+    BYTES_PER_CELL,
+    BYTES_PER_FIELD_ELEMENT,
+    CELLS_PER_EXT_BLOB,
+    FIELD_ELEMENTS_PER_BLOB,
+};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+};
+
+use crate::{
+    content_key::{DASContentKey, SampleKey},
+    sample::{self, DASSample},
+};
+
+/*
+    Erasure coding turns a blob into samples.
+
+    A blob is FIELD_ELEMENTS_PER_BLOB evaluations of a polynomial over the BLS12-381 scalar field.
+    Extending it means evaluating that same polynomial over twice as many points (Reed-Solomon with
+    rate 1/2), so ANY half of the extended data is enough to get the blob back.
+
+    The extended blob is split into CELLS_PER_EXT_BLOB cells of BYTES_PER_CELL bytes.  Each cell
+    comes with a KZG proof against the blob's commitment, which is what DASValidator checks.
+
+    Notes:
+        - c-kzg does the extension (FFT over the roots of unity) and the proofs in one go
+        - The first half of the cells is the original blob.  The second half is parity
+        - Reconstruction needs any SAMPLES_PER_BLOB / 2 verified samples
+*/
+
+pub const SAMPLES_PER_BLOB: usize = CELLS_PER_EXT_BLOB;
+pub const BYTES_PER_SAMPLE: usize = BYTES_PER_CELL;
+pub const SAMPLES_NEEDED_FOR_RECOVERY: usize = SAMPLES_PER_BLOB / 2;
+
+// Leave the top byte of every field element empty so arbitrary data is always a canonical scalar
+const USABLE_BYTES_PER_FIELD_ELEMENT: usize = BYTES_PER_FIELD_ELEMENT - 1;
+pub const MAX_DATA_PER_BLOB: usize = FIELD_ELEMENTS_PER_BLOB * USABLE_BYTES_PER_FIELD_ELEMENT;
+
+/// 1D extends each blob on its own.  2D also extends across blobs, see matrix.rs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtensionMode {
+    #[default]
+    OneDimensional,
+    TwoDimensional,
+}
+
+impl ExtensionMode {
+    /// Fewest of `total` extended cells that can determine the data: half of a 1D extended blob,
+    /// a quarter of a 2D extended matrix.
+    pub fn samples_needed(&self, total: usize) -> usize {
+        match self {
+            ExtensionMode::OneDimensional => total / 2,
+            ExtensionMode::TwoDimensional => total / 4,
+        }
+    }
+}
+
+/// A blob after 1D extension: its commitment plus every sample of the extended data.
+/// Under 2D extension this is one row of the extended matrix.
+#[derive(Clone, Debug)]
+pub struct ExtendedBlob {
+    pub slot: u64,
+    pub blob_index: u64,
+    pub row: u64,
+    pub commitment: Vec<u8>,
+    pub samples: Vec<DASSample>,
+}
+
+impl ExtendedBlob {
+    /// Opaque keys, sha256(commitment || cell_index).
+    pub fn content_keys(&self) -> Vec<DASContentKey> {
+        self.samples
+            .iter()
+            .map(|s| DASContentKey::Sample(sample::sample_id(&s.commitment, s.cell_index)))
+            .collect()
+    }
+
+    /// Keys addressed by coordinates.  1D extension only ever uses row 0.
+    pub fn cell_keys(&self) -> Vec<DASContentKey> {
+        self.samples
+            .iter()
+            .map(|s| DASContentKey::Cell(SampleKey::new(self.slot, self.blob_index, self.row, s.cell_index)))
+            .collect()
+    }
+}
+
+/// Extends a blob 2x and splits it into samples.
+pub fn extend_blob(kzg: &KzgSettings, blob: &Blob, slot: u64, blob_index: u64) -> anyhow::Result<ExtendedBlob> {
+    let commitment = kzg.blob_to_kzg_commitment(blob)?.to_bytes().into_inner().to_vec();
+    let (cells, proofs) = kzg.compute_cells_and_kzg_proofs(blob)?;
+
+    let samples = cells
+        .iter()
+        .zip(proofs.iter())
+        .enumerate()
+        .map(|(i, (cell, proof))| DASSample {
+            commitment: commitment.clone(),
+            cell_index: i as u64,
+            cell: cell.to_bytes().to_vec(),
+            proof: proof.to_bytes().into_inner().to_vec(),
+        })
+        .collect();
+
+    Ok(ExtendedBlob {
+        slot,
+        blob_index,
+        row: 0,
+        commitment,
+        samples,
+    })
+}
+
+/// Packs arbitrary bytes into a blob, 31 bytes per field element.  Short data is zero padded.
+pub fn blob_from_data(data: &[u8]) -> anyhow::Result<Blob> {
+    ensure!(data.len() <= MAX_DATA_PER_BLOB, "Data too large for one blob: {} > {}", data.len(), MAX_DATA_PER_BLOB);
+
+    let mut bytes = [0u8; BYTES_PER_BLOB];
+    for (i, chunk) in data.chunks(USABLE_BYTES_PER_FIELD_ELEMENT).enumerate() {
+        let start = i * BYTES_PER_FIELD_ELEMENT + 1;
+        bytes[start..start + chunk.len()].copy_from_slice(chunk);
+    }
+
+    Ok(Blob::new(bytes))
+}
+
+/// Inverse of blob_from_data.  Returns all MAX_DATA_PER_BLOB bytes, padding included.
+pub fn data_from_blob(blob: &Blob) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(MAX_DATA_PER_BLOB);
+    for element in blob.chunks(BYTES_PER_FIELD_ELEMENT) {
+        if element[0] != 0 {
+            return Err(anyhow!("Blob was not packed with blob_from_data"));
+        }
+        data.extend_from_slice(&element[1..]);
+    }
+
+    Ok(data)
+}
+
+
+/// A blob rebuilt from a partial set of its samples.
+#[derive(Clone, Debug)]
+pub struct Reconstruction {
+    pub blob: Blob,
+    /// Every sample of the extended blob, recovered ones included.
+    pub samples: Vec<DASSample>,
+    /// Indices we were not given.
+    pub missing: Vec<u64>,
+    /// Index of every input we threw away: undecodable, wrong commitment, bad proof, or a duplicate that
+    /// doesn't match the sample already verified for its index.  One entry per bad input.
+    pub inconsistent: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub enum ReconstructionError {
+    NotEnoughSamples {
+        valid: usize,
+        needed: usize,
+        missing: Vec<u64>,
+        inconsistent: Vec<u64>,
+    },
+    Kzg(String),
+}
+
+impl ReconstructionError {
+    /// How many more valid samples would have made reconstruction possible.
+    pub fn samples_short(&self) -> usize {
+        match self {
+            Self::NotEnoughSamples { valid, needed, .. } => needed.saturating_sub(*valid),
+            Self::Kzg(_) => 0,
+        }
+    }
+}
+
+impl fmt::Display for ReconstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotEnoughSamples { valid, needed, inconsistent, .. } => write!(
+                f,
+                "not enough samples to reconstruct blob: have {} valid ({} inconsistent), need {} more",
+                valid,
+                inconsistent.len(),
+                self.samples_short(),
+            ),
+            Self::Kzg(err) => write!(f, "kzg error during reconstruction: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReconstructionError {}
+
+/// Rebuilds a blob from any half of its extended samples.
+/// `samples` are (cell index, sample content) pairs, as fetched from the overlay.
+pub fn reconstruct_blob(kzg: &KzgSettings, commitment: &[u8], samples: &[(u64, Vec<u8>)]) -> Result<Reconstruction, ReconstructionError> {
+    let mut valid: BTreeMap<u64, DASSample> = BTreeMap::new();
+    let mut inconsistent = Vec::new();
+
+    for (index, content) in samples {
+        let sample = match DASSample::from_bytes(content) {
+            Ok(sample) if sample.cell_index == *index && sample.commitment == commitment => sample,
+            _ => {
+                inconsistent.push(*index);
+                continue;
+            }
+        };
+
+        // A verified sample stands.  Only one cell can pass the proof check for an index, so a
+        // duplicate that differs is junk and must not cost us the good one
+        if let Some(existing) = valid.get(index) {
+            if *existing != sample {
+                inconsistent.push(*index);
+            }
+            continue;
+        }
+
+        if sample.verify(kzg).is_err() {
+            inconsistent.push(*index);
+            continue;
+        }
+        valid.insert(*index, sample);
+    }
+    inconsistent.sort_unstable();
+
+    let missing: Vec<u64> = (0..SAMPLES_PER_BLOB as u64)
+        .filter(|i| !valid.contains_key(i) && !inconsistent.contains(i))
+        .collect();
+
+    if valid.len() < SAMPLES_NEEDED_FOR_RECOVERY {
+        return Err(ReconstructionError::NotEnoughSamples {
+            valid: valid.len(),
+            needed: SAMPLES_NEEDED_FOR_RECOVERY,
+            missing,
+            inconsistent,
+        });
+    }
+
+    let indices: Vec<u64> = valid.keys().copied().collect();
+    let cells = valid
+        .values()
+        .map(|s| Cell::from_bytes(&s.cell))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ReconstructionError::Kzg(format!("{:?}", err)))?;
+    let (cells, proofs) = kzg
+        .recover_cells_and_kzg_proofs(&indices, &cells)
+        .map_err(|err| ReconstructionError::Kzg(format!("{:?}", err)))?;
+
+    // The first half of the extended blob is the original blob
+    let mut bytes = [0u8; BYTES_PER_BLOB];
+    for (i, cell) in cells.iter().take(SAMPLES_PER_BLOB / 2).enumerate() {
+        bytes[i * BYTES_PER_CELL..(i + 1) * BYTES_PER_CELL].copy_from_slice(&cell.to_bytes());
+    }
+    let blob = Blob::new(bytes);
+
+    let samples = cells
+        .iter()
+        .zip(proofs.iter())
+        .enumerate()
+        .map(|(i, (cell, proof))| DASSample {
+            commitment: commitment.to_vec(),
+            cell_index: i as u64,
+            cell: cell.to_bytes().to_vec(),
+            proof: proof.to_bytes().into_inner().to_vec(),
+        })
+        .collect();
+
+    Ok(Reconstruction {
+        blob,
+        samples,
+        missing,
+        inconsistent,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kzg() -> KzgSettings {
+        sample::load_trusted_setup(sample::TRUSTED_SETUP_PATH).unwrap()
+    }
+
+    fn round_trip(data: &[u8]) {
+        let unpacked = data_from_blob(&blob_from_data(data).unwrap()).unwrap();
+        assert_eq!(unpacked.len(), MAX_DATA_PER_BLOB);
+        assert_eq!(&unpacked[..data.len()], data);
+        assert!(unpacked[data.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn empty_data_round_trips() {
+        round_trip(&[]);
+    }
+
+    #[test]
+    fn data_round_trips_around_the_field_element_boundary() {
+        for len in [1, 30, 31, 32, 62, 63] {
+            let data: Vec<u8> = (1..=len as u8).collect();
+            round_trip(&data);
+        }
+    }
+
+    #[test]
+    fn byte_32_spills_into_the_second_field_element() {
+        let data = [0xff; 32];
+        let blob = blob_from_data(&data).unwrap();
+        // Top byte of every element stays empty
+        assert_eq!(blob[0], 0);
+        assert!(blob[1..BYTES_PER_FIELD_ELEMENT].iter().all(|&b| b == 0xff));
+        assert_eq!(blob[BYTES_PER_FIELD_ELEMENT], 0);
+        assert_eq!(blob[BYTES_PER_FIELD_ELEMENT + 1], 0xff);
+        assert_eq!(blob[BYTES_PER_FIELD_ELEMENT + 2], 0);
+    }
+
+    #[test]
+    fn max_size_data_round_trips() {
+        let data: Vec<u8> = (0..MAX_DATA_PER_BLOB).map(|i| (i % 251) as u8).collect();
+        round_trip(&data);
+    }
+
+    #[test]
+    fn rejects_data_over_max_size() {
+        assert!(blob_from_data(&vec![1; MAX_DATA_PER_BLOB + 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_blob_not_packed_by_blob_from_data() {
+        let mut bytes = [0u8; BYTES_PER_BLOB];
+        bytes[BYTES_PER_FIELD_ELEMENT] = 1;
+        assert!(data_from_blob(&Blob::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn extension_doubles_the_blob_and_every_proof_verifies() {
+        let kzg = kzg();
+        let blob = blob_from_data(b"extend me").unwrap();
+        let extended = extend_blob(&kzg, &blob, 3, 1).unwrap();
+
+        assert_eq!(extended.samples.len(), 2 * BYTES_PER_BLOB / BYTES_PER_CELL);
+        assert_eq!(extended.samples.len(), SAMPLES_PER_BLOB);
+        for (i, sample) in extended.samples.iter().enumerate() {
+            assert_eq!(sample.cell_index, i as u64);
+            assert_eq!(sample.commitment, extended.commitment);
+            sample.verify(&kzg).unwrap();
+        }
+
+        // The first half is the blob itself
+        let original: Vec<u8> = extended.samples[..SAMPLES_PER_BLOB / 2]
+            .iter()
+            .flat_map(|s| s.cell.iter().copied())
+            .collect();
+        assert_eq!(&original[..], &blob[..]);
+    }
+
+    fn extended(kzg: &KzgSettings) -> (Blob, ExtendedBlob) {
+        let blob = blob_from_data(b"reconstruct me").unwrap();
+        let extended = extend_blob(kzg, &blob, 0, 0).unwrap();
+        (blob, extended)
+    }
+
+    fn contents(samples: &[DASSample]) -> Vec<(u64, Vec<u8>)> {
+        samples.iter().map(|s| (s.cell_index, s.to_bytes())).collect()
+    }
+
+    /// A sample with a flipped cell bit: decodes fine, fails its proof.
+    fn tampered(sample: &DASSample) -> Vec<u8> {
+        let mut sample = sample.clone();
+        sample.cell[BYTES_PER_FIELD_ELEMENT - 1] ^= 1;
+        sample.to_bytes()
+    }
+
+    #[test]
+    fn recovers_from_exactly_half_of_the_samples() {
+        let kzg = kzg();
+        let (blob, extended) = extended(&kzg);
+        // Parity only: none of the original cells
+        let given = contents(&extended.samples[SAMPLES_NEEDED_FOR_RECOVERY..]);
+
+        let reconstruction = reconstruct_blob(&kzg, &extended.commitment, &given).unwrap();
+        assert_eq!(&reconstruction.blob[..], &blob[..]);
+        assert_eq!(reconstruction.samples, extended.samples);
+        assert_eq!(reconstruction.missing, (0..SAMPLES_NEEDED_FOR_RECOVERY as u64).collect::<Vec<_>>());
+        assert!(reconstruction.inconsistent.is_empty());
+    }
+
+    #[test]
+    fn one_sample_short_of_half_fails() {
+        let kzg = kzg();
+        let (_, extended) = extended(&kzg);
+        let given = contents(&extended.samples[..SAMPLES_NEEDED_FOR_RECOVERY - 1]);
+
+        let err = reconstruct_blob(&kzg, &extended.commitment, &given).unwrap_err();
+        assert_eq!(err.samples_short(), 1);
+    }
+
+    #[test]
+    fn counts_every_bad_input_as_inconsistent() {
+        let kzg = kzg();
+        let (blob, extended) = extended(&kzg);
+        let mut given = contents(&extended.samples[..SAMPLES_NEEDED_FOR_RECOVERY]);
+        // Undecodable, tampered and from another blob, all on indices we also have good samples for
+        given.push((0, vec![0; 10]));
+        given.push((1, tampered(&extended.samples[1])));
+        let other = extend_blob(&kzg, &blob_from_data(b"some other blob").unwrap(), 0, 0).unwrap();
+        given.push((2, other.samples[2].to_bytes()));
+        // An index with nothing but junk
+        given.push((100, tampered(&extended.samples[100])));
+        // Identical duplicates are fine
+        given.push((3, extended.samples[3].to_bytes()));
+
+        let reconstruction = reconstruct_blob(&kzg, &extended.commitment, &given).unwrap();
+        assert_eq!(&reconstruction.blob[..], &blob[..]);
+        assert_eq!(reconstruction.inconsistent, vec![0, 1, 2, 100]);
+        assert!(!reconstruction.missing.contains(&100));
+    }
+
+    #[test]
+    fn junk_duplicates_cant_block_recovery() {
+        let kzg = kzg();
+        let (blob, extended) = extended(&kzg);
+        // Junk for every index, before and after the real sample
+        let mut given = Vec::new();
+        for sample in &extended.samples[..SAMPLES_NEEDED_FOR_RECOVERY] {
+            given.push((sample.cell_index, tampered(sample)));
+            given.push((sample.cell_index, sample.to_bytes()));
+            given.push((sample.cell_index, vec![1; 3]));
+        }
+
+        let reconstruction = reconstruct_blob(&kzg, &extended.commitment, &given).unwrap();
+        assert_eq!(&reconstruction.blob[..], &blob[..]);
+        assert_eq!(reconstruction.inconsistent.len(), 2 * SAMPLES_NEEDED_FOR_RECOVERY);
+    }
+
+    #[test]
+    fn cell_keys_carry_the_blob_coordinates() {
+        let kzg = kzg();
+        let extended = extend_blob(&kzg, &blob_from_data(b"keys").unwrap(), 3, 1).unwrap();
+        let keys = extended.cell_keys();
+        assert_eq!(keys.len(), SAMPLES_PER_BLOB);
+        assert_eq!(keys[5], DASContentKey::Cell(SampleKey::new(3, 1, 0, 5)));
+    }
+}
@@ -0,0 +1,391 @@
+use discv5::enr::NodeId;
+use anyhow::bail;
+use parking_lot::{Mutex, RwLock};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, LogNormal, Normal};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::Duration,
+};
+
+/*
+    What a message between two simulated nodes goes through: how long it takes and how likely it is
+    to be lost.
+
+    Every node is placed in a region when it joins the virtual network.  The profile of a link is,
+    first match wins:
+        1. An override set for that exact pair of nodes (VirtualNetwork::set_link)
+        2. The profile between the two nodes' regions (either direction)
+        3. The default profile
+
+    Latency is drawn per message, so jitter reorders messages like a real network would.  Without
+    regions every link uses the default profile.
+
+    Both transports apply a link model:
+        - Virtual: the virtual network rolls the dice for every message, each way (see transport.rs)
+        - Udp: a node's router holds back every TalkReq it receives for the link's latency before
+          handling it, and the response for another draw before sending it.  A lost request or response
+          is never answered, the requester times out.  Overlay messages and uTP packets are both
+          TalkReqs, so this covers pings, FindContent, Offers and content transfers alike
+        Without link settings UDP messages take whatever loopback takes.
+
+    Limits:
+        - Discv5's own packets (session handshakes, its PING and FINDNODE) are handled inside the discv5
+          server and can't be held back.  The simulation sends none of its own: tables are filled with
+          add_enr (see topology.rs) and lookups are overlay FindNodes, which are TalkReqs
+        - On the virtual transport discv5 servers are never started and exchange no messages at all
+
+        [transport]
+        kind = "virtual"
+        default = { latency = { kind = "normal", mean_ms = 40, jitter_ms = 10 }, loss = 0.001 }
+        regions = [{ name = "eu", weight = 2 }, { name = "us", weight = 1 }]
+        region_links = [
+            { from = "eu", to = "us", profile = { latency = { kind = "log_normal", median_ms = 90, sigma = 0.3 }, loss = 0.01 } },
+        ]
+*/
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Latency {
+    Constant { ms: u64 },
+    Uniform { min_ms: u64, max_ms: u64 },
+    /// Truncated at 0
+    Normal { mean_ms: f64, jitter_ms: f64 },
+    /// Long tailed.  `sigma` is the standard deviation of the underlying normal
+    LogNormal { median_ms: f64, sigma: f64 },
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::Constant { ms: 50 }
+    }
+}
+
+impl Latency {
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        let ms = match *self {
+            Latency::Constant { ms } => ms as f64,
+            Latency::Uniform { min_ms, max_ms } => rng.gen_range(min_ms..=max_ms.max(min_ms)) as f64,
+            Latency::Normal { mean_ms, jitter_ms } => match Normal::new(mean_ms, jitter_ms) {
+                Ok(normal) => normal.sample(rng),
+                Err(_) => mean_ms,
+            },
+            Latency::LogNormal { median_ms, sigma } => match LogNormal::new(median_ms.max(f64::MIN_POSITIVE).ln(), sigma) {
+                Ok(log_normal) => log_normal.sample(rng),
+                Err(_) => median_ms,
+            },
+        };
+        Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkProfile {
+    pub latency: Latency,
+    /// Probability that a single message is lost
+    pub loss: f64,
+}
+
+impl LinkProfile {
+    pub fn new(latency: Latency, loss: f64) -> Self {
+        Self { latency, loss }
+    }
+
+    /// None if the message is lost, otherwise how long it takes.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Option<Duration> {
+        if rng.gen_bool(self.loss.clamp(0.0, 1.0)) {
+            return None;
+        }
+        Some(self.latency.sample(rng))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub name: String,
+    /// Relative share of the nodes placed here
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RegionLink {
+    pub from: String,
+    pub to: String,
+    pub profile: LinkProfile,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkModel {
+    pub default: LinkProfile,
+    pub regions: Vec<Region>,
+    pub region_links: Vec<RegionLink>,
+}
+
+impl LinkProfile {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.loss) {
+            bail!("Loss must be a probability between 0 and 1, not {}", self.loss);
+        }
+        let valid = match self.latency {
+            Latency::Constant { .. } => true,
+            Latency::Uniform { min_ms, max_ms } => min_ms <= max_ms,
+            Latency::Normal { mean_ms, jitter_ms } => mean_ms.is_finite() && jitter_ms.is_finite() && jitter_ms >= 0.0,
+            Latency::LogNormal { median_ms, sigma } => median_ms.is_finite() && median_ms > 0.0 && sigma.is_finite() && sigma >= 0.0,
+        };
+        if !valid {
+            bail!("Invalid latency {:?}", self.latency);
+        }
+        Ok(())
+    }
+}
+
+impl LinkModel {
+    /// Rejects profiles that can't be sampled and region links to unknown regions.
+    pub fn check(&self) -> anyhow::Result<()> {
+        self.default.check()?;
+        for region in &self.regions {
+            if !region.weight.is_finite() || region.weight < 0.0 {
+                bail!("Region {} needs a non-negative weight, not {}", region.name, region.weight);
+            }
+        }
+        for link in &self.region_links {
+            for name in [&link.from, &link.to] {
+                if !self.regions.iter().any(|r| &r.name == name) {
+                    bail!("Region link {} -> {} names unknown region {}", link.from, link.to, name);
+                }
+            }
+            link.profile.check()?;
+        }
+        Ok(())
+    }
+}
+
+/// A LinkModel in use: where every node is, plus per pair overrides.
+#[derive(Debug, Default)]
+pub struct LinkTable {
+    model: LinkModel,
+    region_of: HashMap<NodeId, usize>,
+    overrides: HashMap<(NodeId, NodeId), LinkProfile>,
+}
+
+impl LinkTable {
+    pub fn new(model: LinkModel) -> Self {
+        Self {
+            model,
+            ..Default::default()
+        }
+    }
+
+    /// Places a node in a region, weighted at random.  A node keeps its region across restarts.
+    pub fn place<R: Rng>(&mut self, node_id: NodeId, rng: &mut R) -> Option<usize> {
+        if let Some(&region) = self.region_of.get(&node_id) {
+            return Some(region);
+        }
+        let total: f64 = self.model.regions.iter().map(|r| r.weight.max(0.0)).sum();
+        if total <= 0.0 {
+            return None;
+        }
+
+        let mut point = rng.gen_range(0.0..total);
+        let mut region = self.model.regions.len() - 1;
+        for (i, r) in self.model.regions.iter().enumerate() {
+            if point < r.weight.max(0.0) {
+                region = i;
+                break;
+            }
+            point -= r.weight.max(0.0);
+        }
+        self.region_of.insert(node_id, region);
+        Some(region)
+    }
+
+    pub fn region(&self, node_id: &NodeId) -> Option<&str> {
+        self.region_of.get(node_id).map(|&i| self.model.regions[i].name.as_str())
+    }
+
+    /// Overrides the link between `a` and `b`, both directions.
+    pub fn set_link(&mut self, a: NodeId, b: NodeId, profile: LinkProfile) {
+        self.overrides.insert((a, b), profile.clone());
+        self.overrides.insert((b, a), profile);
+    }
+
+    pub fn clear_link(&mut self, a: &NodeId, b: &NodeId) {
+        self.overrides.remove(&(*a, *b));
+        self.overrides.remove(&(*b, *a));
+    }
+
+    pub fn profile(&self, from: &NodeId, to: &NodeId) -> &LinkProfile {
+        if let Some(profile) = self.overrides.get(&(*from, *to)) {
+            return profile;
+        }
+
+        let regions = (self.region(from), self.region(to));
+        if let (Some(a), Some(b)) = regions {
+            let region_link = self
+                .model
+                .region_links
+                .iter()
+                .find(|l| (l.from == a && l.to == b) || (l.from == b && l.to == a));
+            if let Some(link) = region_link {
+                return &link.profile;
+            }
+        }
+
+        &self.model.default
+    }
+}
+
+
+/// A LinkTable shared by every node of a run, plus the rng each message rolls its dice with.
+#[derive(Clone)]
+pub struct LinkShaper {
+    table: Arc<RwLock<LinkTable>>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl LinkShaper {
+    pub fn new(model: LinkModel, seed: u64) -> Self {
+        Self {
+            table: Arc::new(RwLock::new(LinkTable::new(model))),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+        }
+    }
+
+    /// See LinkTable::place.
+    pub fn place(&self, node_id: NodeId) -> Option<usize> {
+        self.table.write().place(node_id, &mut *self.rng.lock())
+    }
+
+    pub fn region(&self, node_id: &NodeId) -> Option<String> {
+        self.table.read().region(node_id).map(str::to_string)
+    }
+
+    pub fn set_link(&self, a: NodeId, b: NodeId, profile: LinkProfile) {
+        self.table.write().set_link(a, b, profile);
+    }
+
+    pub fn clear_link(&self, a: &NodeId, b: &NodeId) {
+        self.table.write().clear_link(a, b);
+    }
+
+    /// How long one message from `from` to `to` takes.  None means it's lost.
+    pub fn sample(&self, from: &NodeId, to: &NodeId) -> Option<Duration> {
+        let table = self.table.read();
+        table.profile(from, to).sample(&mut *self.rng.lock())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant(ms: u64) -> LinkProfile {
+        LinkProfile::new(Latency::Constant { ms }, 0.0)
+    }
+
+    fn model() -> LinkModel {
+        LinkModel {
+            default: constant(10),
+            regions: vec![
+                Region { name: "eu".to_string(), weight: 1.0 },
+                Region { name: "us".to_string(), weight: 0.0 },
+            ],
+            region_links: vec![RegionLink { from: "eu".to_string(), to: "eu".to_string(), profile: constant(20) }],
+        }
+    }
+
+    #[test]
+    fn latencies_stay_in_range() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let uniform = Latency::Uniform { min_ms: 10, max_ms: 20 };
+        let normal = Latency::Normal { mean_ms: 5.0, jitter_ms: 50.0 };
+        for _ in 0..100 {
+            let ms = uniform.sample(&mut rng).as_millis();
+            assert!((10..=20).contains(&ms));
+            // Truncated at 0, not negative
+            normal.sample(&mut rng);
+        }
+        assert_eq!(Latency::Constant { ms: 7 }.sample(&mut rng), Duration::from_millis(7));
+    }
+
+    #[test]
+    fn loss_is_a_probability() {
+        let mut rng = StdRng::seed_from_u64(1);
+        assert!(LinkProfile::new(Latency::Constant { ms: 1 }, 1.0).sample(&mut rng).is_none());
+        assert!(LinkProfile::new(Latency::Constant { ms: 1 }, 0.0).sample(&mut rng).is_some());
+
+        let half = LinkProfile::new(Latency::Constant { ms: 1 }, 0.5);
+        let lost = (0..1000).filter(|_| half.sample(&mut rng).is_none()).count();
+        assert!((400..600).contains(&lost));
+    }
+
+    #[test]
+    fn check_rejects_profiles_that_cant_be_sampled() {
+        assert!(model().check().is_ok());
+        assert!(LinkProfile::new(Latency::Constant { ms: 1 }, 1.5).check().is_err());
+        assert!(LinkProfile::new(Latency::Uniform { min_ms: 20, max_ms: 10 }, 0.0).check().is_err());
+        assert!(LinkProfile::new(Latency::LogNormal { median_ms: 0.0, sigma: 1.0 }, 0.0).check().is_err());
+        assert!(LinkProfile::new(Latency::Normal { mean_ms: f64::NAN, jitter_ms: 1.0 }, 0.0).check().is_err());
+
+        let mut unknown_region = model();
+        unknown_region.region_links[0].to = "asia".to_string();
+        assert!(unknown_region.check().is_err());
+    }
+
+    #[test]
+    fn overrides_then_regions_then_the_default() {
+        let mut table = LinkTable::new(model());
+        let mut rng = StdRng::seed_from_u64(1);
+        let (a, b, outsider) = (NodeId::random(), NodeId::random(), NodeId::random());
+        // "us" has no weight, everyone placed lands in "eu"
+        assert_eq!(table.place(a, &mut rng), Some(0));
+        table.place(b, &mut rng);
+        assert_eq!(table.region(&b), Some("eu"));
+
+        assert_eq!(table.profile(&a, &b), &constant(20));
+        assert_eq!(table.profile(&a, &outsider), &constant(10));
+        table.set_link(a, b, constant(30));
+        assert_eq!(table.profile(&b, &a), &constant(30));
+        table.clear_link(&a, &b);
+        assert_eq!(table.profile(&a, &b), &constant(20));
+    }
+
+    #[test]
+    fn nodes_keep_their_region() {
+        let model = LinkModel {
+            regions: vec![Region { name: "eu".to_string(), weight: 1.0 }, Region { name: "us".to_string(), weight: 1.0 }],
+            ..Default::default()
+        };
+        let mut table = LinkTable::new(model);
+        let mut rng = StdRng::seed_from_u64(1);
+        let node_id = NodeId::random();
+        let region = table.place(node_id, &mut rng);
+        assert!((0..10).all(|_| table.place(node_id, &mut rng) == region));
+        // No regions, no placement
+        assert_eq!(LinkTable::new(LinkModel::default()).place(node_id, &mut rng), None);
+    }
+
+    #[test]
+    fn shapers_with_the_same_seed_agree() {
+        let model = LinkModel { default: LinkProfile::new(Latency::Uniform { min_ms: 0, max_ms: 1000 }, 0.3), ..Default::default() };
+        let (a, b) = (NodeId::random(), NodeId::random());
+        let draws = |seed| {
+            let shaper = LinkShaper::new(model.clone(), seed);
+            (0..20).map(|_| shaper.sample(&a, &b)).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(5), draws(5));
+        assert_ne!(draws(5), draws(6));
+    }
+}
@@ -1,55 +1,48 @@
 #![allow(unused)]
-use discv5::{
-    Discv5Event, 
-    TalkRequest
-};
+use c_kzg::KzgSettings;
+use clap::Parser;
 use discv5_overlay::{
-    portalnet::{
-        discovery::Discovery,
-        overlay_service::{
-            OverlayCommand,
-            OverlayService
-        },
-        storage::MemoryContentStore,
-        types::{
-            distance::XorMetric,
-            messages::ProtocolId
-        }, 
-    },
-    utp::stream::{UtpListener, UtpListenerEvent}
-};
-use futures::StreamExt; 
-use rand::Rng;
-use std::{
-    str::FromStr,
-    sync::Arc,
-};
-use tokio::{
-    select, 
-    sync::mpsc::{UnboundedReceiver, UnboundedSender}
+    portalnet::{discovery::Discovery, types::messages::ProtocolId},
+    utp::stream::UtpListener,
 };
-use tokio_stream::wrappers::ReceiverStream;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{path::Path, sync::Arc};
 use tracing;
-use tracing::log::error;
 
 use crate::{
-    node_struct::DASNode,
-    content_key::{
-        DASContentKey, 
-        DASValidator,
-        SecureDASContentKey,
-        SecureDASValidator,
-    }
+    cli::{Args, Scenario},
+    content_key::{DASValidator, SecureDASValidator},
+    network::{NetworkConfig, SimNetwork},
+    node_struct::{DASNode, NodeConfig},
+    runtime::NodeServices,
+    sample::BlobCommitments,
+    scenario::ScenarioFile,
+    transport::{Transport, VirtualOverlay, VirtualPeer},
 };
 
+pub mod adversary;
+pub mod churn;
+pub mod cli;
 pub mod content_key;
 pub mod discovery;
+pub mod eclipse;
+pub mod erasure;
+pub mod links;
+pub mod matrix;
+pub mod network;
 pub mod node_struct;
 pub mod overlay;
-
-pub const NUMBER_OF_NODES: usize = 10;
-const DAS_PROTOCOL_ID: &str = "DAS";
-const SECURE_DAS_PROTOCOL_ID: &str = "SECURE_DAS";
+pub mod partition;
+pub mod pruning;
+pub mod publish;
+pub mod router;
+pub mod runtime;
+pub mod sample;
+pub mod sampling;
+pub mod scenario;
+pub mod storage;
+pub mod topology;
+pub mod transport;
 
 /*
     Goals 
@@ -58,7 +51,6 @@ const SECURE_DAS_PROTOCOL_ID: &str = "SECURE_DAS";
 
     Questions:
         - Are data shard validator committees the same committees used within Light Client things?  
-        - Should I create a proxy for message processing to handle my two seperate overlays?
 
 
     Notes:
@@ -69,192 +61,61 @@ const SECURE_DAS_PROTOCOL_ID: &str = "SECURE_DAS";
 
 #[tokio::main]
 async fn main() {
-    //============================ 
-    //   Part 1:  Node Creation
-    //============================ 
-    let mut discv5_structs = Vec::new();
-    let mut nodes = Vec::new();
-
-    // Create all Discv5 servers, then pass these into create_nodes.
-    for i in 0.. NUMBER_OF_NODES {
-        let discv5_struct = discovery::create_discovery(i as u16).await;
-        discv5_structs.push(discv5_struct)
+    let args = Args::parse();
+    if let Some(path) = &args.scenario_file {
+        if let Err(err) = run_scenario_file(path, args.results.as_deref(), args.seed).await {
+            println!("Scenario failed: {:#}", err);
+        }
+        return;
     }
 
-    // Populate discv5 tables
-    for i in 0..NUMBER_OF_NODES {
-        populate_discv5_table(i, discv5_structs.clone());
+    let topology = args.topology();
+    if let Err(err) = topology.check(args.nodes) {
+        println!("Invalid topology: {}", err);
+        return;
     }
 
-    // Instantiates protocol structs and message processing within each node
-    for i in discv5_structs.into_iter() {
-        let (
-            starter_node, 
-            mut overlay_service,
-            mut secure_overlay_service, 
-            utp_events_tx, 
-            utp_listener_rx
-        ) = create_node(i).await;
-     
-        let mut event_str = ReceiverStream::new(starter_node.discovery.discv5.event_stream().await.unwrap());
-
-        // Copying the entire node to pass info into our task manager  :P 
-        let node = starter_node.clone(); 
-        nodes.push(starter_node);
-        
-        // Instantiates task manager to continually process ALL messages for each node (server side of node).
-        // Wrap message processing code into a function so it's easy to read.
-        tokio::spawn(async move {
-            loop {
-                /// "Select!" randomly picks one of these match branches to process an event 
-                select! {
-                    // =========================== 
-                    // Overlay Message Processing:  
-                    // =========================== 
-                    // Request 
-                    Some(command) = overlay_service.command_rx.recv() => {
-                        match command {
-                            OverlayCommand::Request(request) => { 
-                                println!("Processing Overlay Request"); 
-                                overlay_service.process_request(request)
-                            }, 
-                            _ => {}    
-                        }
-                    }
-                    // Response 
-                    Some(response) = overlay_service.response_rx.recv() => {
-                        // Look up active request that corresponds to the response.
-                        let optional_active_request = overlay_service.active_outgoing_requests.write().remove(&response.request_id);
-                        if let Some(active_request) = optional_active_request {
-                            println!("Send overlay response");
-                            println!("\n");
-                            // Send response to responder if present.
-                            if let Some(responder) = active_request.responder {
-                                let _ = responder.send(response.response.clone());
-                            }
-
-                            // Perform background processing.
-                            match response.response {
-                                Ok(response) => overlay_service.process_response(response, active_request.destination, active_request.request, active_request.query_id),
-                                Err(error) => overlay_service.process_request_failure(response.request_id, active_request.destination, error),
-                            }
-
-                        } else {
-                            println!("No request found for response");
-                        }
-                    } 
-                    // ================================== 
-                    // Secure Overlay Message Processing:  
-                    // ================================== 
-                    // Request 
-                    Some(command) = secure_overlay_service.command_rx.recv() => {
-                        match command {
-                            OverlayCommand::Request(request) => { 
-                                println!("Processing Secure Overlay Request"); 
-                                secure_overlay_service.process_request(request)
-                            }, 
-                            _ => {}    
-                        }
-                    }
-                    // Response 
-                    Some(response) = secure_overlay_service.response_rx.recv() => {
-                        // Look up active request that corresponds to the response.
-                        let optional_active_request = secure_overlay_service.active_outgoing_requests.write().remove(&response.request_id);
-                        if let Some(active_request) = optional_active_request {
-                            println!("Send secure overlay response");
-                            println!("\n");
-                            // Send response to responder if present.
-                            if let Some(responder) = active_request.responder {
-                                let _ = responder.send(response.response.clone());
-                            }
-
-                            // Perform background processing.
-                            match response.response {
-                                Ok(response) => secure_overlay_service.process_response(response, active_request.destination, active_request.request, active_request.query_id),
-                                Err(error) => secure_overlay_service.process_request_failure(response.request_id, active_request.destination, error),
-                            }
-
-                        } else {
-                            println!("No request found for response");
-                        }
-                    } 
-                    // ==========================
-                    // Discv5 Message Processing: 
-                    // ==========================
-                    // Incoming event
-                    Some(event) = event_str.next() => {
-                        // let chan = format!("{:?} {i}", node.discovery.discv5.local_enr().node_id().to_string());
-                        match event {
-                            Discv5Event::TalkRequest(req) => {
-                                // println!("Stream {}: Discv5 TalkReq received", chan);  
-                                
-                                let node = node.clone(); 
-                                tokio::spawn(async move {
-                                    let protocol = ProtocolId::from_str(&hex::encode_upper(req.protocol())).unwrap();
-
-                                    if protocol == ProtocolId::Custom(DAS_PROTOCOL_ID.to_string()) {
-                                        println!("Enters DAS Protocol");  
-                                        let talk_resp = match node.overlay.process_one_request(&req).await {
-                                            Ok(response) => discv5_overlay::portalnet::types::messages::Message::from(response).into(),
-                                            Err(err) => {
-                                                error!("Error processing request:");
-                                                // error!("Node {chan} Error processing request: {err}");
-                                                return;
-                                            },
-                                        };
-
-                                        if let Err(err) = req.respond(talk_resp) {
-                                            println!("Error");  
-                                            error!("Unable to respond to talk request: ");
-                                            // error!("Unable to respond to talk request: {}", err);
-                                            return;
-                                        }
-
-                                        return;
-                                    }
-
-                                    // See if we can watch for a secure overlay event.
-                                    if protocol == ProtocolId::Custom(SECURE_DAS_PROTOCOL_ID.to_string()) {
-                                        println!("Enters SecureDAS Protocol");  
-                                        let talk_resp = match node.overlay.process_one_request(&req).await {
-                                        // let talk_resp = match node.secure_overlay.process_one_request(&req).await {
-                                            Ok(response) => discv5_overlay::portalnet::types::messages::Message::from(response).into(),
-                                            Err(err) => {
-                                                error!("Node Error processing request: ");
-                                                // error!("Node {chan} Error processing request: {err}");
-                                                return;
-                                            },
-                                        };
-
-                                        if let Err(err) = req.respond(talk_resp) {
-                                            println!("Error");  
-                                            error!("Unable to respond to talk request: ");
-                                            // error!("Unable to respond to talk request: {}", err);
-                                            return;
-                                        }
-
-                                        return;
-                                    }
-                                    // let resp = handle_talk_request(req.node_id().clone(), req.protocol(), req.body().to_vec(), node, opts, enr_to_libp2p, node_ids, i).await;
-                                    // let resp = handle_talk_request(req.node_id().clone(), req.protocol(), req.body().to_vec(), node).await;
-                                    // req.respond(resp);
-                                });
-                            },
-                            _ => {}    
-                        }
-                    },
-                }
-            } 
-        });
-    }
+    let transport = match args.transport() {
+        Ok(transport) => transport,
+        Err(err) => {
+            println!("Invalid transport: {}", err);
+            return;
+        }
+    };
+
+    let seed = args.seed();
+    println!("Running {:?} with {} nodes, seed {}", args.scenario, args.nodes, seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    //============================ 
+    //   Part 1:  Node Creation
+    //============================ 
+    // In memory unless --storage-dir is given, pruned after the retention window unless --no-pruning
+    let config = NetworkConfig {
+        nodes: args.nodes,
+        topology,
+        base_port: args.base_port,
+        request_timeout: args.request_timeout(),
+        node: NodeConfig {
+            storage: args.storage(),
+            pruning: args.pruning(),
+            ..Default::default()
+        },
+        transport,
+    };
+    let network = SimNetwork::start(&config, &mut rng).await;
+    let nodes = &network.nodes;
 
     // View of a node's routing table
     // ----------------------------
-    println!("Node's discv5 routing table: {:?}", nodes[2].discovery.connected_peers()); 
+    let viewed = 2 % nodes.len();
+    println!("Node's discv5 routing table: {:?}", nodes[viewed].discovery.connected_peers()); 
+    println!("\n");
+    println!("Node's overlay routing table: {:?}", nodes[viewed].overlay.table_entries_id()); 
     println!("\n");
-    println!("Node's overlay routing table: {:?}", nodes[2].overlay.table_entries_id()); 
+    println!("Node's secure overlay routing table: {:?}", nodes[viewed].secure_overlay.table_entries_id()); 
     println!("\n");
-    println!("Node's secure overlay routing table: {:?}", nodes[2].secure_overlay.table_entries_id()); 
+    println!("Routing table sizes across nodes: {:?}", network.routing_tables()); 
     println!("\n");
 
 
@@ -263,103 +124,160 @@ async fn main() {
        Part 2: Node Communication
     ================================== 
        Creates simple communication between nodes. We need to pass our overlay messages 
-       through peers' routing tables.  Implement concurrent communication later.     
+       through peers' routing tables.     
     
        Overlay Protocol struct --> calls our Overlay Service   
     */
+    if nodes.len() < 2 {
+        println!("Need at least 2 nodes to communicate");
+    } else {
+        run_ping(&nodes).await;
+
+        if args.scenario == Scenario::Publish || args.scenario == Scenario::Sampling {
+            run_publish(&nodes).await;
+        }
+
+        if args.scenario == Scenario::Sampling {
+            let sampler = rng.gen_range(0..nodes.len());
+            let config = sampling::SamplingConfig {
+                lookup_timeout: args.lookup_timeout(),
+                fallback_timeout: args.lookup_timeout(),
+                ..Default::default()
+            };
+            run_sampling(&nodes[sampler], &config, &mut rng).await;
+        }
+    }
+
+    //================================ 
+    //         Sanity Check 
+    //================================ 
+    println!("Overlay Protocol ID: {:?}", nodes[viewed].overlay.protocol()); 
+    println!("Secure Overlay Protocol ID: {:?}", nodes[viewed].secure_overlay.protocol()); 
+    println!("Node stats: {:?}", nodes[viewed].stats_snapshot()); 
+
+    //================================ 
+    //           Shutdown 
+    //================================ 
+    network.shutdown().await;
+
+}
+
+
+async fn run_scenario_file(path: &Path, results: Option<&Path>, seed: Option<u64>) -> anyhow::Result<()> {
+    let mut scenario = ScenarioFile::load(path)?;
+    // --seed reruns a scenario on a different (or a failing run's) network
+    if seed.is_some() {
+        scenario.seed = seed;
+    }
+    let results_path = results.map(Path::to_path_buf).unwrap_or_else(|| scenario.results_path());
+
+    let results = scenario::run(&scenario).await?;
+    println!("Scenario summary: {:?}", results.summary);
+    scenario::write_results(&results_path, &results)?;
+    println!("Results written to {}", results_path.display());
+    Ok(())
+}
 
+
+async fn run_ping(nodes: &[DASNode]) {
     // Overlay Messaging
     // ------------------ 
-    let das_ping = nodes[1].overlay.send_ping(nodes[2].overlay.local_enr());
+    let das_ping = nodes[0].overlay.send_ping(nodes[1].overlay.local_enr());
     das_ping.await;
     // TODO: Send find_nodes 
-    // TODO: Send find_content 
 
     // Secure Overlay Messaging
     // -------------------------- 
-    let secure_das_ping = nodes[1].secure_overlay.send_ping(nodes[2].secure_overlay.local_enr());
+    let secure_das_ping = nodes[0].secure_overlay.send_ping(nodes[1].secure_overlay.local_enr());
     secure_das_ping.await;
     // TODO: Send find_nodes 
-    // TODO: Send find_content 
+}
 
-    //================================ 
-    //         Sanity Check 
-    //================================ 
-    println!("Overlay Protocol ID: {:?}", nodes[2].overlay.protocol()); 
-    println!("Secure Overlay Protocol ID: {:?}", nodes[2].secure_overlay.protocol()); 
 
+async fn run_publish(nodes: &[DASNode]) {
+    // Sample Dissemination
+    // --------------------
+    // Node 0 plays proposer: extend a blob and offer each sample to the 3 closest peers
+    let blob = erasure::blob_from_data(b"DAS Playground").unwrap();
+    match nodes[0].publish_blob(&[blob], 0, 0, erasure::ExtensionMode::OneDimensional, 3).await {
+        Ok(report) => println!("Published blob: {}/{} samples delivered", report.delivered(), report.deliveries.len()),
+        Err(err) => println!("Unable to publish blob: {}", err),
+    }
 }
 
 
-async fn create_node(discv5_struct: Arc<Discovery>) -> (
-        DASNode, 
-        OverlayService<DASContentKey, XorMetric, DASValidator, MemoryContentStore>,
-        OverlayService<SecureDASContentKey, XorMetric, SecureDASValidator, MemoryContentStore>,
-        UnboundedSender<TalkRequest>,
-        UnboundedReceiver<UtpListenerEvent>
-    ) {
+async fn run_sampling<R: Rng>(node: &DASNode, config: &sampling::SamplingConfig, rng: &mut R) {
+    // Sampling
+    // --------
+    let result = node.sample_blob(0, 0, config, rng).await;
+    println!("Sampling verdict: {:?} ({} of {} samples failed, took {:?})", result.verdict, result.failed().len(), result.lookups.len(), result.elapsed);
+}
+
 
-    // DAS Overlay UTP Channel 
+async fn create_node(discv5_struct: Arc<Discovery>, kzg: Arc<KzgSettings>, commitments: BlobCommitments, config: &NodeConfig, transport: Transport) -> (DASNode, NodeServices) {
+    // UTP Channel, shared by both overlays
+    // Every uTP packet is a TalkReq on the same "utp" protocol, so there's no telling which of two
+    // listeners one is for.  Streams carry their overlay's protocol id instead, see the runtime
     let ( utp_events_tx, 
-            utp_listener_tx, mut utp_listener_rx, 
+            utp_listener_tx, utp_listener_rx, 
             mut utp_listener,
     ) = UtpListener::new(discv5_struct.clone());
-    tokio::spawn(async move { utp_listener.start().await });
-    
-    // Secure DAS Overlay UTP Channel 
-    let ( secure_utp_events_tx, 
-            secure_utp_listener_tx, mut secure_utp_listener_rx, 
-            mut secure_utp_listener,
-    ) = UtpListener::new(discv5_struct.clone());
-    tokio::spawn(async move { secure_utp_listener.start().await });
+    let utp_handle = tokio::spawn(async move { utp_listener.start().await; });
+
+    // On the virtual transport the service would ping its bootnodes through the unstarted discv5
+    // server and wait out the request timeout on each.  SimNetwork pings them virtually instead
+    let bootnode_enrs = match &transport {
+        Transport::Virtual(_) => Vec::new(),
+        Transport::Udp(_) => discv5_struct.discv5.table_entries_enr(),
+    };
 
     // DAS and Secure DAS Overlay Protocols
-    let (overlay, overlay_service) = overlay::create_das_overlay(discv5_struct.clone(), utp_listener_tx).await;
-    let (secure_overlay, secure_overlay_service) = overlay::create_secure_das_overlay(discv5_struct.clone(), secure_utp_listener_tx).await;  
+    let (overlay, overlay_service) = overlay::create_das_overlay(discv5_struct.clone(), bootnode_enrs.clone(), utp_listener_tx.clone(), kzg.clone(), commitments.clone(), &config.storage.das).await;
+    let (secure_overlay, secure_overlay_service) = overlay::create_secure_das_overlay(discv5_struct.clone(), bootnode_enrs, utp_listener_tx, kzg.clone(), commitments.clone(), &config.storage.secure_das).await;  
 
     //  Samples: TODO
     
     //  Handled_ids: TODO 
 
     // Creates node (Timofey creates node with utp_listener_tx) 
-    let mut my_node = DASNode::new(discv5_struct, overlay, secure_overlay);
+    let mut my_node = DASNode::new(discv5_struct, overlay, secure_overlay, kzg.clone(), commitments.clone(), transport.clone());
+    // uTP packets arrive as TalkReqs too.  The listener answers them itself
+    my_node.router.forward(ProtocolId::Utp, utp_events_tx);
+    // Over UDP the router holds back what this node receives for as long as its links say
+    if let Transport::Udp(Some(links)) = &transport {
+        let node_id = my_node.overlay.local_enr().node_id();
+        links.place(node_id);
+        my_node.router.shape(node_id, links.clone());
+    }
+
+    // On the virtual transport other nodes reach this one through the virtual network
+    let virtual_link = match &transport {
+        Transport::Virtual(network) => {
+            let das: Arc<dyn VirtualPeer> = Arc::new(VirtualOverlay::new(my_node.overlay.clone(), Arc::new(DASValidator::new(kzg.clone(), commitments.clone())), my_node.adversary.clone()));
+            let secure_das: Arc<dyn VirtualPeer> = Arc::new(VirtualOverlay::new(my_node.secure_overlay.clone(), Arc::new(SecureDASValidator::new(kzg, commitments)), my_node.adversary.clone()));
+            Some(network.register(
+                my_node.overlay.local_enr().node_id(),
+                vec![(my_node.overlay.protocol().clone(), das), (my_node.secure_overlay.protocol().clone(), secure_das)],
+            ))
+        }
+        Transport::Udp(_) => None,
+    };
     
-    (
-        my_node,
+    // Samples expire after the retention window
+    let mut background_tasks = Vec::new();
+    if let Some(pruning) = &config.pruning {
+        background_tasks.push(pruning::spawn_pruning("DAS".to_string(), my_node.overlay.store.clone(), config.clock, pruning.clone(), my_node.stats.clone()));
+        background_tasks.push(pruning::spawn_pruning("SecureDAS".to_string(), my_node.secure_overlay.store.clone(), config.clock, pruning.clone(), my_node.stats.clone()));
+    }
+
+    let services = NodeServices {
         overlay_service,
         secure_overlay_service,
-        utp_events_tx,
-        utp_listener_rx
-    ) 
-}
-
-
-// Adds nodes from within the simulation to routing tables.  
-fn populate_discv5_table(local_index: usize, mut structs: Vec<Arc<Discovery>>) {
-    // Number of peers a node adds to their routing table 
-    let mut n = 3;
-    let mut used_indexes = Vec::new();
-
-    while n != 0 {
-        let mut invalid_index = false; 
-        let mut rng = rand::thread_rng();
-        let rand = rng.gen_range(0usize..NUMBER_OF_NODES);
+        utp_listener_rx,
+        utp_listeners: vec![utp_handle],
+        background_tasks,
+        virtual_link,
+    };
 
-        // Makes sure we aren't duplicating nodes within our routing table 
-        for i in 0..used_indexes.len() {
-            if rand == used_indexes[i] || rand == local_index {
-                invalid_index = true; 
-            }
-        }
-        
-        if invalid_index == false {
-            match structs[local_index].discv5.add_enr(structs[rand].discv5.local_enr().clone()) {
-                Ok(_) => {
-                    used_indexes.push(rand);
-                    n -= 1;
-                },
-                Err(_) => continue,
-            }
-        }
-    }
+    (my_node, services)
 }
\ No newline at end of file
@@ -0,0 +1,381 @@
+use anyhow::ensure;
+use bls12_381::{G1Affine, G1Projective, Scalar};
+use c_kzg::{
+    Blob,
+    KzgSettings,
+    BYTES_PER_BLOB,
+    BYTES_PER_FIELD_ELEMENT,
+};
+use std::collections::BTreeMap;
+
+use crate::{
+    content_key::{DASContentKey, LineKey},
+    erasure::{self, ExtendedBlob, ReconstructionError, SAMPLES_PER_BLOB},
+    sample::DASSample,
+};
+
+/*
+    Two-dimensional extension.
+
+    The n blobs of a slot are the rows of a matrix.  Each row is extended 2x on its own (erasure.rs),
+    then every column is extended 2x across the rows, giving a 2n x SAMPLES_PER_BLOB matrix of cells.
+    Any row can be repaired from half of its cells, and so can any column.
+
+    Column extension is Reed-Solomon over the BLS12-381 scalar field, with row r evaluated at x = r.
+    Everything involved is linear in the row polynomial, so the same Lagrange coefficients that give
+    an extension row's data also give its cells and its KZG proofs (as G1 points).  That is what lets a
+    column be repaired WITH proofs, without ever seeing the rest of the rows.
+
+    Notes:
+        - Lagrange interpolation is O(n^2) per field element.  n is blobs per slot, so it's small
+        - Extension rows get their own commitments, computed the same way as any other blob
+*/
+
+/// A row or a column of the extended matrix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Line {
+    Row(u64),
+    Column(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct ExtendedMatrix {
+    pub slot: u64,
+    pub blob_index: u64,
+    /// 2n rows.  The first n are the original blobs.
+    pub rows: Vec<ExtendedBlob>,
+}
+
+impl ExtendedMatrix {
+    pub fn original_rows(&self) -> usize {
+        self.rows.len() / 2
+    }
+
+    pub fn row_commitments(&self) -> Vec<Vec<u8>> {
+        self.rows.iter().map(|row| row.commitment.clone()).collect()
+    }
+
+    pub fn line(&self, line: Line) -> Vec<DASSample> {
+        match line {
+            Line::Row(r) => self.rows[r as usize].samples.clone(),
+            Line::Column(c) => self.rows.iter().map(|row| row.samples[c as usize].clone()).collect(),
+        }
+    }
+
+    pub fn cell_keys(&self) -> Vec<DASContentKey> {
+        self.rows.iter().flat_map(|row| row.cell_keys()).collect()
+    }
+
+    pub fn row_keys(&self) -> Vec<DASContentKey> {
+        (0..self.rows.len() as u64)
+            .map(|r| DASContentKey::Row(LineKey::new(self.slot, self.blob_index, r)))
+            .collect()
+    }
+
+    pub fn column_keys(&self) -> Vec<DASContentKey> {
+        (0..SAMPLES_PER_BLOB as u64)
+            .map(|c| DASContentKey::Column(LineKey::new(self.slot, self.blob_index, c)))
+            .collect()
+    }
+}
+
+/// Extends blobs in both dimensions.
+pub fn extend_blobs_2d(kzg: &KzgSettings, blobs: &[Blob], slot: u64, blob_index: u64) -> anyhow::Result<ExtendedMatrix> {
+    ensure!(!blobs.is_empty(), "Nothing to extend");
+    let n = blobs.len() as u64;
+
+    let original = blobs
+        .iter()
+        .map(|blob| bytes_to_scalars(blob.as_ref()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let points: Vec<u64> = (0..n).collect();
+
+    // Extension rows n..2n, one field element at a time
+    let mut all_blobs = blobs.to_vec();
+    for target in n..2 * n {
+        let coefficients = lagrange_coefficients(&points, target);
+        let elements: Vec<Scalar> = (0..original[0].len())
+            .map(|i| combine(&coefficients, original.iter().map(|row| row[i])))
+            .collect();
+
+        let mut bytes = [0u8; BYTES_PER_BLOB];
+        bytes.copy_from_slice(&scalars_to_bytes(&elements));
+        all_blobs.push(Blob::new(bytes));
+    }
+
+    let mut rows = Vec::with_capacity(all_blobs.len());
+    for (r, blob) in all_blobs.iter().enumerate() {
+        let mut row = erasure::extend_blob(kzg, blob, slot, blob_index)?;
+        row.row = r as u64;
+        rows.push(row);
+    }
+
+    Ok(ExtendedMatrix {
+        slot,
+        blob_index,
+        rows,
+    })
+}
+
+/// Rebuilds every cell of a row or column from half of its cells.
+/// `cells` are (position along the line, sample content) pairs: the column index for a row, the row index for a column.
+pub fn repair_line(kzg: &KzgSettings, row_commitments: &[Vec<u8>], line: Line, cells: &[(u64, Vec<u8>)]) -> Result<Vec<DASSample>, ReconstructionError> {
+    match line {
+        Line::Row(r) => {
+            let commitment = row_commitments
+                .get(r as usize)
+                .ok_or_else(|| ReconstructionError::Kzg(format!("No commitment for row {}", r)))?;
+            erasure::reconstruct_blob(kzg, commitment, cells).map(|reconstruction| reconstruction.samples)
+        }
+        Line::Column(c) => repair_column(kzg, row_commitments, c, cells),
+    }
+}
+
+fn repair_column(kzg: &KzgSettings, row_commitments: &[Vec<u8>], column: u64, cells: &[(u64, Vec<u8>)]) -> Result<Vec<DASSample>, ReconstructionError> {
+    let total = row_commitments.len() as u64;
+    let needed = row_commitments.len() / 2;
+
+    let mut valid: BTreeMap<u64, DASSample> = BTreeMap::new();
+    let mut inconsistent = Vec::new();
+    for (row, content) in cells {
+        let sample = match DASSample::from_bytes(content) {
+            Ok(sample) if *row < total && sample.cell_index == column && sample.commitment == row_commitments[*row as usize] => sample,
+            _ => {
+                inconsistent.push(*row);
+                continue;
+            }
+        };
+        // Same as erasure::reconstruct_blob: a verified cell stands, a differing duplicate is junk
+        if let Some(existing) = valid.get(row) {
+            if *existing != sample {
+                inconsistent.push(*row);
+            }
+            continue;
+        }
+        if sample.verify(kzg).is_err() {
+            inconsistent.push(*row);
+            continue;
+        }
+        valid.insert(*row, sample);
+    }
+    inconsistent.sort_unstable();
+
+    if valid.len() < needed {
+        let missing = (0..total)
+            .filter(|r| !valid.contains_key(r) && !inconsistent.contains(r))
+            .collect();
+        return Err(ReconstructionError::NotEnoughSamples {
+            valid: valid.len(),
+            needed,
+            missing,
+            inconsistent,
+        });
+    }
+
+    // Any `needed` rows pin down the column polynomial
+    let points: Vec<u64> = valid.keys().copied().take(needed).collect();
+    let mut known_cells = Vec::with_capacity(needed);
+    let mut known_proofs = Vec::with_capacity(needed);
+    for row in points.iter() {
+        let sample = &valid[row];
+        known_cells.push(bytes_to_scalars(&sample.cell).map_err(|err| ReconstructionError::Kzg(err.to_string()))?);
+        known_proofs.push(decode_point(&sample.proof)?);
+    }
+
+    let mut samples = Vec::with_capacity(total as usize);
+    for row in 0..total {
+        if let Some(sample) = valid.get(&row) {
+            samples.push(sample.clone());
+            continue;
+        }
+
+        let coefficients = lagrange_coefficients(&points, row);
+        let cell: Vec<Scalar> = (0..known_cells[0].len())
+            .map(|i| combine(&coefficients, known_cells.iter().map(|cell| cell[i])))
+            .collect();
+        let proof = coefficients
+            .iter()
+            .zip(known_proofs.iter())
+            .fold(G1Projective::identity(), |acc, (c, p)| acc + p * c);
+
+        samples.push(DASSample {
+            commitment: row_commitments[row as usize].clone(),
+            cell_index: column,
+            cell: scalars_to_bytes(&cell),
+            proof: G1Affine::from(proof).to_compressed().to_vec(),
+        });
+    }
+
+    Ok(samples)
+}
+
+/// L_i(target) for every point i.  Points are distinct row indices.
+fn lagrange_coefficients(points: &[u64], target: u64) -> Vec<Scalar> {
+    let x = Scalar::from(target);
+    points
+        .iter()
+        .map(|&i| {
+            let xi = Scalar::from(i);
+            let (num, den) = points
+                .iter()
+                .filter(|&&j| j != i)
+                .fold((Scalar::one(), Scalar::one()), |(num, den), &j| {
+                    let xj = Scalar::from(j);
+                    (num * (x - xj), den * (xi - xj))
+                });
+            num * den.invert().unwrap()
+        })
+        .collect()
+}
+
+fn combine(coefficients: &[Scalar], values: impl Iterator<Item = Scalar>) -> Scalar {
+    coefficients
+        .iter()
+        .zip(values)
+        .fold(Scalar::zero(), |acc, (c, v)| acc + c * v)
+}
+
+// c-kzg field elements are big endian, bls12_381 scalars are little endian
+fn bytes_to_scalars(bytes: &[u8]) -> anyhow::Result<Vec<Scalar>> {
+    bytes
+        .chunks(BYTES_PER_FIELD_ELEMENT)
+        .map(|chunk| {
+            let mut le = [0u8; 32];
+            le.copy_from_slice(chunk);
+            le.reverse();
+            Option::<Scalar>::from(Scalar::from_bytes(&le))
+                .ok_or_else(|| anyhow::anyhow!("Non-canonical field element"))
+        })
+        .collect()
+}
+
+fn scalars_to_bytes(scalars: &[Scalar]) -> Vec<u8> {
+    scalars
+        .iter()
+        .flat_map(|s| {
+            let mut be = s.to_bytes();
+            be.reverse();
+            be
+        })
+        .collect()
+}
+
+fn decode_point(bytes: &[u8]) -> Result<G1Projective, ReconstructionError> {
+    let mut compressed = [0u8; 48];
+    compressed.copy_from_slice(bytes);
+    Option::<G1Affine>::from(G1Affine::from_compressed(&compressed))
+        .map(|p| G1Projective::from(&p))
+        .ok_or_else(|| ReconstructionError::Kzg("Invalid proof point".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{erasure::ExtensionMode, sample};
+
+    fn kzg() -> KzgSettings {
+        sample::load_trusted_setup(sample::TRUSTED_SETUP_PATH).unwrap()
+    }
+
+    fn matrix(kzg: &KzgSettings) -> ExtendedMatrix {
+        let blobs: Vec<Blob> = [&b"first row"[..], &b"second row"[..]]
+            .iter()
+            .map(|data| erasure::blob_from_data(data).unwrap())
+            .collect();
+        extend_blobs_2d(kzg, &blobs, 5, 0).unwrap()
+    }
+
+    fn cells(samples: &[DASSample], positions: impl Iterator<Item = usize>) -> Vec<(u64, Vec<u8>)> {
+        positions.map(|i| (i as u64, samples[i].to_bytes())).collect()
+    }
+
+    #[test]
+    fn extension_doubles_the_rows_and_every_cell_verifies() {
+        let kzg = kzg();
+        let extended = matrix(&kzg);
+
+        assert_eq!(extended.rows.len(), 4);
+        assert_eq!(extended.original_rows(), 2);
+        assert_eq!(extended.row_keys().len(), 4);
+        assert_eq!(extended.column_keys().len(), SAMPLES_PER_BLOB);
+        assert_eq!(extended.cell_keys().len(), 4 * SAMPLES_PER_BLOB);
+
+        let first = erasure::extend_blob(&kzg, &erasure::blob_from_data(b"first row").unwrap(), 5, 0).unwrap();
+        assert_eq!(extended.rows[0].commitment, first.commitment);
+        for (r, row) in extended.rows.iter().enumerate() {
+            assert_eq!(row.row, r as u64);
+            for sample in row.samples.iter() {
+                assert_eq!(sample.commitment, row.commitment);
+                sample.verify(&kzg).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn repairs_a_column_from_half_of_its_cells() {
+        let kzg = kzg();
+        let extended = matrix(&kzg);
+        let column = extended.line(Line::Column(7));
+
+        // Extension rows only
+        let repaired = repair_line(&kzg, &extended.row_commitments(), Line::Column(7), &cells(&column, 2..4)).unwrap();
+        assert_eq!(repaired, column);
+        for sample in repaired.iter() {
+            sample.verify(&kzg).unwrap();
+        }
+    }
+
+    #[test]
+    fn repairs_a_row_from_half_of_its_cells() {
+        let kzg = kzg();
+        let extended = matrix(&kzg);
+        let row = extended.line(Line::Row(3));
+
+        let given = cells(&row, (0..SAMPLES_PER_BLOB).step_by(2));
+        let repaired = repair_line(&kzg, &extended.row_commitments(), Line::Row(3), &given).unwrap();
+        assert_eq!(repaired, row);
+    }
+
+    #[test]
+    fn a_bad_duplicate_doesnt_cost_a_verified_cell() {
+        let kzg = kzg();
+        let extended = matrix(&kzg);
+        let column = extended.line(Line::Column(0));
+
+        let mut tampered = column[1].clone();
+        tampered.cell[BYTES_PER_FIELD_ELEMENT - 1] ^= 1;
+        let mut given = cells(&column, 0..2);
+        given.push((1, tampered.to_bytes()));
+        given.push((0, vec![0; 4]));
+
+        let repaired = repair_line(&kzg, &extended.row_commitments(), Line::Column(0), &given).unwrap();
+        assert_eq!(repaired, column);
+    }
+
+    #[test]
+    fn junk_alone_is_not_enough() {
+        let kzg = kzg();
+        let extended = matrix(&kzg);
+        let column = extended.line(Line::Column(0));
+
+        let mut given = cells(&column, 0..1);
+        given.push((1, vec![0; 4]));
+        given.push((2, column[3].to_bytes()));
+
+        match repair_line(&kzg, &extended.row_commitments(), Line::Column(0), &given) {
+            Err(ReconstructionError::NotEnoughSamples { valid, needed, missing, inconsistent }) => {
+                assert_eq!((valid, needed), (1, 2));
+                assert_eq!(missing, vec![3]);
+                assert_eq!(inconsistent, vec![1, 2]);
+            }
+            other => panic!("Expected NotEnoughSamples, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_quarter_of_the_matrix_can_determine_it() {
+        let total = 4 * SAMPLES_PER_BLOB;
+        assert_eq!(ExtensionMode::TwoDimensional.samples_needed(total), SAMPLES_PER_BLOB);
+        assert_eq!(ExtensionMode::OneDimensional.samples_needed(SAMPLES_PER_BLOB), erasure::SAMPLES_NEEDED_FOR_RECOVERY);
+    }
+}
@@ -0,0 +1,271 @@
+use c_kzg::KzgSettings;
+use discv5::enr::CombinedKey;
+use discv5_overlay::portalnet::discovery::Discovery;
+use futures::future::join_all;
+use rand::Rng;
+use serde::Serialize;
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    adversary::AdversaryPolicy,
+    discovery,
+    links::LinkShaper,
+    node_struct::{DASNode, NodeConfig},
+    runtime::{self, NodeRuntime},
+    sample::{self, BlobCommitments},
+    topology::{self, Topology, TopologyStats},
+    transport::{Transport, TransportConfig, VirtualNetwork},
+};
+
+/*
+    A whole simulated network: every node plus the runtime processing its messages.
+
+    Same flow as always: create every discv5 server, populate the discv5 tables from the topology
+    (see topology.rs), then create_node and start a runtime for each.  Nodes keep their index for the whole run, even while stopped.
+    Nodes added later with add_node (e.g. eclipse attackers) get the next indices.
+
+    Restarting a node runs create_node again on the node's discv5 server, so it comes back with the
+    same node id and discv5 table but fresh overlays.  In-memory stores start out empty, disk stores
+    pick up where they left off.
+
+    Determinism:
+        Node keys and the discv5 tables are drawn from the rng passed to start, in that order.  With
+        the same seed, node i has the same node id and the same peers on every run.  Message timing
+        is still up to the OS and tokio, so lookups racing each other can finish differently.
+
+    Transport:
+        On the virtual transport (see transport.rs) no sockets are bound and base_port only ends up
+        in the ENRs.  Once every node is up, each one pings its discv5 peers on both overlays so the
+        overlay routing tables start out connected.
+*/
+
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    pub nodes: usize,
+    pub topology: Topology,
+    pub base_port: u16,
+    pub request_timeout: Duration,
+    pub node: NodeConfig,
+    pub transport: TransportConfig,
+}
+
+pub struct SimNetwork {
+    pub nodes: Vec<DASNode>,
+    runtimes: Vec<Option<NodeRuntime>>,
+    discoveries: Vec<Arc<Discovery>>,
+    node_config: NodeConfig,
+    base_port: u16,
+    request_timeout: Duration,
+    /// Kept here so a node restarts with the policy it had
+    policies: Vec<Option<AdversaryPolicy>>,
+    pub kzg: Arc<KzgSettings>,
+    /// Stand-in for the beacon chain: every node sees the same blob commitments.
+    pub commitments: BlobCommitments,
+    pub transport: Transport,
+    pub topology: TopologyStats,
+}
+
+/// Routing table sizes across every running node.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TableSizes {
+    pub min: usize,
+    pub mean: f64,
+    pub max: usize,
+}
+
+impl TableSizes {
+    fn of(sizes: &[usize]) -> Self {
+        if sizes.is_empty() {
+            return Self::default();
+        }
+        Self {
+            min: *sizes.iter().min().unwrap(),
+            mean: sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+            max: *sizes.iter().max().unwrap(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RoutingTableStats {
+    pub discv5: TableSizes,
+    pub overlay: TableSizes,
+    pub secure_overlay: TableSizes,
+}
+
+impl SimNetwork {
+    pub async fn start<R: Rng>(config: &NetworkConfig, rng: &mut R) -> Self {
+        // Every validator shares the same trusted setup.  Load it once.
+        let kzg = Arc::new(sample::load_trusted_setup(sample::TRUSTED_SETUP_PATH).unwrap());
+        let commitments = BlobCommitments::new();
+        let transport = match &config.transport {
+            TransportConfig::Udp(links) => Transport::Udp(links.clone().map(|links| LinkShaper::new(links, rng.gen()))),
+            TransportConfig::Virtual(virtual_config) => Transport::Virtual(VirtualNetwork::new(virtual_config.clone(), rng.gen())),
+        };
+
+        // Create all Discv5 servers, then pass these into create_nodes.  Node keys come from rng
+        let mut discv5_structs = Vec::with_capacity(config.nodes);
+        for i in 0..config.nodes {
+            let enr_key = discovery::node_key(rng);
+            discv5_structs.push(discovery::create_discovery(i, config.base_port, config.request_timeout, enr_key, !transport.is_virtual()).await);
+        }
+
+        // Populate discv5 tables
+        let adjacency = config.topology.generate(config.nodes, rng);
+        let topology = topology::apply(&adjacency, &discv5_structs);
+        println!("Topology {:?}: {:?}", config.topology, topology);
+
+        // Instantiates protocol structs and message processing within each node
+        let mut nodes = Vec::with_capacity(config.nodes);
+        let mut runtimes = Vec::with_capacity(config.nodes);
+        for discv5_struct in discv5_structs.iter().cloned() {
+            let (node, services) = crate::create_node(discv5_struct, kzg.clone(), commitments.clone(), &config.node, transport.clone()).await;
+            runtimes.push(Some(NodeRuntime::start(node.clone(), services, runtime::DEFAULT_DRAIN_TIMEOUT).await));
+            nodes.push(node);
+        }
+
+        let network = Self {
+            nodes,
+            runtimes,
+            policies: vec![None; discv5_structs.len()],
+            discoveries: discv5_structs,
+            node_config: config.node.clone(),
+            base_port: config.base_port,
+            request_timeout: config.request_timeout,
+            kzg,
+            commitments,
+            transport,
+            topology,
+        };
+        if network.transport.is_virtual() {
+            network.ping_discv5_peers().await;
+        }
+
+        network
+    }
+
+    /// Routing table sizes of every running node, on discv5 and both overlays.
+    pub fn routing_tables(&self) -> RoutingTableStats {
+        self.routing_tables_of(&self.running())
+    }
+
+    /// Same as routing_tables, over the running nodes among `nodes`.
+    pub fn routing_tables_of(&self, nodes: &[usize]) -> RoutingTableStats {
+        let running: Vec<usize> = nodes.iter().copied().filter(|&i| self.is_running(i)).collect();
+        let sizes = |size: &dyn Fn(&DASNode) -> usize| -> TableSizes {
+            TableSizes::of(&running.iter().map(|&i| size(&self.nodes[i])).collect::<Vec<_>>())
+        };
+        RoutingTableStats {
+            discv5: sizes(&|node| node.discovery.discv5.table_entries_id().len()),
+            overlay: sizes(&|node| node.overlay.table_entries_id().len()),
+            secure_overlay: sizes(&|node| node.secure_overlay.table_entries_id().len()),
+        }
+    }
+
+    /// Every node pings the peers in its discv5 table on both overlays.
+    pub async fn ping_discv5_peers(&self) {
+        join_all(self.nodes.iter().map(ping_peers)).await;
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn is_running(&self, i: usize) -> bool {
+        matches!(self.runtimes.get(i), Some(Some(runtime)) if !runtime.is_finished())
+    }
+
+    /// Indices of every node whose runtime is up.
+    pub fn running(&self) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|&i| self.is_running(i)).collect()
+    }
+
+    /// Starts one more node with the given key and returns its index.  It knows no one: add ENRs to
+    /// its discv5 table and ping from it to connect it.
+    pub async fn add_node(&mut self, enr_key: CombinedKey) -> usize {
+        let i = self.nodes.len();
+        let discovery = discovery::create_discovery(i, self.base_port, self.request_timeout, enr_key, !self.transport.is_virtual()).await;
+        let (node, services) = crate::create_node(discovery.clone(), self.kzg.clone(), self.commitments.clone(), &self.node_config, self.transport.clone()).await;
+        self.runtimes.push(Some(NodeRuntime::start(node.clone(), services, runtime::DEFAULT_DRAIN_TIMEOUT).await));
+        self.nodes.push(node);
+        self.discoveries.push(discovery);
+        self.policies.push(None);
+        i
+    }
+
+    /// Stops node i's runtime.  It stays in `nodes` but stops answering overlay requests.  Over UDP its
+    /// discv5 server keeps answering, there's no way to shut down a shared Discovery.
+    pub async fn stop_node(&mut self, i: usize) {
+        if let Some(runtime) = self.runtimes.get_mut(i).and_then(Option::take) {
+            if let Err(err) = runtime.shutdown().await {
+                println!("Node {} runtime panicked: {}", i, err);
+            }
+        }
+    }
+
+    /// Switches node i to a byzantine policy, or back to honest with None.
+    pub fn set_policy(&mut self, i: usize, policy: Option<AdversaryPolicy>) {
+        self.nodes[i].set_policy(policy.clone());
+        self.policies[i] = policy;
+    }
+
+    /// Brings a stopped node back with the same node id.  Does nothing if it's running.
+    pub async fn restart_node(&mut self, i: usize) {
+        if self.is_running(i) {
+            return;
+        }
+        self.stop_node(i).await;
+
+        let (node, services) = crate::create_node(
+            self.discoveries[i].clone(),
+            self.kzg.clone(),
+            self.commitments.clone(),
+            &self.node_config,
+            self.transport.clone(),
+        )
+        .await;
+        node.set_policy(self.policies[i].clone());
+        self.runtimes[i] = Some(NodeRuntime::start(node.clone(), services, runtime::DEFAULT_DRAIN_TIMEOUT).await);
+        self.nodes[i] = node;
+
+        if self.transport.is_virtual() {
+            ping_peers(&self.nodes[i]).await;
+        }
+    }
+
+    pub async fn shutdown(mut self) {
+        for i in 0..self.nodes.len() {
+            self.stop_node(i).await;
+        }
+    }
+}
+
+/// Pings every peer in the node's discv5 table on both overlays.
+async fn ping_peers(node: &DASNode) {
+    join_all(node.discovery.discv5.table_entries_enr().into_iter().map(|enr| async move {
+        futures::join!(node.overlay.send_ping(enr.clone()), node.secure_overlay.send_ping(enr));
+    }))
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_sizes_summarize_every_table() {
+        let sizes = TableSizes::of(&[4, 1, 7]);
+        assert_eq!((sizes.min, sizes.max), (1, 7));
+        assert_eq!(sizes.mean, 4.0);
+
+        let none = TableSizes::of(&[]);
+        assert_eq!((none.min, none.mean, none.max), (0, 0.0, 0));
+    }
+}
@@ -2,25 +2,108 @@ use discv5_overlay::{
     portalnet::{
         discovery::Discovery, 
         overlay::OverlayProtocol, 
-        storage::MemoryContentStore, 
         types::distance::XorMetric
     }, 
 };
-use std::sync::Arc;
+use c_kzg::KzgSettings;
+use discv5::enr::NodeId;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use crate::content_key::{
-    DASContentKey, 
-    DASValidator,
-    SecureDASContentKey,
-    SecureDASValidator,
+use crate::{
+    adversary::{Adversary, AdversaryPolicy, GuardedHandler},
+    content_key::{
+        DASContentKey, 
+        DASValidator,
+        SecureDASContentKey,
+        SecureDASValidator,
+    },
+    pruning::{PruningConfig, SlotClock},
+    router::ProtocolRouter,
+    sample::BlobCommitments,
+    storage::{DASContentStore, EvictionStats, PruneReport, StorageConfig},
+    transport::{OfferTransfers, Transport},
 };
 
 
+/// Per-node settings create_node needs besides the discovery struct.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    pub storage: StorageConfig,
+    /// None keeps every sample forever
+    pub pruning: Option<PruningConfig>,
+    pub clock: SlotClock,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            storage: StorageConfig::default(),
+            pruning: Some(PruningConfig::default()),
+            clock: SlotClock::starting_now(),
+        }
+    }
+}
+
+/// Counters a node keeps about itself.
+#[derive(Debug, Default)]
+pub struct NodeStats {
+    pub samples_pruned: AtomicUsize,
+    pub bytes_pruned: AtomicUsize,
+    /// Peers that answered a lookup with content failing verification -> how many times
+    pub offenders: Mutex<HashMap<NodeId, usize>>,
+}
+
+impl NodeStats {
+    pub fn record_prune(&self, report: &PruneReport) {
+        self.samples_pruned.fetch_add(report.removed, Ordering::Relaxed);
+        self.bytes_pruned.fetch_add(report.bytes_freed, Ordering::Relaxed);
+    }
+
+    pub fn record_offenders(&self, offenders: &[NodeId]) {
+        let mut counts = self.offenders.lock();
+        for offender in offenders {
+            *counts.entry(*offender).or_default() += 1;
+        }
+    }
+}
+
+/// Point in time view of a node's stats, including what its stores know.
+#[derive(Clone, Debug, Default)]
+pub struct StatsSnapshot {
+    pub samples_pruned: usize,
+    pub bytes_pruned: usize,
+    /// Distinct peers caught serving invalid content
+    pub offenders: usize,
+    pub das_stored: usize,
+    pub das_bytes: usize,
+    pub das_evictions: EvictionStats,
+    pub secure_das_stored: usize,
+    pub secure_das_bytes: usize,
+    pub secure_das_evictions: EvictionStats,
+}
+
+
 #[derive(Clone)]
 pub struct DASNode {
     pub discovery: Arc<Discovery>,
-    pub overlay: Arc<OverlayProtocol<DASContentKey, XorMetric, DASValidator, MemoryContentStore>>,
-    pub secure_overlay: Arc<OverlayProtocol<SecureDASContentKey, XorMetric, SecureDASValidator, MemoryContentStore>>,
+    pub overlay: Arc<OverlayProtocol<DASContentKey, XorMetric, DASValidator, DASContentStore>>,
+    pub secure_overlay: Arc<OverlayProtocol<SecureDASContentKey, XorMetric, SecureDASValidator, DASContentStore>>,
+    pub kzg: Arc<KzgSettings>,
+    pub commitments: BlobCommitments,
+    pub router: ProtocolRouter,
+    pub stats: Arc<NodeStats>,
+    pub transport: Transport,
+    /// How the content of our accepted Offers got to the peers, see publish.rs
+    pub transfers: OfferTransfers,
+    /// Byzantine behaviour, if any.  Honest by default
+    pub adversary: Adversary,
     
     samples: [u8; 8],
     pub handled_ids: i32,
@@ -30,15 +113,83 @@ pub struct DASNode {
 impl DASNode {
     pub fn new(
         discovery: Arc<Discovery>,
-        overlay: Arc<OverlayProtocol<DASContentKey, XorMetric, DASValidator, MemoryContentStore>>,
-        secure_overlay: Arc<OverlayProtocol<SecureDASContentKey, XorMetric, SecureDASValidator, MemoryContentStore>>,
+        overlay: Arc<OverlayProtocol<DASContentKey, XorMetric, DASValidator, DASContentStore>>,
+        secure_overlay: Arc<OverlayProtocol<SecureDASContentKey, XorMetric, SecureDASValidator, DASContentStore>>,
+        kzg: Arc<KzgSettings>,
+        commitments: BlobCommitments,
+        transport: Transport,
     ) -> Self {
+        // Every overlay this node takes part in.  TalkReqs are dispatched by protocol id, through the adversary policy
+        let adversary = Adversary::default();
+        let mut router = ProtocolRouter::new();
+        router.register(overlay.protocol().clone(), Arc::new(GuardedHandler::new(overlay.clone(), adversary.clone())));
+        router.register(secure_overlay.protocol().clone(), Arc::new(GuardedHandler::new(secure_overlay.clone(), adversary.clone())));
+
         Self {
             discovery,
             overlay,
             secure_overlay,
+            kzg,
+            commitments,
+            router,
+            stats: Arc::new(NodeStats::default()),
+            transport,
+            transfers: OfferTransfers::default(),
+            adversary,
             samples: [0; 8],       
             handled_ids: 0,
         }
     }
-}
\ No newline at end of file
+
+    /// Switches the node to a byzantine policy, or back to honest with None.
+    pub fn set_policy(&self, policy: Option<AdversaryPolicy>) {
+        let corrupt = policy.as_ref().and_then(|p| p.corrupt.clone());
+        self.overlay.store.write().serve_corrupted(corrupt.clone());
+        self.secure_overlay.store.write().serve_corrupted(corrupt);
+        self.adversary.set(policy);
+    }
+
+    pub fn stats_snapshot(&self) -> StatsSnapshot {
+        let store = self.overlay.store.read();
+        let secure_store = self.secure_overlay.store.read();
+
+        StatsSnapshot {
+            samples_pruned: self.stats.samples_pruned.load(Ordering::Relaxed),
+            bytes_pruned: self.stats.bytes_pruned.load(Ordering::Relaxed),
+            offenders: self.stats.offenders.lock().len(),
+            das_stored: store.len(),
+            das_bytes: store.size(),
+            das_evictions: store.evictions(),
+            secure_das_stored: secure_store.len(),
+            secure_das_bytes: secure_store.size(),
+            secure_das_evictions: secure_store.evictions(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunes_add_up() {
+        let stats = NodeStats::default();
+        stats.record_prune(&PruneReport { removed: 3, bytes_freed: 300 });
+        stats.record_prune(&PruneReport { removed: 1, bytes_freed: 50 });
+
+        assert_eq!(stats.samples_pruned.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.bytes_pruned.load(Ordering::Relaxed), 350);
+    }
+
+    #[test]
+    fn offenders_are_counted_per_peer() {
+        let stats = NodeStats::default();
+        let (liar, other) = (NodeId::random(), NodeId::random());
+        stats.record_offenders(&[liar, other]);
+        stats.record_offenders(&[liar]);
+
+        let offenders = stats.offenders.lock();
+        assert_eq!(offenders.get(&liar), Some(&2));
+        assert_eq!(offenders.get(&other), Some(&1));
+    }
+}
@@ -1,3 +1,4 @@
+use discv5::{enr::NodeId, Enr};
 use discv5_overlay::{
     portalnet::{
         discovery::Discovery,
@@ -6,25 +7,35 @@ use discv5_overlay::{
             OverlayProtocol
         },
         overlay_service::OverlayService,
-        storage::{DistanceFunction, MemoryContentStore},
+        storage::ContentStore,
         types::{
-            distance::{Distance, XorMetric},
-            messages::ProtocolId
+            content_key::OverlayContentKey,
+            distance::XorMetric,
+            messages::{CustomPayload, ProtocolId, Request, Response}
         }
     },
+    types::validation::Validator,
     utp::stream::UtpListenerRequest,
 };
+use c_kzg::KzgSettings;
+use futures::future::join_all;
+use ssz::Encode;
 use std::{
+    collections::HashSet,
     sync::Arc,
     time::Duration
 };
 use tokio::sync::mpsc;
 
-use crate::content_key::{
-    DASContentKey,
-    DASValidator,
-    SecureDASContentKey, 
-    SecureDASValidator, 
+use crate::{
+    content_key::{
+        DASContentKey,
+        DASValidator,
+        SecureDASContentKey, 
+        SecureDASValidator, 
+    },
+    sample::BlobCommitments,
+    storage::{DASContentStore, StoreConfig},
 };
 
 const DAS_PROTOCOL_ID: &str = "DAS";
@@ -53,12 +64,12 @@ const SECURE_DAS_PROTOCOL_ID: &str = "SECURE_DAS";
 //
 // I'm spending a lot of time on complexities within Rust.  Make simple overlay creation functions for now.
 // Circle back once I've implemented the message proxy
-pub async fn create_das_overlay(discovery: Arc<Discovery>, utp_listener_tx: mpsc::UnboundedSender<UtpListenerRequest>) -> (
-    Arc<OverlayProtocol<DASContentKey, XorMetric, DASValidator, MemoryContentStore>>, 
-    OverlayService<DASContentKey, XorMetric, DASValidator, MemoryContentStore>,
+pub async fn create_das_overlay(discovery: Arc<Discovery>, bootnode_enrs: Vec<Enr>, utp_listener_tx: mpsc::UnboundedSender<UtpListenerRequest>, kzg: Arc<KzgSettings>, commitments: BlobCommitments, storage: &StoreConfig) -> (
+    Arc<OverlayProtocol<DASContentKey, XorMetric, DASValidator, DASContentStore>>, 
+    OverlayService<DASContentKey, XorMetric, DASValidator, DASContentStore>,
 ){
     let config = OverlayConfig {
-        bootnode_enrs: discovery.discv5.table_entries_enr(),
+        bootnode_enrs,
         ping_queue_interval: Some(Duration::from_secs(10000)),
         query_num_results: usize::MAX,
         query_timeout: Duration::from_secs(60),
@@ -66,22 +77,21 @@ pub async fn create_das_overlay(discovery: Arc<Discovery>, utp_listener_tx: mpsc
         ..Default::default()
     };
     // println!("Overlay config bootnodes *OVERLAY*: {:?}", config.bootnode_enrs);
-    let storage = {
-        Arc::new(parking_lot::RwLock::new(MemoryContentStore::new(
-            discovery.discv5.local_enr().node_id(),
-            DistanceFunction::Xor,
-        )))
-    };
+    let store = DASContentStore::new(storage, discovery.discv5.local_enr().node_id(), DAS_PROTOCOL_ID)
+        .expect("Unable to open DAS content store");
+    // Only the starting point: pings and pongs carry the store's current radius, see advertise_radius_in_request
+    let data_radius = store.radius();
+    let storage = Arc::new(parking_lot::RwLock::new(store));
   
     let protocol = ProtocolId::Custom(DAS_PROTOCOL_ID.to_string());
-    let validator = Arc::new(DASValidator);
+    let validator = Arc::new(DASValidator::new(kzg, commitments));
 
     let (overlay, service) = OverlayProtocol::new(
         config,
         discovery.clone(),
         utp_listener_tx,
         storage,
-        Distance::MAX,
+        data_radius,
         protocol,
         validator,
     );
@@ -96,13 +106,13 @@ pub async fn create_das_overlay(discovery: Arc<Discovery>, utp_listener_tx: mpsc
 } 
 
 
-pub async fn create_secure_das_overlay(discovery: Arc<Discovery>, utp_listener_tx: mpsc::UnboundedSender<UtpListenerRequest>) -> (
-    Arc<OverlayProtocol<SecureDASContentKey, XorMetric, SecureDASValidator, MemoryContentStore>>, 
-    OverlayService<SecureDASContentKey, XorMetric, SecureDASValidator, MemoryContentStore>,
+pub async fn create_secure_das_overlay(discovery: Arc<Discovery>, bootnode_enrs: Vec<Enr>, utp_listener_tx: mpsc::UnboundedSender<UtpListenerRequest>, kzg: Arc<KzgSettings>, commitments: BlobCommitments, storage: &StoreConfig) -> (
+    Arc<OverlayProtocol<SecureDASContentKey, XorMetric, SecureDASValidator, DASContentStore>>, 
+    OverlayService<SecureDASContentKey, XorMetric, SecureDASValidator, DASContentStore>,
 ){
 
         let config = OverlayConfig {
-        bootnode_enrs: discovery.discv5.table_entries_enr(),
+        bootnode_enrs,
         ping_queue_interval: Some(Duration::from_secs(10000)),
         query_num_results: usize::MAX,
         query_timeout: Duration::from_secs(60),
@@ -110,22 +120,21 @@ pub async fn create_secure_das_overlay(discovery: Arc<Discovery>, utp_listener_t
         ..Default::default()
     };
 
-    let storage = {
-        Arc::new(parking_lot::RwLock::new(MemoryContentStore::new(
-            discovery.discv5.local_enr().node_id(),
-            DistanceFunction::Xor,
-        )))
-    };
+    let store = DASContentStore::new(storage, discovery.discv5.local_enr().node_id(), SECURE_DAS_PROTOCOL_ID)
+        .expect("Unable to open SecureDAS content store");
+    // Same as for DAS: replaced by the store's current radius in pings and pongs
+    let data_radius = store.radius();
+    let storage = Arc::new(parking_lot::RwLock::new(store));
   
     let protocol = ProtocolId::Custom(SECURE_DAS_PROTOCOL_ID.to_string());
-    let validator = Arc::new(SecureDASValidator);
+    let validator = Arc::new(SecureDASValidator::new(kzg, commitments));
 
     let (overlay, service) = OverlayProtocol::new(
         config,
         discovery.clone(),
         utp_listener_tx,
         storage,
-        Distance::MAX,
+        data_radius,
         protocol,
         validator,
     );
@@ -135,4 +144,185 @@ pub async fn create_secure_das_overlay(discovery: Arc<Discovery>, utp_listener_t
         overlay, 
         service 
     )
-} 
\ No newline at end of file
+} 
+
+
+/// The data radius as pings and pongs carry it: the SSZ encoded radius of `store` right now.
+pub fn radius_payload<TStore: ContentStore>(store: &TStore) -> CustomPayload {
+    CustomPayload::from(store.radius().as_ssz_bytes())
+}
+
+/// OverlayProtocol fills pings with the radius it was created with and never updates it.  The store's
+/// radius shrinks as it fills, so outgoing pings get the current one swapped in.  Other requests are left alone.
+pub fn advertise_radius_in_request<TStore: ContentStore>(request: &mut Request, store: &TStore) {
+    if let Request::Ping(ping) = request {
+        ping.custom_payload = radius_payload(store);
+    }
+}
+
+/// Same as advertise_radius_in_request, for the pongs we answer pings with.
+pub fn advertise_radius_in_response<TStore: ContentStore>(response: &mut Response, store: &TStore) {
+    if let Response::Pong(pong) = response {
+        pong.custom_payload = radius_payload(store);
+    }
+}
+
+
+/// XOR distance between two ids, big endian.  Comparing these arrays compares distances.
+pub fn xor_distance(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut distance = [0u8; 32];
+    for i in 0..32 {
+        distance[i] = a[i] ^ b[i];
+    }
+    distance
+}
+
+/// Kademlia bucket of `b` as seen from `a`: 256 minus the number of leading bits they share.  None if equal.
+pub fn log_distance(a: &[u8; 32], b: &[u8; 32]) -> Option<u32> {
+    let distance = xor_distance(a, b);
+    let leading_zeros = match distance.iter().position(|&byte| byte != 0) {
+        Some(i) => i as u32 * 8 + distance[i].leading_zeros(),
+        None => return None,
+    };
+    Some(256 - leading_zeros)
+}
+
+/// The `n` ENRs out of `enrs` whose node ids are closest to `target`.
+pub fn closest_enrs(enrs: Vec<Enr>, target: &[u8; 32], n: usize) -> Vec<Enr> {
+    let mut enrs = enrs;
+    enrs.sort_by_key(|enr| xor_distance(&enr.node_id().raw(), target));
+    enrs.truncate(n);
+    enrs
+}
+
+/// Peers asked at once in each round of find_closest
+pub const LOOKUP_PARALLELISM: usize = 3;
+
+/// Iterative FindNodes lookup for the `n` nodes closest to `target`, beyond what the local routing table holds.
+/// Every round asks the closest LOOKUP_PARALLELISM peers not asked yet for the buckets around `target`
+/// from their side.  Done once the closest `n` found so far have all been asked.  Peers that don't
+/// answer are kept: an offer to them fails and is reported as such.
+pub async fn find_closest<TContentKey, TValidator, TStore>(
+    overlay: &OverlayProtocol<TContentKey, XorMetric, TValidator, TStore>,
+    target: &[u8; 32],
+    n: usize,
+) -> Vec<Enr>
+where
+    TContentKey: OverlayContentKey + Send + Sync,
+    TValidator: 'static + Validator<TContentKey> + Send + Sync,
+    TStore: 'static + ContentStore + Send + Sync,
+{
+    let local_id = overlay.local_enr().node_id();
+    let mut closest = closest_enrs(overlay.table_entries_enr(), target, n);
+    let mut seen: HashSet<NodeId> = closest.iter().map(|enr| enr.node_id()).collect();
+    let mut asked: HashSet<NodeId> = HashSet::new();
+
+    loop {
+        let round: Vec<Enr> = closest
+            .iter()
+            .filter(|enr| !asked.contains(&enr.node_id()))
+            .take(LOOKUP_PARALLELISM)
+            .cloned()
+            .collect();
+        if round.is_empty() {
+            return closest;
+        }
+        asked.extend(round.iter().map(|enr| enr.node_id()));
+
+        let responses = join_all(round.into_iter().map(|enr| {
+            // Distance 0 asks for the peer's own ENR, for the unlikely target that is the peer itself
+            let distances: Vec<u16> = match log_distance(&enr.node_id().raw(), target) {
+                Some(d) => (d.saturating_sub(1).max(1)..=(d + 1).min(256)).map(|d| d as u16).collect(),
+                None => vec![0],
+            };
+            overlay.send_find_nodes(enr, distances)
+        }))
+        .await;
+
+        for nodes in responses.into_iter().flatten() {
+            for enr in nodes.enrs.into_iter().map(Enr::from) {
+                if enr.node_id() != local_id && seen.insert(enr.node_id()) {
+                    closest.push(enr);
+                }
+            }
+        }
+        closest = closest_enrs(closest, target, n);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discv5_overlay::portalnet::types::{distance::Distance, messages::Ping};
+
+    use crate::content_key::SampleKey;
+
+    #[test]
+    fn a_filled_store_advertises_a_smaller_radius() {
+        let config = StoreConfig { capacity: Some(1000), ..Default::default() };
+        let mut store = DASContentStore::new(&config, NodeId::random(), DAS_PROTOCOL_ID).unwrap();
+        let mut ping = Request::Ping(Ping { enr_seq: 1, custom_payload: radius_payload(&store) });
+        let empty = radius_payload(&store);
+        assert_eq!(empty, CustomPayload::from(Distance::MAX.as_ssz_bytes()));
+
+        store.put(DASContentKey::Cell(SampleKey::new(0, 0, 0, 0)), vec![0u8; 600]).unwrap();
+        assert!(store.radius() < Distance::MAX);
+
+        advertise_radius_in_request(&mut ping, &store);
+        match ping {
+            Request::Ping(ping) => {
+                assert_ne!(ping.custom_payload, empty);
+                assert_eq!(ping.custom_payload, CustomPayload::from(store.radius().as_ssz_bytes()));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn enr() -> Enr {
+        discv5::enr::EnrBuilder::new("v4").build(&discv5::enr::CombinedKey::generate_secp256k1()).unwrap()
+    }
+
+    #[test]
+    fn xor_distance_is_symmetric_and_zero_to_itself() {
+        let (a, b) = ([0x0f; 32], [0xf0; 32]);
+        assert_eq!(xor_distance(&a, &b), [0xff; 32]);
+        assert_eq!(xor_distance(&a, &b), xor_distance(&b, &a));
+        assert_eq!(xor_distance(&a, &a), [0; 32]);
+    }
+
+    #[test]
+    fn closest_enrs_are_sorted_by_distance() {
+        let target = [0x55; 32];
+        let enrs: Vec<Enr> = (0..10).map(|_| enr()).collect();
+        let closest = closest_enrs(enrs.clone(), &target, 3);
+
+        assert_eq!(closest.len(), 3);
+        let distances: Vec<[u8; 32]> = closest.iter().map(|enr| xor_distance(&enr.node_id().raw(), &target)).collect();
+        assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+        let nearest_left_out = enrs
+            .iter()
+            .filter(|enr| !closest.contains(enr))
+            .map(|enr| xor_distance(&enr.node_id().raw(), &target))
+            .min()
+            .unwrap();
+        assert!(distances[2] <= nearest_left_out);
+        assert_eq!(closest_enrs(enrs, &target, 20).len(), 10);
+    }
+
+    #[test]
+    fn log_distance_is_the_kademlia_bucket() {
+        let local = [0u8; 32];
+        let with_first_byte = |byte: u8| {
+            let mut id = [0u8; 32];
+            id[0] = byte;
+            id
+        };
+        assert_eq!(log_distance(&local, &with_first_byte(0x80)), Some(256));
+        assert_eq!(log_distance(&local, &with_first_byte(0x01)), Some(249));
+        let mut last_bit = [0u8; 32];
+        last_bit[31] = 1;
+        assert_eq!(log_distance(&local, &last_bit), Some(1));
+        assert_eq!(log_distance(&local, &local), None);
+    }
+}
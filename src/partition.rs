@@ -0,0 +1,310 @@
+use anyhow::bail;
+use discv5::enr::NodeId;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    churn,
+    network::{RoutingTableStats, SimNetwork},
+    sampling::{SamplingConfig, Verdict},
+    transport::Transport,
+};
+
+/*
+    Splitting the network in groups that can't reach each other, then healing the split.
+
+    Only on the virtual transport: while partitioned, every message between nodes on different
+    sides is lost, so requests across the split time out like they would behind a real netsplit.
+
+    Each side is measured on its own:
+        - the share of published samples its running nodes hold, on each overlay
+        - its routing table sizes
+        - how much of its overlay routing tables point to the other side.  During the split those
+          entries are dead weight.  After healing, a converged table looks like the whole network again
+        - optionally a sampling run on the latest blob, from one of its honest nodes
+
+    With `split`, every node of the scenario (adversaries included) is dealt to a side.  With
+    `groups`, the nodes no group lists form one more side, last, marked `unassigned` in the results.
+    Nodes started later (eclipse attackers) are on no side: they only reach each other.
+
+        [[partitions]]
+        at_slot = 2
+        heal_slot = 5
+        split = [0.5, 0.5]         # shares of the nodes, assigned at random
+        recovery_slots = 3         # keep measuring this long after healing
+*/
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionSpec {
+    pub at_slot: u64,
+    pub heal_slot: u64,
+    /// Shares of the nodes on each side.  Ignored if `groups` is given
+    #[serde(default = "default_split")]
+    pub split: Vec<f64>,
+    /// Explicit node indices on each side
+    #[serde(default)]
+    pub groups: Option<Vec<Vec<usize>>>,
+    #[serde(default = "default_recovery_slots")]
+    pub recovery_slots: u64,
+    #[serde(default = "default_probe")]
+    pub probe: bool,
+}
+
+fn default_split() -> Vec<f64> {
+    vec![0.5, 0.5]
+}
+
+fn default_recovery_slots() -> u64 {
+    2
+}
+
+fn default_probe() -> bool {
+    true
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Partitioned,
+    Healed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SideStats {
+    pub side: usize,
+    /// The side of the nodes no group listed
+    pub unassigned: bool,
+    pub nodes: usize,
+    pub online: usize,
+    pub das_held: f64,
+    pub secure_das_held: f64,
+    pub routing_tables: RoutingTableStats,
+    /// Share of overlay routing table entries on another side
+    pub overlay_cross_share: f64,
+    pub secure_overlay_cross_share: f64,
+    pub probe: Option<Verdict>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PartitionRecord {
+    pub slot: u64,
+    pub phase: Phase,
+    pub sides: Vec<SideStats>,
+}
+
+impl PartitionSpec {
+    pub fn check(&self, nodes: usize) -> anyhow::Result<()> {
+        if self.heal_slot <= self.at_slot {
+            bail!("Partition at slot {} must heal after it, not at {}", self.at_slot, self.heal_slot);
+        }
+        if let Some(groups) = &self.groups {
+            if groups.is_empty() || groups.iter().any(Vec::is_empty) {
+                bail!("Partition groups can't be empty: {:?}", groups);
+            }
+            if let Some(&i) = groups.iter().flatten().find(|&&i| i >= nodes) {
+                bail!("Partition group node {} doesn't exist, there are {} nodes", i, nodes);
+            }
+            let mut assigned = HashSet::new();
+            if let Some(&i) = groups.iter().flatten().find(|&&i| !assigned.insert(i)) {
+                bail!("Partition group node {} is listed more than once", i);
+            }
+        } else if self.split.iter().any(|&s| s < 0.0) || self.split.iter().sum::<f64>() <= 0.0 {
+            bail!("Partition split needs positive shares: {:?}", self.split);
+        }
+        Ok(())
+    }
+
+    /// Node indices on each side.  With `groups`, unlisted nodes make up a last, unassigned side.
+    pub fn sides<R: Rng>(&self, nodes: usize, rng: &mut R) -> Vec<Vec<usize>> {
+        if let Some(groups) = &self.groups {
+            let mut sides = groups.clone();
+            let unassigned = self.unassigned(nodes);
+            if !unassigned.is_empty() {
+                sides.push(unassigned);
+            }
+            return sides;
+        }
+
+        let mut shuffled: Vec<usize> = (0..nodes).collect();
+        shuffled.shuffle(rng);
+        let total: f64 = self.split.iter().sum();
+        let mut sides = Vec::new();
+        let mut start = 0;
+        for (i, share) in self.split.iter().enumerate() {
+            let end = if i + 1 == self.split.len() {
+                nodes
+            } else {
+                (start + (nodes as f64 * share / total).round() as usize).min(nodes)
+            };
+            sides.push(shuffled[start..end].to_vec());
+            start = end;
+        }
+        sides
+    }
+
+    /// Nodes no group lists.  Empty with `split`.
+    pub fn unassigned(&self, nodes: usize) -> Vec<usize> {
+        match &self.groups {
+            Some(groups) => {
+                let assigned: HashSet<usize> = groups.iter().flatten().copied().collect();
+                (0..nodes).filter(|i| !assigned.contains(i)).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether `slot` should be measured: from the split until recovery_slots after healing.
+    pub fn measures(&self, slot: u64) -> bool {
+        slot >= self.at_slot && slot < self.heal_slot + self.recovery_slots
+    }
+}
+
+/// Cuts the links between `sides`.  Fails on the UDP transport.
+pub fn apply(network: &SimNetwork, sides: &[Vec<usize>]) -> anyhow::Result<()> {
+    let virtual_network = match &network.transport {
+        Transport::Virtual(virtual_network) => virtual_network,
+        Transport::Udp(_) => bail!("Partitions need the virtual transport"),
+    };
+    let groups: Vec<Vec<NodeId>> = sides
+        .iter()
+        .map(|side| side.iter().map(|&i| node_id(network, i)).collect())
+        .collect();
+    virtual_network.partition(&groups);
+    Ok(())
+}
+
+pub fn heal(network: &SimNetwork) {
+    if let Transport::Virtual(virtual_network) = &network.transport {
+        virtual_network.heal();
+    }
+}
+
+/// Measures every side on its own.  Probes sample from `probers` only.
+pub async fn measure<R: Rng>(
+    network: &SimNetwork,
+    spec: &PartitionSpec,
+    sides: &[Vec<usize>],
+    blobs: &[(u64, u64)],
+    probers: &[usize],
+    sampling_config: &SamplingConfig,
+    rng: &mut R,
+) -> Vec<SideStats> {
+    let unassigned_side = spec.groups.as_ref().map(Vec::len).filter(|&n| n < sides.len());
+    let side_of: HashMap<NodeId, usize> = sides
+        .iter()
+        .enumerate()
+        .flat_map(|(side, nodes)| nodes.iter().map(move |&i| (node_id(network, i), side)))
+        .collect();
+
+    let mut stats = Vec::new();
+    for (side, nodes) in sides.iter().enumerate() {
+        let online: Vec<usize> = nodes.iter().copied().filter(|&i| network.is_running(i)).collect();
+        let (das_held, secure_das_held) = churn::held(network, &online, blobs);
+
+        let cross_share = |entries: Vec<Vec<NodeId>>| {
+            let entries: Vec<NodeId> = entries.into_iter().flatten().collect();
+            if entries.is_empty() {
+                return 0.0;
+            }
+            let cross = entries.iter().filter(|id| side_of.get(id).map_or(false, |&s| s != side)).count();
+            cross as f64 / entries.len() as f64
+        };
+        let overlay_cross_share = cross_share(online.iter().map(|&i| network.nodes[i].overlay.table_entries_id()).collect());
+        let secure_overlay_cross_share = cross_share(online.iter().map(|&i| network.nodes[i].secure_overlay.table_entries_id()).collect());
+
+        let candidates: Vec<usize> = online.iter().copied().filter(|i| probers.contains(i)).collect();
+        let probe = match (spec.probe, blobs.last(), candidates.choose(rng)) {
+            (true, Some(&(slot, blob_index)), Some(&node)) => {
+                let indices = network.nodes[node].choose_samples(slot, blob_index, sampling_config, rng);
+                Some(network.nodes[node].sample_blob_at(slot, blob_index, &indices, sampling_config).await.verdict)
+            }
+            _ => None,
+        };
+
+        stats.push(SideStats {
+            side,
+            unassigned: unassigned_side == Some(side),
+            nodes: nodes.len(),
+            online: online.len(),
+            das_held,
+            secure_das_held,
+            routing_tables: network.routing_tables_of(&online),
+            overlay_cross_share,
+            secure_overlay_cross_share,
+            probe,
+        });
+    }
+
+    stats
+}
+
+fn node_id(network: &SimNetwork, i: usize) -> NodeId {
+    network.nodes[i].overlay.local_enr().node_id()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn spec(split: Vec<f64>, groups: Option<Vec<Vec<usize>>>) -> PartitionSpec {
+        PartitionSpec {
+            at_slot: 1,
+            heal_slot: 3,
+            split,
+            groups,
+            recovery_slots: default_recovery_slots(),
+            probe: false,
+        }
+    }
+
+    fn sides(spec: &PartitionSpec, nodes: usize) -> Vec<Vec<usize>> {
+        spec.sides(nodes, &mut StdRng::seed_from_u64(1))
+    }
+
+    #[test]
+    fn a_split_covers_every_node_once() {
+        let sides = sides(&spec(vec![0.7, 0.3], None), 10);
+        assert_eq!(sides.iter().map(Vec::len).collect::<Vec<_>>(), [7, 3]);
+        let mut all: Vec<usize> = sides.into_iter().flatten().collect();
+        all.sort();
+        assert_eq!(all, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shares_are_relative_and_the_last_side_takes_the_rest() {
+        let sides = sides(&spec(vec![1.0, 1.0, 1.0], None), 10);
+        assert_eq!(sides.iter().map(Vec::len).collect::<Vec<_>>(), [3, 3, 4]);
+    }
+
+    #[test]
+    fn unlisted_nodes_form_a_last_side() {
+        let some = spec(Vec::new(), Some(vec![vec![0, 1], vec![4]]));
+        assert_eq!(sides(&some, 6), [vec![0, 1], vec![4], vec![2, 3, 5]]);
+        assert_eq!(some.unassigned(6), [2, 3, 5]);
+
+        let everyone = spec(Vec::new(), Some(vec![vec![0, 1], vec![2]]));
+        assert_eq!(sides(&everyone, 3).len(), 2);
+    }
+
+    #[test]
+    fn check_rejects_bad_groups_and_splits() {
+        assert!(spec(vec![0.5, 0.5], None).check(10).is_ok());
+        assert!(PartitionSpec { heal_slot: 1, ..spec(vec![0.5, 0.5], None) }.check(10).is_err());
+        assert!(spec(vec![-1.0, 2.0], None).check(10).is_err());
+        assert!(spec(vec![0.0, 0.0], None).check(10).is_err());
+        assert!(spec(Vec::new(), Some(vec![vec![0], vec![]])).check(10).is_err());
+        assert!(spec(Vec::new(), Some(vec![vec![0], vec![10]])).check(10).is_err());
+        assert!(spec(Vec::new(), Some(vec![vec![0, 1], vec![1]])).check(10).is_err());
+    }
+
+    #[test]
+    fn measured_until_recovery_is_over() {
+        let spec = spec(vec![0.5, 0.5], None);
+        let measured: Vec<u64> = (0..8).filter(|&slot| spec.measures(slot)).collect();
+        assert_eq!(measured, [1, 2, 3, 4]);
+    }
+}
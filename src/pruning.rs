@@ -0,0 +1,131 @@
+use parking_lot::RwLock;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    node_struct::NodeStats,
+    storage::DASContentStore,
+};
+
+/*
+    Samples are only needed for a limited retention window.  Once a slot is older than
+    `retention_epochs` epochs, every sample belonging to it is deleted from the store.
+    The default is the spec's 4096 epochs.  Simulations are far shorter, so `retention_slots` can
+    set the window in slots instead and overrides the epochs when given.
+
+    The pruning task wakes up every `interval`, prunes, logs what it freed and adds it to the node's stats.
+    Set with --retention-epochs, --retention-slots, --prune-interval and --no-pruning, or the [storage] table of a scenario.
+*/
+
+pub const SECONDS_PER_SLOT: u64 = 12;
+pub const SLOTS_PER_EPOCH: u64 = 32;
+pub const DEFAULT_RETENTION_EPOCHS: u64 = 4096;
+
+/// Maps wall clock time to slots.  Slot 0 starts at `genesis`.
+#[derive(Clone, Copy, Debug)]
+pub struct SlotClock {
+    pub genesis: Instant,
+    pub slot_duration: Duration,
+}
+
+impl SlotClock {
+    pub fn new(genesis: Instant, slot_duration: Duration) -> Self {
+        Self { genesis, slot_duration }
+    }
+
+    /// A clock whose genesis is now, with mainnet slot times.
+    pub fn starting_now() -> Self {
+        Self::new(Instant::now(), Duration::from_secs(SECONDS_PER_SLOT))
+    }
+
+    pub fn current_slot(&self) -> u64 {
+        (self.genesis.elapsed().as_millis() / self.slot_duration.as_millis().max(1)) as u64
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PruningConfig {
+    pub retention_epochs: u64,
+    /// Overrides retention_epochs when set
+    pub retention_slots: Option<u64>,
+    pub interval: Duration,
+}
+
+impl Default for PruningConfig {
+    fn default() -> Self {
+        Self {
+            retention_epochs: DEFAULT_RETENTION_EPOCHS,
+            retention_slots: None,
+            interval: Duration::from_secs(SECONDS_PER_SLOT * SLOTS_PER_EPOCH),
+        }
+    }
+}
+
+impl PruningConfig {
+    /// Length of the retention window in slots.
+    pub fn retention_slots(&self) -> u64 {
+        self.retention_slots
+            .unwrap_or_else(|| self.retention_epochs.saturating_mul(SLOTS_PER_EPOCH))
+    }
+
+    /// First slot still inside the retention window.
+    pub fn oldest_retained_slot(&self, current_slot: u64) -> u64 {
+        current_slot.saturating_sub(self.retention_slots())
+    }
+}
+
+/// Spawns a task that keeps pruning `store`.  `name` is only used for logging.
+pub fn spawn_pruning(
+    name: String,
+    store: Arc<RwLock<DASContentStore>>,
+    clock: SlotClock,
+    config: PruningConfig,
+    stats: Arc<NodeStats>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+
+            let cutoff = config.oldest_retained_slot(clock.current_slot());
+            match store.write().prune_before(cutoff) {
+                Ok(report) if report.removed > 0 => {
+                    println!("{}: pruned {} samples before slot {}, freed {} bytes", name, report.removed, cutoff, report.bytes_freed);
+                    stats.record_prune(&report);
+                }
+                Ok(_) => {}
+                Err(err) => println!("{}: pruning failed: {}", name, err),
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_defaults_to_the_spec_window() {
+        let config = PruningConfig::default();
+        assert_eq!(config.retention_slots(), 4096 * 32);
+        assert_eq!(config.oldest_retained_slot(10), 0);
+        assert_eq!(config.oldest_retained_slot(4096 * 32 + 10), 10);
+    }
+
+    #[test]
+    fn retention_is_counted_in_epochs() {
+        let config = PruningConfig { retention_epochs: 2, ..Default::default() };
+        assert_eq!(config.oldest_retained_slot(100), 100 - 2 * SLOTS_PER_EPOCH);
+    }
+
+    #[test]
+    fn retention_slots_override_epochs() {
+        let config = PruningConfig { retention_epochs: 2, retention_slots: Some(4), ..Default::default() };
+        assert_eq!(config.retention_slots(), 4);
+        assert_eq!(config.oldest_retained_slot(100), 96);
+    }
+}
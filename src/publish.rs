@@ -0,0 +1,345 @@
+use anyhow::ensure;
+use c_kzg::Blob;
+use discv5::{enr::NodeId, Enr};
+use discv5_overlay::{
+    portalnet::{
+        overlay::OverlayProtocol,
+        storage::ContentStore,
+        types::{
+            content_key::OverlayContentKey,
+            distance::XorMetric,
+        },
+    },
+    types::validation::Validator,
+};
+use futures::future::join_all;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    time::Duration,
+};
+use tracing::warn;
+
+use crate::{
+    content_key::{DASContentKey, SampleKey, SecureDASContentKey},
+    erasure::{self, ExtendedBlob, ExtensionMode},
+    matrix::{self, Line},
+    node_struct::DASNode,
+    overlay,
+    sample::DASLine,
+    storage::DASContentStore,
+    transport::{OfferTransfers, TransferOutcome},
+};
+
+/*
+    Proposer side of DAS: erasure code a blob and push every sample to the nodes responsible for it.
+
+    "Responsible" means XOR-closest to the sample's content id among the nodes an iterative FindNodes
+    lookup towards it turns up (overlay::find_closest).  Our own routing table is dense around our
+    node id, not around the samples', so its closest entries are rarely the ones sampling will ask.
+    Samples are written to our own store first.  The overlay service serves accepted offers out of
+    the local store over uTP, so the store is where Offer/Accept actually reads the bytes from.
+    They stay pinned there until every offer is answered, so a store with max_bytes can't evict them
+    mid-transfer.  Afterwards the store drops whatever it has no room for, farthest first.
+    A sample that is missing from the store anyway when its offer goes out isn't offered: it counts
+    as failed for every peer it was meant for.
+
+    An Accept only means the peer wants the content.  A sample is delivered to a peer once the
+    transfer after the Accept finishes: the uTP stream closes, or on the virtual network the peer
+    has validated and stored it.  A reset stream, or one that doesn't finish within
+    TRANSFER_TIMEOUT, fails every sample in it.  Stream events don't say which Offer they belong
+    to, so Offers on one overlay go out one at a time.
+
+    Under 2D extension the blobs are the rows of one matrix (matrix.rs).  Every cell is published
+    like a 1D sample, under its (row, column) key.  Every row and column is also offered whole on
+    the DAS overlay, under its line key, for nodes that want to repair a line in one request.
+
+    Notes:
+        - Samples go to both overlays.  The SecureDAS overlay is the backup sampling falls back to
+        - Samples headed to the same peer are batched into one Offer
+        - Portal caps an Offer at 64 content keys
+*/
+
+pub const MAX_KEYS_PER_OFFER: usize = 64;
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What happened to a single sample.
+#[derive(Clone, Debug, Default)]
+pub struct SampleDelivery {
+    /// Accepted, and the transfer that followed finished
+    pub accepted_by: Vec<NodeId>,
+    pub declined_by: Vec<NodeId>,
+    /// The Offer failed, or it was accepted and the transfer didn't finish
+    pub failed: Vec<(NodeId, String)>,
+}
+
+impl SampleDelivery {
+    pub fn delivered(&self) -> bool {
+        !self.accepted_by.is_empty()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PublishReport {
+    pub slot: u64,
+    pub blob_index: u64,
+    pub mode: ExtensionMode,
+    /// One per row.  Just the blob's under 1D extension
+    pub commitments: Vec<Vec<u8>>,
+    pub deliveries: Vec<(SampleKey, SampleDelivery)>,
+    /// Same samples, offered on the SecureDAS overlay
+    pub secure_deliveries: Vec<(SampleKey, SampleDelivery)>,
+    /// Whole rows and columns, on the DAS overlay.  2D extension only
+    pub lines: Vec<(Line, SampleDelivery)>,
+}
+
+impl PublishReport {
+    pub fn delivered(&self) -> usize {
+        self.deliveries.iter().filter(|(_, d)| d.delivered()).count()
+    }
+
+    pub fn undelivered(&self) -> Vec<SampleKey> {
+        self.deliveries
+            .iter()
+            .filter(|(_, d)| !d.delivered())
+            .map(|(key, _)| *key)
+            .collect()
+    }
+}
+
+impl DASNode {
+    /// Erasure codes `blobs` and offers each sample to the `replication` peers closest to it,
+    /// on the DAS overlay and on the SecureDAS overlay.  1D extension takes a single blob, 2D
+    /// extension takes every blob of the matrix.
+    pub async fn publish_blob(&self, blobs: &[Blob], slot: u64, blob_index: u64, mode: ExtensionMode, replication: usize) -> anyhow::Result<PublishReport> {
+        let (rows, extended_matrix) = match mode {
+            ExtensionMode::OneDimensional => {
+                ensure!(blobs.len() == 1, "1D extension publishes one blob at a time, not {}", blobs.len());
+                (vec![erasure::extend_blob(&self.kzg, &blobs[0], slot, blob_index)?], None)
+            }
+            ExtensionMode::TwoDimensional => {
+                let extended = matrix::extend_blobs_2d(&self.kzg, blobs, slot, blob_index)?;
+                (extended.rows.clone(), Some(extended))
+            }
+        };
+        for row in rows.iter() {
+            self.commitments.insert(slot, blob_index, row.row, row.commitment.clone());
+        }
+
+        let cells: Vec<(SampleKey, Vec<u8>)> = rows
+            .iter()
+            .flat_map(|row: &ExtendedBlob| {
+                row.samples
+                    .iter()
+                    .map(move |sample| (SampleKey::new(slot, blob_index, row.row, sample.cell_index), sample.to_bytes()))
+            })
+            .collect();
+        let (sample_keys, contents): (Vec<SampleKey>, Vec<Vec<u8>>) = cells.into_iter().unzip();
+
+        let das_samples = sample_keys
+            .iter()
+            .zip(contents.iter())
+            .map(|(key, content)| (DASContentKey::Cell(*key), content.clone()))
+            .collect();
+        let secure_samples = sample_keys
+            .iter()
+            .zip(contents.iter())
+            .map(|(key, content)| (SecureDASContentKey::Cell(*key), content.clone()))
+            .collect();
+
+        let (lines, line_contents) = match &extended_matrix {
+            Some(extended) => {
+                let lines: Vec<Line> = (0..extended.rows.len() as u64)
+                    .map(Line::Row)
+                    .chain((0..erasure::SAMPLES_PER_BLOB as u64).map(Line::Column))
+                    .collect();
+                let keys = extended.row_keys().into_iter().chain(extended.column_keys());
+                let contents = keys
+                    .zip(lines.iter())
+                    .map(|(key, &line)| (key, DASLine { samples: extended.line(line) }.to_bytes()))
+                    .collect();
+                (lines, contents)
+            }
+            None => (Vec::new(), Vec::new()),
+        };
+
+        let (deliveries, secure_deliveries, line_deliveries) = futures::join!(
+            offer_to_closest(&self.overlay, &self.transfers, das_samples, replication),
+            offer_to_closest(&self.secure_overlay, &self.transfers, secure_samples, replication),
+            offer_to_closest(&self.overlay, &self.transfers, line_contents, replication),
+        );
+
+        Ok(PublishReport {
+            slot,
+            blob_index,
+            mode,
+            commitments: rows.into_iter().map(|row| row.commitment).collect(),
+            deliveries: sample_keys.iter().copied().zip(deliveries?).collect(),
+            secure_deliveries: sample_keys.iter().copied().zip(secure_deliveries?).collect(),
+            lines: lines.into_iter().zip(line_deliveries?).collect(),
+        })
+    }
+}
+
+/// Stores `samples` locally, then offers each one to the `replication` peers in `overlay` closest to it.
+/// Deliveries come back in the same order as `samples`.
+pub async fn offer_to_closest<TContentKey, TValidator>(
+    overlay: &OverlayProtocol<TContentKey, XorMetric, TValidator, DASContentStore>,
+    transfers: &OfferTransfers,
+    samples: Vec<(TContentKey, Vec<u8>)>,
+    replication: usize,
+) -> anyhow::Result<Vec<SampleDelivery>>
+where
+    TContentKey: OverlayContentKey + Clone + Display + Into<Vec<u8>> + Send + Sync,
+    TValidator: 'static + Validator<TContentKey> + Send + Sync,
+{
+    let mut deliveries = vec![SampleDelivery::default(); samples.len()];
+    let mut offers: HashMap<NodeId, (Enr, Vec<usize>)> = HashMap::new();
+
+    let content_ids: Vec<[u8; 32]> = samples.iter().map(|(key, _)| key.content_id()).collect();
+    overlay.store.write().pin(content_ids.iter().copied());
+
+    // One lookup per sample, all at once
+    let closest = join_all(content_ids.iter().map(|id| overlay::find_closest(overlay, id, replication))).await;
+
+    for (i, (key, content)) in samples.iter().enumerate() {
+        if let Err(err) = overlay.store.write().put(key.clone(), content) {
+            let _ = overlay.store.write().unpin(content_ids.iter().copied());
+            return Err(anyhow::anyhow!("Unable to store sample {}: {:?}", key, err));
+        }
+
+        for enr in closest[i].iter().cloned() {
+            offers.entry(enr.node_id()).or_insert_with(|| (enr, Vec::new())).1.push(i);
+        }
+    }
+
+    // One task per (peer, batch of keys)
+    let requests = offers.into_iter().flat_map(|(node_id, (enr, indices))| {
+        indices
+            .chunks(MAX_KEYS_PER_OFFER)
+            .map(|batch| (node_id, enr.clone(), batch.to_vec()))
+            .collect::<Vec<_>>()
+    });
+    let samples = &samples;
+    let results = join_all(requests.map(|(node_id, enr, batch)| async move {
+        // The transfer would skip these without telling anyone
+        let (batch, missing): (Vec<usize>, Vec<usize>) = {
+            let store = overlay.store.read();
+            batch.into_iter().partition(|&i| matches!(store.get(&samples[i].0), Ok(Some(_))))
+        };
+        let keys: Vec<Vec<u8>> = batch.iter().map(|&i| samples[i].0.clone().into()).collect();
+        let result = if keys.is_empty() {
+            None
+        } else {
+            Some(offer(overlay, transfers, keys, enr).await)
+        };
+        (node_id, batch, missing, result)
+    }))
+    .await;
+
+    if let Err(err) = overlay.store.write().unpin(content_ids) {
+        warn!("Unable to evict offered samples: {}", err);
+    }
+
+    for (node_id, batch, missing, result) in results {
+        for i in missing {
+            deliveries[i].failed.push((node_id, "Missing from the local store".to_string()));
+        }
+        let result = match result {
+            Some(result) => result,
+            None => continue,
+        };
+        match result {
+            Ok(offered) => {
+                for (&i, offered) in batch.iter().zip(offered) {
+                    match offered {
+                        Offered::Delivered => deliveries[i].accepted_by.push(node_id),
+                        Offered::Declined => deliveries[i].declined_by.push(node_id),
+                        Offered::Failed(err) => deliveries[i].failed.push((node_id, err)),
+                    }
+                }
+            }
+            Err(err) => {
+                for &i in batch.iter() {
+                    deliveries[i].failed.push((node_id, err.clone()));
+                }
+            }
+        }
+    }
+
+    Ok(deliveries)
+}
+
+enum Offered {
+    Delivered,
+    Declined,
+    Failed(String),
+}
+
+/// Offers `keys` to `enr` and waits for the content of the ones it accepts to get there.
+async fn offer<TContentKey, TValidator>(
+    overlay: &OverlayProtocol<TContentKey, XorMetric, TValidator, DASContentStore>,
+    transfers: &OfferTransfers,
+    keys: Vec<Vec<u8>>,
+    enr: Enr,
+) -> Result<Vec<Offered>, String>
+where
+    TContentKey: OverlayContentKey + Clone + Display + Into<Vec<u8>> + Send + Sync,
+    TValidator: 'static + Validator<TContentKey> + Send + Sync,
+{
+    // Held until this Offer's transfer is over, the next outcome is ours
+    let outcomes = transfers.outcomes(overlay.protocol());
+    let mut outcomes = outcomes.lock().await;
+    // Left behind by Offers that gave up waiting
+    while outcomes.try_recv().is_ok() {}
+
+    let accept = overlay.send_offer(keys.clone(), enr).await.map_err(|err| format!("{:?}", err))?;
+    let accepted: Vec<bool> = (0..keys.len()).map(|i| accept.content_keys.get(i).unwrap_or(false)).collect();
+    if !accepted.contains(&true) {
+        return Ok(keys.iter().map(|_| Offered::Declined).collect());
+    }
+
+    let outcome = match tokio::time::timeout(TRANSFER_TIMEOUT, outcomes.recv()).await {
+        Ok(Some(outcome)) => outcome,
+        _ => TransferOutcome::error("Transfer didn't finish in time"),
+    };
+    Ok(keys
+        .iter()
+        .zip(accepted)
+        .map(|(key, accepted)| match (accepted, outcome.failure(key)) {
+            (false, _) => Offered::Declined,
+            (true, Some(err)) => Offered::Failed(err),
+            (true, None) => Offered::Delivered,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_finished_transfers_count_as_delivered() {
+        let delivery = |accepted: usize, declined: usize, failed: usize| SampleDelivery {
+            accepted_by: (0..accepted).map(|_| NodeId::random()).collect(),
+            declined_by: (0..declined).map(|_| NodeId::random()).collect(),
+            failed: (0..failed).map(|_| (NodeId::random(), "stream reset".to_string())).collect(),
+        };
+        let report = PublishReport {
+            slot: 1,
+            blob_index: 0,
+            mode: ExtensionMode::OneDimensional,
+            commitments: Vec::new(),
+            deliveries: vec![
+                (SampleKey::new(1, 0, 0, 0), delivery(1, 2, 1)),
+                (SampleKey::new(1, 0, 0, 1), delivery(0, 3, 0)),
+                (SampleKey::new(1, 0, 0, 2), delivery(0, 0, 2)),
+            ],
+            secure_deliveries: Vec::new(),
+            lines: Vec::new(),
+        };
+
+        assert_eq!(report.delivered(), 1);
+        assert_eq!(report.undelivered(), vec![SampleKey::new(1, 0, 0, 1), SampleKey::new(1, 0, 0, 2)]);
+    }
+}
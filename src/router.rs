@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use discv5::{enr::NodeId, TalkRequest};
+use discv5_overlay::{
+    portalnet::{
+        overlay::OverlayProtocol,
+        storage::ContentStore,
+        types::{
+            content_key::OverlayContentKey,
+            distance::XorMetric,
+            messages::{Message, ProtocolId},
+        },
+    },
+    types::validation::Validator,
+};
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::log::error;
+
+use crate::{links::LinkShaper, overlay};
+
+/*
+    Discv5 hands us every TalkReq, whatever overlay it's meant for.  The router looks at the
+    request's protocol id and passes it to the overlay registered under it.
+
+    Adding an overlay means registering it in DASNode::new.  The event loop doesn't change.
+
+    Some protocols aren't answered by a handler but by a task of their own: uTP packets go to the
+    node's uTP listener, which responds to them itself.  Those are forwarded whole, see forward.
+
+    Over UDP with a link model (see links.rs) the router is where messages wait out their link: a
+    TalkReq is held back before it's handled and its response before it's sent.  A lost one is never
+    answered.  Forwarded requests are answered by someone else, so they wait out both legs up front.
+
+    Notes:
+        - Unknown protocols get an empty TalkResp.  That's what the discv5 spec asks for
+        - Requests an overlay fails to process get the same empty response, so the requester isn't left waiting for a timeout
+*/
+
+/// Anything that can answer a TalkReq for one protocol.
+#[async_trait]
+pub trait TalkRequestHandler: Send + Sync {
+    async fn handle(&self, request: &TalkRequest) -> anyhow::Result<Vec<u8>>;
+}
+
+#[async_trait]
+impl<TContentKey, TValidator, TStore> TalkRequestHandler for OverlayProtocol<TContentKey, XorMetric, TValidator, TStore>
+where
+    TContentKey: 'static + OverlayContentKey + Send + Sync,
+    TValidator: 'static + Validator<TContentKey> + Send + Sync,
+    TStore: 'static + ContentStore + Send + Sync,
+{
+    async fn handle(&self, request: &TalkRequest) -> anyhow::Result<Vec<u8>> {
+        match self.process_one_request(request).await {
+            Ok(mut response) => {
+                overlay::advertise_radius_in_response(&mut response, &*self.store.read());
+                Ok(Message::from(response).into())
+            }
+            Err(err) => Err(anyhow::anyhow!("{:?}", err)),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ProtocolRouter {
+    handlers: Vec<(ProtocolId, Arc<dyn TalkRequestHandler>)>,
+    /// Protocols whose TalkReqs go to a channel.  The reader responds
+    forwards: Vec<(ProtocolId, UnboundedSender<TalkRequest>)>,
+    /// The local node id and the links its TalkReqs and responses go through
+    links: Option<(NodeId, LinkShaper)>,
+}
+
+impl ProtocolRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, protocol: ProtocolId, handler: Arc<dyn TalkRequestHandler>) {
+        self.handlers.retain(|(p, _)| *p != protocol);
+        self.handlers.push((protocol, handler));
+    }
+
+    /// Sends every TalkReq for `protocol` to `requests` instead of a handler, e.g. uTP packets to the uTP listener.
+    pub fn forward(&mut self, protocol: ProtocolId, requests: UnboundedSender<TalkRequest>) {
+        self.forwards.retain(|(p, _)| *p != protocol);
+        self.forwards.push((protocol, requests));
+    }
+
+    /// Applies `links` to every TalkReq `local` receives and to its response.
+    pub fn shape(&mut self, local: NodeId, links: LinkShaper) {
+        self.links = Some((local, links));
+    }
+
+    /// Latencies of a request from `source` and of its response.  None for a leg that gets lost.
+    fn legs(&self, source: &NodeId) -> (Option<Duration>, Option<Duration>) {
+        match &self.links {
+            Some((local, links)) => (links.sample(source, local), links.sample(local, source)),
+            None => (Some(Duration::ZERO), Some(Duration::ZERO)),
+        }
+    }
+
+    pub fn protocols(&self) -> Vec<ProtocolId> {
+        self.handlers
+            .iter()
+            .map(|(p, _)| p.clone())
+            .chain(self.forwards.iter().map(|(p, _)| p.clone()))
+            .collect()
+    }
+
+    pub fn handler(&self, protocol: &ProtocolId) -> Option<Arc<dyn TalkRequestHandler>> {
+        self.handlers
+            .iter()
+            .find(|(p, _)| p == protocol)
+            .map(|(_, handler)| handler.clone())
+    }
+
+    /// Processes a TalkReq with whichever handler owns its protocol and responds to it.
+    pub async fn route(&self, request: TalkRequest) {
+        let protocol = ProtocolId::from_str(&hex::encode_upper(request.protocol())).ok();
+        // Lost on the way in: never answered, the requester times out
+        let (inbound, outbound) = match self.legs(request.node_id()) {
+            (Some(inbound), outbound) => (inbound, outbound),
+            (None, _) => return,
+        };
+
+        let forward = protocol
+            .as_ref()
+            .and_then(|protocol| self.forwards.iter().find(|(p, _)| p == protocol));
+        if let Some((_, requests)) = forward {
+            let outbound = match outbound {
+                Some(outbound) => outbound,
+                None => return,
+            };
+            tokio::time::sleep(inbound + outbound).await;
+            // Only fails once the reader is gone, e.g. during shutdown
+            if let Err(err) = requests.send(request) {
+                error!("No one to forward talk request to");
+                if let Err(err) = err.0.respond(Vec::new()) {
+                    error!("Unable to respond to talk request: {:?}", err);
+                }
+            }
+            return;
+        }
+
+        tokio::time::sleep(inbound).await;
+        let handler = protocol.and_then(|protocol| self.handler(&protocol));
+
+        let talk_resp = match handler {
+            Some(handler) => match handler.handle(&request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    error!("Error processing request: {}", err);
+                    Vec::new()
+                }
+            },
+            None => {
+                error!("No overlay registered for protocol: {}", hex::encode(request.protocol()));
+                Vec::new()
+            }
+        };
+
+        // Lost on the way back: handled, but never answered
+        let outbound = match outbound {
+            Some(outbound) => outbound,
+            None => return,
+        };
+        tokio::time::sleep(outbound).await;
+        if let Err(err) = request.respond(talk_resp) {
+            error!("Unable to respond to talk request: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Answer(Vec<u8>);
+
+    #[async_trait]
+    impl TalkRequestHandler for Answer {
+        async fn handle(&self, _request: &TalkRequest) -> anyhow::Result<Vec<u8>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn custom(name: &str) -> ProtocolId {
+        ProtocolId::Custom(name.to_string())
+    }
+
+    #[test]
+    fn registering_a_protocol_again_replaces_its_handler() {
+        let mut router = ProtocolRouter::new();
+        let second: Arc<dyn TalkRequestHandler> = Arc::new(Answer(vec![2]));
+        router.register(custom("das"), Arc::new(Answer(vec![1])));
+        router.register(custom("das"), second.clone());
+
+        assert_eq!(router.protocols(), vec![custom("das")]);
+        assert!(Arc::ptr_eq(&router.handler(&custom("das")).unwrap(), &second));
+        assert!(router.handler(&custom("secure-das")).is_none());
+    }
+
+    #[test]
+    fn forwarded_protocols_are_listed_but_have_no_handler() {
+        let mut router = ProtocolRouter::new();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        router.register(custom("das"), Arc::new(Answer(vec![])));
+        router.forward(ProtocolId::Utp, tx.clone());
+        router.forward(ProtocolId::Utp, tx);
+
+        assert_eq!(router.protocols(), vec![custom("das"), ProtocolId::Utp]);
+        assert!(router.handler(&ProtocolId::Utp).is_none());
+        assert!(router.handler(&custom("das")).is_some());
+    }
+
+    #[test]
+    fn unshaped_routers_deliver_instantly() {
+        let router = ProtocolRouter::new();
+        assert_eq!(router.legs(&NodeId::random()), (Some(Duration::ZERO), Some(Duration::ZERO)));
+    }
+}
@@ -0,0 +1,277 @@
+use discv5::Discv5Event;
+use discv5_overlay::{
+    portalnet::{
+        overlay_service::{
+            OverlayCommand,
+            OverlayService,
+        },
+        types::distance::XorMetric,
+    },
+    utp::stream::{UtpListenerEvent, UtpStreamId},
+};
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::{
+    select,
+    sync::{
+        mpsc::UnboundedReceiver,
+        oneshot,
+    },
+    task::{JoinError, JoinHandle},
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    content_key::{
+        DASContentKey,
+        DASValidator,
+        SecureDASContentKey,
+        SecureDASValidator,
+    },
+    node_struct::DASNode,
+    overlay,
+    storage::DASContentStore,
+    transport::{self, TransferOutcome, VirtualLink, VirtualMessage},
+};
+
+/*
+    The server side of a node: the task that processes ALL messages for a DASNode.
+        - Overlay requests and responses
+        - Secure overlay requests and responses
+        - Discv5 events (TalkReqs go through the node's router)
+        - uTP stream events, for either overlay: the node has one uTP listener, and each stream
+          carries the protocol id of the overlay that opened it
+        - On the virtual transport: requests from other nodes' inboxes instead of discv5 events, and
+          outgoing overlay requests go to the virtual network instead of discv5
+
+    A runtime runs until it's shut down.  Shutting down:
+        1. Stops taking new overlay requests and discv5 events
+        2. Keeps processing responses until neither service has active outgoing requests (or drain_timeout passes)
+        3. Stops the uTP listeners and background tasks
+*/
+
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything create_node sets up besides the DASNode itself.  The runtime takes ownership of it.
+pub struct NodeServices {
+    pub overlay_service: OverlayService<DASContentKey, XorMetric, DASValidator, DASContentStore>,
+    pub secure_overlay_service: OverlayService<SecureDASContentKey, XorMetric, SecureDASValidator, DASContentStore>,
+    /// Streams of both overlays.  The listener's TalkReqs come in through the node's router
+    pub utp_listener_rx: UnboundedReceiver<UtpListenerEvent>,
+    pub utp_listeners: Vec<JoinHandle<()>>,
+    /// Anything else that should stop with the node, e.g. pruning
+    pub background_tasks: Vec<JoinHandle<()>>,
+    /// Set when the node runs on the virtual transport
+    pub virtual_link: Option<VirtualLink>,
+}
+
+pub struct NodeRuntime {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<()>,
+}
+
+impl NodeRuntime {
+    /// Spawns the node's message processing task.
+    pub async fn start(node: DASNode, services: NodeServices, drain_timeout: Duration) -> Self {
+        // The discv5 server was never started on the virtual transport, there are no events
+        let event_str = match services.virtual_link {
+            Some(_) => None,
+            None => Some(ReceiverStream::new(node.discovery.discv5.event_stream().await.unwrap())),
+        };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let handle = tokio::spawn(run(node, services, event_str, shutdown_rx, drain_timeout));
+
+        Self {
+            shutdown_tx: Some(shutdown_tx),
+            handle,
+        }
+    }
+
+    pub fn handle(&self) -> &JoinHandle<()> {
+        &self.handle
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Asks the node to stop and waits for it to drain.
+    pub async fn shutdown(mut self) -> Result<(), JoinError> {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        self.handle.await
+    }
+}
+
+// Same steps for both overlays, the service types just differ
+macro_rules! process_response {
+    ($service:expr, $response:expr) => {{
+        let response = $response;
+        // Look up active request that corresponds to the response.
+        let optional_active_request = $service.active_outgoing_requests.write().remove(&response.request_id);
+        if let Some(active_request) = optional_active_request {
+            // Send response to responder if present.
+            if let Some(responder) = active_request.responder {
+                let _ = responder.send(response.response.clone());
+            }
+
+            // Perform background processing.
+            match response.response {
+                Ok(response) => $service.process_response(response, active_request.destination, active_request.request, active_request.query_id),
+                Err(error) => $service.process_request_failure(response.request_id, active_request.destination, error),
+            }
+        } else {
+            println!("No request found for response");
+        }
+    }};
+}
+
+async fn run(
+    node: DASNode,
+    services: NodeServices,
+    mut event_str: Option<ReceiverStream<Discv5Event>>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+    drain_timeout: Duration,
+) {
+    let NodeServices {
+        mut overlay_service,
+        mut secure_overlay_service,
+        mut utp_listener_rx,
+        utp_listeners,
+        background_tasks,
+        mut virtual_link,
+    } = services;
+
+    loop {
+        /// "Select!" randomly picks one of these match branches to process an event
+        select! {
+            _ = &mut shutdown_rx => break,
+            // ===========================
+            // Overlay Message Processing:
+            // ===========================
+            Some(command) = overlay_service.command_rx.recv() => {
+                if let OverlayCommand::Request(mut request) = command {
+                    overlay::advertise_radius_in_request(&mut request.request, &*node.overlay.store.read());
+                    match &virtual_link {
+                        Some(link) => link.send(&mut overlay_service, node.overlay.protocol().clone(), &node.transfers, request),
+                        None => overlay_service.process_request(request),
+                    }
+                }
+            }
+            Some(response) = overlay_service.response_rx.recv() => {
+                process_response!(overlay_service, response)
+            }
+            // ==================================
+            // Secure Overlay Message Processing:
+            // ==================================
+            Some(command) = secure_overlay_service.command_rx.recv() => {
+                if let OverlayCommand::Request(mut request) = command {
+                    overlay::advertise_radius_in_request(&mut request.request, &*node.secure_overlay.store.read());
+                    match &virtual_link {
+                        Some(link) => link.send(&mut secure_overlay_service, node.secure_overlay.protocol().clone(), &node.transfers, request),
+                        None => secure_overlay_service.process_request(request),
+                    }
+                }
+            }
+            Some(response) = secure_overlay_service.response_rx.recv() => {
+                process_response!(secure_overlay_service, response)
+            }
+            // ==========================
+            // Discv5 Message Processing:
+            // ==========================
+            Some(event) = next_event(&mut event_str) => {
+                if let Discv5Event::TalkRequest(req) = event {
+                    let router = node.router.clone();
+                    tokio::spawn(async move { router.route(req).await });
+                }
+            }
+            // =======================
+            // uTP Stream Processing:
+            // =======================
+            Some(event) = utp_listener_rx.recv() => {
+                process_utp_event(&node, event);
+            }
+            // ===========================
+            // Virtual Message Processing:
+            // ===========================
+            Some(message) = next_message(&mut virtual_link) => {
+                if message.protocol == *node.overlay.protocol() {
+                    overlay_service.process_request(transport::incoming_request(message))
+                } else if message.protocol == *node.secure_overlay.protocol() {
+                    secure_overlay_service.process_request(transport::incoming_request(message))
+                }
+            }
+        }
+    }
+
+    // Drain: let requests we already sent finish, but don't start anything new
+    let drain = async {
+        loop {
+            let in_flight = overlay_service.active_outgoing_requests.read().len()
+                + secure_overlay_service.active_outgoing_requests.read().len();
+            if in_flight == 0 {
+                break;
+            }
+
+            select! {
+                Some(response) = overlay_service.response_rx.recv() => {
+                    process_response!(overlay_service, response)
+                }
+                Some(response) = secure_overlay_service.response_rx.recv() => {
+                    process_response!(secure_overlay_service, response)
+                }
+                else => break,
+            }
+        }
+    };
+    if tokio::time::timeout(drain_timeout, drain).await.is_err() {
+        println!("Node shut down with requests still in flight");
+    }
+
+    for task in utp_listeners.into_iter().chain(background_tasks) {
+        task.abort();
+    }
+    drop(utp_listener_rx);
+    // Closes the inbox.  Other nodes now see this one as stopped
+    drop(virtual_link);
+}
+
+/// Hands a finished or reset uTP stream to the overlay that opened it.  Closed AcceptStreams are
+/// content someone offered us, the overlay validates and stores it.  OfferStreams are our own
+/// Offers' content going out, publish waits for those.
+fn process_utp_event(node: &DASNode, event: UtpListenerEvent) {
+    let protocol = match &event {
+        UtpListenerEvent::ClosedStream(_, protocol, _) | UtpListenerEvent::ResetStream(protocol, _) => protocol.clone(),
+    };
+    match &event {
+        UtpListenerEvent::ClosedStream(_, _, UtpStreamId::OfferStream) => node.transfers.finished(&protocol, TransferOutcome::default()),
+        UtpListenerEvent::ResetStream(_, UtpStreamId::OfferStream) => node.transfers.finished(&protocol, TransferOutcome::error("uTP stream reset")),
+        _ => {}
+    }
+
+    let result = if protocol == *node.overlay.protocol() {
+        node.overlay.process_utp_event(event)
+    } else if protocol == *node.secure_overlay.protocol() {
+        node.secure_overlay.process_utp_event(event)
+    } else {
+        return;
+    };
+    if let Err(err) = result {
+        println!("Unable to process uTP stream for {:?}: {}", protocol, err);
+    }
+}
+
+async fn next_event(event_str: &mut Option<ReceiverStream<Discv5Event>>) -> Option<Discv5Event> {
+    match event_str {
+        Some(event_str) => event_str.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn next_message(virtual_link: &mut Option<VirtualLink>) -> Option<VirtualMessage> {
+    match virtual_link {
+        Some(link) => link.inbox.recv().await,
+        None => futures::future::pending().await,
+    }
+}
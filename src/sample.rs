@@ -0,0 +1,348 @@
+use anyhow::{anyhow, ensure};
+use c_kzg::{
+    Bytes48,
+    Cell,
+    KzgSettings,
+    BYTES_PER_CELL,
+    BYTES_PER_COMMITMENT,
+    BYTES_PER_PROOF,
+    CELLS_PER_EXT_BLOB,
+};
+use parking_lot::RwLock;
+use sha2::{Digest, Sha256};
+use ssz::{Decode, Encode};
+use ssz_derive::{Decode, Encode};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::content_key::{LineKey, SampleKey};
+
+/*
+    A sample is a single KZG cell of an extended blob, along with the proof that it
+    belongs to the blob's commitment.  This is what travels over the DAS and SecureDAS
+    overlays as content.
+
+    Notes:
+        - The trusted setup is the mainnet ceremony output, loaded from a local file
+        - Precompute is left at 0.  We verify far more than we prove
+*/
+
+pub const TRUSTED_SETUP_PATH: &str = "assets/trusted_setup.txt";
+
+/// Content stored under a sample key.  SSZ encoded over the wire.
+#[derive(Clone, Debug, Decode, Encode, PartialEq)]
+pub struct DASSample {
+    pub commitment: Vec<u8>,
+    pub cell_index: u64,
+    pub cell: Vec<u8>,
+    pub proof: Vec<u8>,
+}
+
+impl DASSample {
+    pub fn from_bytes(content: &[u8]) -> anyhow::Result<Self> {
+        let sample = DASSample::from_ssz_bytes(content)
+            .map_err(|err| anyhow!("Unable to decode sample: {:?}", err))?;
+
+        ensure!(sample.commitment.len() == BYTES_PER_COMMITMENT, "Invalid commitment length: {}", sample.commitment.len());
+        ensure!(sample.proof.len() == BYTES_PER_PROOF, "Invalid proof length: {}", sample.proof.len());
+        ensure!(sample.cell.len() == BYTES_PER_CELL, "Invalid cell length: {}", sample.cell.len());
+        ensure!((sample.cell_index as usize) < CELLS_PER_EXT_BLOB, "Cell index out of range: {}", sample.cell_index);
+
+        Ok(sample)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.as_ssz_bytes()
+    }
+
+    /// Checks the cell's KZG proof against the commitment it claims to belong to.
+    pub fn verify(&self, kzg: &KzgSettings) -> anyhow::Result<()> {
+        let commitment = Bytes48::from_bytes(&self.commitment)?;
+        let proof = Bytes48::from_bytes(&self.proof)?;
+        let cell = Cell::from_bytes(&self.cell)?;
+
+        let valid = kzg.verify_cell_kzg_proof_batch(&[commitment], &[self.cell_index], &[cell], &[proof])?;
+        ensure!(valid, "Invalid KZG proof for cell {}", self.cell_index);
+
+        Ok(())
+    }
+}
+
+/// Content stored under a row or column key.  samples[i] is column i of a row, or row i of a column.
+#[derive(Clone, Debug, Decode, Encode, PartialEq)]
+pub struct DASLine {
+    pub samples: Vec<DASSample>,
+}
+
+impl DASLine {
+    pub fn from_bytes(content: &[u8]) -> anyhow::Result<Self> {
+        let line = DASLine::from_ssz_bytes(content)
+            .map_err(|err| anyhow!("Unable to decode line: {:?}", err))?;
+        for sample in line.samples.iter() {
+            // Re-run the length checks on every sample
+            DASSample::from_bytes(&sample.as_ssz_bytes())?;
+        }
+
+        Ok(line)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.as_ssz_bytes()
+    }
+}
+
+/// Opaque sample id: sha256(commitment || cell_index).  Binds a sample key to the blob it came from.
+pub fn sample_id(commitment: &[u8], cell_index: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(commitment);
+    hasher.update(cell_index.to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Decodes and verifies a sample stored under an opaque sample id.
+pub fn verify_sample(kzg: &KzgSettings, id: &[u8; 32], content: &[u8]) -> anyhow::Result<DASSample> {
+    let sample = DASSample::from_bytes(content)?;
+
+    // Without this a peer could send a perfectly valid cell from some other blob
+    ensure!(
+        sample_id(&sample.commitment, sample.cell_index) == *id,
+        "Sample does not match content key {}", hex::encode(id)
+    );
+    sample.verify(kzg)?;
+
+    Ok(sample)
+}
+
+/// Decodes and verifies a sample stored under its (slot, blob, row, column) key.
+pub fn verify_cell(kzg: &KzgSettings, commitments: &BlobCommitments, key: &SampleKey, content: &[u8]) -> anyhow::Result<DASSample> {
+    let sample = DASSample::from_bytes(content)?;
+
+    let commitment = commitments
+        .get(key.slot, key.blob_index, key.row)
+        .ok_or_else(|| anyhow!("No commitment known for {}", key))?;
+    ensure!(sample.commitment == commitment, "Sample commitment does not match blob commitment for {}", key);
+    ensure!(sample.cell_index == key.column, "Sample cell index {} does not match {}", sample.cell_index, key);
+    sample.verify(kzg)?;
+
+    Ok(sample)
+}
+
+/// Decodes and verifies a full row: every cell of the row's extended blob, in order.
+pub fn verify_row(kzg: &KzgSettings, commitments: &BlobCommitments, key: &LineKey, content: &[u8]) -> anyhow::Result<DASLine> {
+    let line = DASLine::from_bytes(content)?;
+    ensure!(line.samples.len() == CELLS_PER_EXT_BLOB, "Row {} has {} cells", key, line.samples.len());
+
+    let commitment = commitments
+        .get(key.slot, key.blob_index, key.index)
+        .ok_or_else(|| anyhow!("No commitment known for row {}", key))?;
+    for (column, sample) in line.samples.iter().enumerate() {
+        ensure!(sample.commitment == commitment, "Cell {} does not belong to row {}", column, key);
+        ensure!(sample.cell_index == column as u64, "Cell {} of row {} is out of order", column, key);
+    }
+    verify_batch(kzg, &line.samples)?;
+
+    Ok(line)
+}
+
+/// Decodes and verifies a full column: one cell from every row of the extended matrix, in order.
+pub fn verify_column(kzg: &KzgSettings, commitments: &BlobCommitments, key: &LineKey, content: &[u8]) -> anyhow::Result<DASLine> {
+    let line = DASLine::from_bytes(content)?;
+    ensure!(!line.samples.is_empty() && line.samples.len() % 2 == 0, "Column {} has {} cells", key, line.samples.len());
+    ensure!(
+        commitments.get(key.slot, key.blob_index, line.samples.len() as u64).is_none(),
+        "Column {} is missing rows", key
+    );
+
+    for (row, sample) in line.samples.iter().enumerate() {
+        let commitment = commitments
+            .get(key.slot, key.blob_index, row as u64)
+            .ok_or_else(|| anyhow!("No commitment known for row {} of column {}", row, key))?;
+        ensure!(sample.commitment == commitment, "Cell {} does not belong to column {}", row, key);
+        ensure!(sample.cell_index == key.index, "Cell {} of column {} has cell index {}", row, key, sample.cell_index);
+    }
+    verify_batch(kzg, &line.samples)?;
+
+    Ok(line)
+}
+
+fn verify_batch(kzg: &KzgSettings, samples: &[DASSample]) -> anyhow::Result<()> {
+    let mut commitments = Vec::with_capacity(samples.len());
+    let mut indices = Vec::with_capacity(samples.len());
+    let mut cells = Vec::with_capacity(samples.len());
+    let mut proofs = Vec::with_capacity(samples.len());
+    for sample in samples {
+        commitments.push(Bytes48::from_bytes(&sample.commitment)?);
+        indices.push(sample.cell_index);
+        cells.push(Cell::from_bytes(&sample.cell)?);
+        proofs.push(Bytes48::from_bytes(&sample.proof)?);
+    }
+
+    let valid = kzg.verify_cell_kzg_proof_batch(&commitments, &indices, &cells, &proofs)?;
+    ensure!(valid, "Invalid KZG proof in batch of {} cells", samples.len());
+
+    Ok(())
+}
+
+/// Blob commitments a node knows about, keyed by (slot, blob index, row).
+/// In a real network these come from beacon blocks.  Here whoever publishes a blob registers them.
+#[derive(Clone, Default)]
+pub struct BlobCommitments(Arc<RwLock<HashMap<(u64, u64, u64), Vec<u8>>>>);
+
+impl BlobCommitments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, slot: u64, blob_index: u64, row: u64, commitment: Vec<u8>) {
+        self.0.write().insert((slot, blob_index, row), commitment);
+    }
+
+    pub fn get(&self, slot: u64, blob_index: u64, row: u64) -> Option<Vec<u8>> {
+        self.0.read().get(&(slot, blob_index, row)).cloned()
+    }
+
+    /// Commitments of rows 0, 1, ... up to the first one we don't know.  One row under 1D extension.
+    pub fn rows(&self, slot: u64, blob_index: u64) -> Vec<Vec<u8>> {
+        let commitments = self.0.read();
+        (0..)
+            .map_while(|row| commitments.get(&(slot, blob_index, row)).cloned())
+            .collect()
+    }
+}
+
+pub fn load_trusted_setup<P: AsRef<Path>>(path: P) -> anyhow::Result<KzgSettings> {
+    KzgSettings::load_trusted_setup_file(path.as_ref(), 0)
+        .map_err(|err| anyhow!("Unable to load trusted setup: {:?}", err))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discv5_overlay::types::validation::Validator;
+
+    use crate::{
+        content_key::{DASContentKey, DASValidator, SecureDASContentKey, SecureDASValidator},
+        erasure::{self, ExtendedBlob},
+    };
+
+    fn kzg() -> KzgSettings {
+        load_trusted_setup(TRUSTED_SETUP_PATH).unwrap()
+    }
+
+    fn extended(kzg: &KzgSettings) -> ExtendedBlob {
+        let blob = erasure::blob_from_data(b"verify me").unwrap();
+        erasure::extend_blob(kzg, &blob, 3, 1).unwrap()
+    }
+
+    fn commitments(extended: &ExtendedBlob) -> BlobCommitments {
+        let commitments = BlobCommitments::new();
+        commitments.insert(extended.slot, extended.blob_index, extended.row, extended.commitment.clone());
+        commitments
+    }
+
+    #[test]
+    fn extended_samples_verify() {
+        let kzg = kzg();
+        let extended = extended(&kzg);
+        for sample in extended.samples.iter().step_by(17) {
+            sample.verify(&kzg).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_flipped_cell_bit_fails_its_proof() {
+        let kzg = kzg();
+        let mut sample = extended(&kzg).samples[5].clone();
+        sample.cell[31] ^= 1;
+        assert!(sample.verify(&kzg).is_err());
+    }
+
+    #[test]
+    fn a_cell_moved_to_another_index_fails_its_proof() {
+        let kzg = kzg();
+        let mut sample = extended(&kzg).samples[5].clone();
+        sample.cell_index = 6;
+        assert!(sample.verify(&kzg).is_err());
+    }
+
+    #[test]
+    fn from_bytes_checks_lengths() {
+        let kzg = kzg();
+        let sample = extended(&kzg).samples[0].clone();
+        assert_eq!(DASSample::from_bytes(&sample.to_bytes()).unwrap(), sample);
+
+        let short_proof = DASSample { proof: vec![0; BYTES_PER_PROOF - 1], ..sample.clone() };
+        assert!(DASSample::from_bytes(&short_proof.to_bytes()).is_err());
+        let out_of_range = DASSample { cell_index: CELLS_PER_EXT_BLOB as u64, ..sample };
+        assert!(DASSample::from_bytes(&out_of_range.to_bytes()).is_err());
+        assert!(DASSample::from_bytes(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn opaque_ids_bind_the_sample_to_its_blob() {
+        let kzg = kzg();
+        let extended = extended(&kzg);
+        let sample = &extended.samples[9];
+        let id = sample_id(&sample.commitment, sample.cell_index);
+
+        verify_sample(&kzg, &id, &sample.to_bytes()).unwrap();
+        // A valid sample, just not the one asked for
+        assert!(verify_sample(&kzg, &id, &extended.samples[10].to_bytes()).is_err());
+    }
+
+    #[test]
+    fn cells_are_checked_against_the_known_commitment() {
+        let kzg = kzg();
+        let extended = extended(&kzg);
+        let commitments = commitments(&extended);
+        let key = SampleKey::new(extended.slot, extended.blob_index, 0, 9);
+        let content = extended.samples[9].to_bytes();
+
+        verify_cell(&kzg, &commitments, &key, &content).unwrap();
+        // Wrong column, then a blob nobody registered
+        assert!(verify_cell(&kzg, &commitments, &SampleKey::new(key.slot, key.blob_index, 0, 10), &content).is_err());
+        assert!(verify_cell(&kzg, &BlobCommitments::new(), &key, &content).is_err());
+    }
+
+    #[test]
+    fn rows_verify_as_a_whole() {
+        let kzg = kzg();
+        let extended = extended(&kzg);
+        let commitments = commitments(&extended);
+        let key = LineKey::new(extended.slot, extended.blob_index, 0);
+
+        let row = DASLine { samples: extended.samples.clone() };
+        verify_row(&kzg, &commitments, &key, &row.to_bytes()).unwrap();
+
+        let mut swapped = row.clone();
+        swapped.samples.swap(0, 1);
+        assert!(verify_row(&kzg, &commitments, &key, &swapped.to_bytes()).is_err());
+        let mut short = row;
+        short.samples.pop();
+        assert!(verify_row(&kzg, &commitments, &key, &short.to_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn both_validators_accept_valid_samples_only() {
+        let kzg = Arc::new(kzg());
+        let extended = extended(&kzg);
+        let commitments = commitments(&extended);
+        let key = SampleKey::new(extended.slot, extended.blob_index, 0, 2);
+        let valid = extended.samples[2].to_bytes();
+        let mut tampered = extended.samples[2].clone();
+        tampered.cell[31] ^= 1;
+        let tampered = tampered.to_bytes();
+
+        let das = DASValidator::new(kzg.clone(), commitments.clone());
+        assert!(das.validate_content(&DASContentKey::Cell(key), &valid).await.is_ok());
+        assert!(das.validate_content(&DASContentKey::Cell(key), &tampered).await.is_err());
+
+        let secure_das = SecureDASValidator::new(kzg, commitments);
+        assert!(secure_das.validate_content(&SecureDASContentKey::Cell(key), &valid).await.is_ok());
+        assert!(secure_das.validate_content(&SecureDASContentKey::Cell(key), &tampered).await.is_err());
+    }
+}
@@ -0,0 +1,562 @@
+use discv5::{enr::NodeId, Enr};
+use discv5_overlay::{
+    portalnet::{
+        overlay::OverlayProtocol,
+        storage::ContentStore,
+        types::{
+            content_key::OverlayContentKey,
+            distance::XorMetric,
+            messages::Content,
+        },
+    },
+    types::validation::Validator,
+};
+use futures::future::join_all;
+use parking_lot::Mutex;
+use rand::{seq::index, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    content_key::{DASContentKey, SampleKey, SecureDASContentKey},
+    erasure::{ExtensionMode, SAMPLES_PER_BLOB},
+    matrix::{self, Line},
+    node_struct::DASNode,
+    overlay,
+    sample,
+    transport::Transport,
+};
+
+/*
+    Client side of DAS: is this blob available?
+
+    Pick k random cells of the extended blob, look each one up on the DAS overlay with FindContent
+    and check the KZG proof of whatever comes back.  If every sample comes back valid the blob is
+    available with probability 1 - 2^-k.
+
+    Verdicts:
+        - Available:     every sample found and valid
+        - Unavailable:   some sample was answered with "not here" or with invalid bytes by every peer asked
+        - Inconclusive:  no sample failed outright, but some lookups ran out of time, had no peer
+                         answer at all, or had no peer to ask
+
+    Fallback:
+        The SecureDAS overlay is the backup DHT.  When a lookup on the DAS overlay fails in a way the
+        FallbackPolicy cares about, the same sample is looked up again on the SecureDAS overlay.  Every
+        lookup records which network (if any) served it.
+
+    2D extension:
+        Samples are drawn from every cell of the extended matrix, not just row 0.  A cell neither
+        overlay could serve is repaired from its column: look up the column's other cells on the DAS
+        overlay and, with half of them, rebuild the whole column (matrix::repair_line).  Columns are
+        2n cells long for n blobs, so that's a handful of lookups.
+
+    Notes:
+        - Lookups walk towards the content id: ask the closest peers we know, follow the ENRs they return
+        - Each lookup has its own timeout.  All k run concurrently
+        - A peer whose content fails verification is recorded as an offender, in the lookup and in the
+          node's stats, and the lookup moves on to the next closest peer
+*/
+
+#[derive(Clone, Debug)]
+pub struct SamplingConfig {
+    /// k, number of random samples per blob
+    pub samples: usize,
+    pub lookup_timeout: Duration,
+    /// Peers to ask before giving up on a single sample
+    pub max_peers_per_lookup: usize,
+    pub fallback: FallbackPolicy,
+    /// Timeout for the retry on the SecureDAS overlay
+    pub fallback_timeout: Duration,
+    /// How the blobs being sampled were extended
+    pub mode: ExtensionMode,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            samples: 16,
+            lookup_timeout: Duration::from_secs(4),
+            max_peers_per_lookup: 8,
+            fallback: FallbackPolicy::OnTimeoutOrInvalid,
+            fallback_timeout: Duration::from_secs(4),
+            mode: ExtensionMode::default(),
+        }
+    }
+}
+
+/// When a failed DAS overlay lookup gets retried on the SecureDAS overlay.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackPolicy {
+    Never,
+    /// Timeouts, unreachable peers, an empty routing table and invalid content.  Not "nobody has it"
+    OnTimeoutOrInvalid,
+    /// Also retry samples nobody on the DAS overlay had
+    OnAnyFailure,
+}
+
+impl FallbackPolicy {
+    pub fn applies_to(&self, outcome: &LookupOutcome) -> bool {
+        match (self, outcome) {
+            (_, LookupOutcome::Found) => false,
+            (FallbackPolicy::Never, _) => false,
+            (FallbackPolicy::OnTimeoutOrInvalid, LookupOutcome::NotFound) => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Network {
+    DAS,
+    SecureDAS,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Verdict {
+    Available,
+    Unavailable,
+    Inconclusive,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LookupOutcome {
+    Found,
+    NotFound,
+    Invalid(String),
+    /// The lookup as a whole ran out of time
+    TimedOut,
+    /// Every peer asked failed to answer: request timeouts, transport or uTP errors.  The last error
+    Unreachable(String),
+    /// The routing table had no one to ask
+    NoPeers,
+}
+
+/// What a lookup has done so far.  Owned by the caller, so it survives the lookup timing out.
+#[derive(Clone, Debug, Default)]
+pub struct LookupProgress {
+    pub queried: usize,
+    /// Peers that answered with content failing verification
+    pub offenders: Vec<NodeId>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SampleLookup {
+    pub key: SampleKey,
+    /// Final outcome, after any fallback
+    pub outcome: LookupOutcome,
+    /// Outcome on the DAS overlay alone
+    pub primary_outcome: LookupOutcome,
+    pub served_by: Option<Network>,
+    pub fell_back: bool,
+    /// Rebuilt from the rest of its column after both overlays failed to serve it.  2D extension only
+    pub repaired: bool,
+    pub elapsed: Duration,
+    pub peers_queried: usize,
+    /// Peers that answered with content failing verification, on either overlay
+    pub offenders: Vec<NodeId>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SamplingResult {
+    pub slot: u64,
+    pub blob_index: u64,
+    pub verdict: Verdict,
+    pub lookups: Vec<SampleLookup>,
+    pub elapsed: Duration,
+}
+
+impl SamplingResult {
+    pub fn failed(&self) -> Vec<SampleKey> {
+        self.lookups
+            .iter()
+            .filter(|l| l.outcome != LookupOutcome::Found)
+            .map(|l| l.key)
+            .collect()
+    }
+
+    /// Samples the DAS overlay failed to serve that the SecureDAS overlay did.
+    pub fn rescued_by_secure_overlay(&self) -> usize {
+        self.lookups
+            .iter()
+            .filter(|l| l.fell_back && l.served_by == Some(Network::SecureDAS))
+            .count()
+    }
+
+    /// Samples no overlay served that were rebuilt from their column.
+    pub fn repaired(&self) -> usize {
+        self.lookups.iter().filter(|l| l.repaired).count()
+    }
+
+    /// Responses that failed verification, over all lookups.
+    pub fn invalid_responses(&self) -> usize {
+        self.lookups.iter().map(|l| l.offenders.len()).sum()
+    }
+
+    pub fn offenders(&self) -> HashSet<NodeId> {
+        self.lookups.iter().flat_map(|l| l.offenders.iter().copied()).collect()
+    }
+}
+
+impl DASNode {
+    /// Runs data availability sampling for one blob with sample indices drawn from `rng`.
+    pub async fn sample_blob<R: Rng>(&self, slot: u64, blob_index: u64, config: &SamplingConfig, rng: &mut R) -> SamplingResult {
+        let indices = self.choose_samples(slot, blob_index, config, rng);
+        self.sample_blob_at(slot, blob_index, &indices, config).await
+    }
+
+    /// config.samples random sample indices for a blob.  Under 2D extension they cover every row of
+    /// the matrix we know the commitment of.
+    pub fn choose_samples<R: Rng>(&self, slot: u64, blob_index: u64, config: &SamplingConfig, rng: &mut R) -> Vec<u64> {
+        let rows = match config.mode {
+            ExtensionMode::OneDimensional => 1,
+            ExtensionMode::TwoDimensional => self.commitments.rows(slot, blob_index).len().max(1),
+        };
+        choose_samples(rng, config.samples, rows)
+    }
+
+    /// Same as sample_blob, but with the sample indices picked by the caller.
+    /// Index i is cell i % SAMPLES_PER_BLOB of row i / SAMPLES_PER_BLOB.
+    pub async fn sample_blob_at(&self, slot: u64, blob_index: u64, indices: &[u64], config: &SamplingConfig) -> SamplingResult {
+        let start = Instant::now();
+
+        let lookups = join_all(indices.iter().map(|&index| async move {
+            let samples_per_row = SAMPLES_PER_BLOB as u64;
+            let key = SampleKey::new(slot, blob_index, index / samples_per_row, index % samples_per_row);
+            let lookup_start = Instant::now();
+            let verify = |content: &[u8]| sample::verify_cell(&self.kzg, &self.commitments, &key, content).map(|_| ());
+            // Outlives the find_content futures, so peers asked and offenders found before a timeout are kept
+            let mut progress = LookupProgress::default();
+
+            let primary_outcome = tokio::time::timeout(
+                config.lookup_timeout,
+                find_content(&self.overlay, &self.transport, DASContentKey::Cell(key), verify, config.max_peers_per_lookup, &mut progress),
+            )
+            .await
+            .unwrap_or(LookupOutcome::TimedOut);
+
+            let fell_back = config.fallback.applies_to(&primary_outcome);
+            let (outcome, served_by) = if primary_outcome == LookupOutcome::Found {
+                (LookupOutcome::Found, Some(Network::DAS))
+            } else if fell_back {
+                let outcome = tokio::time::timeout(
+                    config.fallback_timeout,
+                    find_content(&self.secure_overlay, &self.transport, SecureDASContentKey::Cell(key), verify, config.max_peers_per_lookup, &mut progress),
+                )
+                .await
+                .unwrap_or(LookupOutcome::TimedOut);
+                let served_by = (outcome == LookupOutcome::Found).then_some(Network::SecureDAS);
+                (outcome, served_by)
+            } else {
+                (primary_outcome.clone(), None)
+            };
+
+            let repaired = config.mode == ExtensionMode::TwoDimensional
+                && outcome != LookupOutcome::Found
+                && tokio::time::timeout(config.lookup_timeout, self.repair_from_column(key, config, &mut progress))
+                    .await
+                    .unwrap_or(false);
+            let outcome = if repaired { LookupOutcome::Found } else { outcome };
+            self.stats.record_offenders(&progress.offenders);
+
+            SampleLookup {
+                key,
+                outcome,
+                primary_outcome,
+                served_by,
+                fell_back,
+                repaired,
+                elapsed: lookup_start.elapsed(),
+                peers_queried: progress.queried,
+                offenders: progress.offenders,
+            }
+        }))
+        .await;
+
+        SamplingResult {
+            slot,
+            blob_index,
+            verdict: verdict(&lookups),
+            lookups,
+            elapsed: start.elapsed(),
+        }
+    }
+}
+
+impl DASNode {
+    /// Looks up the other cells of `key`'s column and, if half of them come back valid, rebuilds
+    /// the column.  True if that gives a cell for `key` that verifies.
+    async fn repair_from_column(&self, key: SampleKey, config: &SamplingConfig, progress: &mut LookupProgress) -> bool {
+        let row_commitments = self.commitments.rows(key.slot, key.blob_index);
+        let others: Vec<SampleKey> = (0..row_commitments.len() as u64)
+            .filter(|&row| row != key.row)
+            .map(|row| SampleKey::new(key.slot, key.blob_index, row, key.column))
+            .collect();
+
+        // Each lookup gets its own progress, merged below.  They can't share the caller's &mut
+        let fetched = join_all(others.iter().map(|&other| async move {
+            let mut progress = LookupProgress::default();
+            let found = Mutex::new(None);
+            let verify = |content: &[u8]| -> anyhow::Result<()> {
+                sample::verify_cell(&self.kzg, &self.commitments, &other, content)?;
+                *found.lock() = Some(content.to_vec());
+                Ok(())
+            };
+            find_content(&self.overlay, &self.transport, DASContentKey::Cell(other), verify, config.max_peers_per_lookup, &mut progress).await;
+            (other.row, found.into_inner(), progress)
+        }))
+        .await;
+
+        let mut cells = Vec::new();
+        for (row, content, lookup) in fetched {
+            progress.queried += lookup.queried;
+            progress.offenders.extend(lookup.offenders);
+            if let Some(content) = content {
+                cells.push((row, content));
+            }
+        }
+
+        match matrix::repair_line(&self.kzg, &row_commitments, Line::Column(key.column), &cells) {
+            Ok(column) => column
+                .get(key.row as usize)
+                .map_or(false, |sample| sample::verify_cell(&self.kzg, &self.commitments, &key, &sample.to_bytes()).is_ok()),
+            Err(_) => false,
+        }
+    }
+}
+
+pub fn verdict(lookups: &[SampleLookup]) -> Verdict {
+    let failed = lookups
+        .iter()
+        .any(|l| matches!(l.outcome, LookupOutcome::NotFound | LookupOutcome::Invalid(_)));
+    let undecided = lookups
+        .iter()
+        .any(|l| matches!(l.outcome, LookupOutcome::TimedOut | LookupOutcome::Unreachable(_) | LookupOutcome::NoPeers));
+
+    if failed {
+        Verdict::Unavailable
+    } else if undecided {
+        Verdict::Inconclusive
+    } else {
+        Verdict::Available
+    }
+}
+
+/// k distinct cell indices out of `rows` rows of extended blobs.  `rows` is 1 under 1D extension.
+pub fn choose_samples<R: Rng>(rng: &mut R, k: usize, rows: usize) -> Vec<u64> {
+    let cells = rows * SAMPLES_PER_BLOB;
+    index::sample(rng, cells, k.min(cells))
+        .into_iter()
+        .map(|i| i as u64)
+        .collect()
+}
+
+/// Walks the overlay towards `key`'s content id until some peer returns content that passes `verify`.
+/// Peers asked and peers whose content fails `verify` are counted in `progress` as the walk goes.
+/// NotFound only if some peer actually answered.
+pub async fn find_content<TContentKey, TValidator, TStore, F>(
+    overlay: &OverlayProtocol<TContentKey, XorMetric, TValidator, TStore>,
+    transport: &Transport,
+    key: TContentKey,
+    verify: F,
+    max_peers: usize,
+    progress: &mut LookupProgress,
+) -> LookupOutcome
+where
+    TContentKey: OverlayContentKey + Clone + Into<Vec<u8>> + Send + Sync,
+    TValidator: 'static + Validator<TContentKey> + Send + Sync,
+    TStore: 'static + ContentStore + Send + Sync,
+    F: Fn(&[u8]) -> anyhow::Result<()>,
+{
+    let target = key.content_id();
+    let local_id = overlay.local_enr().node_id();
+    let mut candidates = overlay::closest_enrs(overlay.table_entries_enr(), &target, max_peers);
+    let mut seen: HashSet<_> = candidates.iter().map(|enr| enr.node_id()).collect();
+    let mut queried = 0;
+    let mut answered = 0;
+    let mut last_invalid = None;
+    let mut last_error = None;
+
+    while !candidates.is_empty() && queried < max_peers {
+        let enr = candidates.remove(0);
+        let node_id = enr.node_id();
+        queried += 1;
+        progress.queried += 1;
+
+        let content = match overlay.send_find_content(enr.clone(), key.clone().into()).await {
+            Ok(Content::Content(bytes)) => bytes.to_vec(),
+            // Too big for a TalkResp, comes over uTP (or the virtual network) instead
+            Ok(Content::ConnectionId(conn_id)) => match transport.find_content_stream(overlay, enr, conn_id).await {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    last_error = Some(err.to_string());
+                    continue;
+                }
+            },
+            Ok(Content::Enrs(enrs)) => {
+                answered += 1;
+                for enr in enrs.into_iter().map(Enr::from) {
+                    if enr.node_id() != local_id && seen.insert(enr.node_id()) {
+                        candidates.push(enr);
+                    }
+                }
+                candidates = overlay::closest_enrs(candidates, &target, max_peers);
+                continue;
+            }
+            Err(err) => {
+                last_error = Some(format!("{:?}", err));
+                continue;
+            }
+        };
+
+        answered += 1;
+        match verify(&content) {
+            Ok(()) => return LookupOutcome::Found,
+            Err(err) => {
+                progress.offenders.push(node_id);
+                last_invalid = Some(err.to_string());
+            }
+        }
+    }
+
+    match (last_invalid, last_error) {
+        (Some(err), _) => LookupOutcome::Invalid(err),
+        _ if queried == 0 => LookupOutcome::NoPeers,
+        (None, Some(err)) if answered == 0 => LookupOutcome::Unreachable(err),
+        _ => LookupOutcome::NotFound,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        erasure,
+        links::{Latency, LinkModel, LinkProfile},
+        network::{NetworkConfig, SimNetwork},
+        node_struct::NodeConfig,
+        topology::Topology,
+        transport::{TransportConfig, VirtualConfig},
+    };
+
+    async fn virtual_network(rng: &mut StdRng) -> SimNetwork {
+        let config = NetworkConfig {
+            nodes: 8,
+            topology: Topology::RandomRegular { degree: 4 },
+            base_port: 19000,
+            request_timeout: Duration::from_secs(2),
+            node: NodeConfig {
+                pruning: None,
+                ..Default::default()
+            },
+            transport: TransportConfig::Virtual(VirtualConfig {
+                links: LinkModel {
+                    default: LinkProfile::new(Latency::Constant { ms: 1 }, 0.0),
+                    ..Default::default()
+                },
+                timeout: Duration::from_secs(2),
+            }),
+        };
+        SimNetwork::start(&config, rng).await
+    }
+
+    async fn sample_everywhere_but(network: &SimNetwork, proposer: usize, config: &SamplingConfig, rng: &mut StdRng) {
+        for (i, node) in network.nodes.iter().enumerate().filter(|(i, _)| *i != proposer) {
+            let result = node.sample_blob(0, 0, config, rng).await;
+            let failed: Vec<_> = result.lookups.iter().filter(|l| l.outcome != LookupOutcome::Found).collect();
+            assert_eq!(result.verdict, Verdict::Available, "node {}: {:?}", i, failed);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_published_blob_is_available_end_to_end() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let network = virtual_network(&mut rng).await;
+
+        let blob = erasure::blob_from_data(b"end to end").unwrap();
+        let report = network.nodes[0].publish_blob(&[blob], 0, 0, ExtensionMode::OneDimensional, 3).await.unwrap();
+        assert!(report.undelivered().is_empty(), "undelivered: {:?}", report.undelivered());
+
+        sample_everywhere_but(&network, 0, &SamplingConfig::default(), &mut rng).await;
+        network.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_published_matrix_is_available_end_to_end() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let network = virtual_network(&mut rng).await;
+
+        let blobs = vec![
+            erasure::blob_from_data(b"first row").unwrap(),
+            erasure::blob_from_data(b"second row").unwrap(),
+        ];
+        let report = network.nodes[0].publish_blob(&blobs, 0, 0, ExtensionMode::TwoDimensional, 3).await.unwrap();
+        assert_eq!(report.deliveries.len(), 4 * SAMPLES_PER_BLOB);
+        assert!(report.undelivered().is_empty(), "undelivered: {:?}", report.undelivered());
+
+        let config = SamplingConfig {
+            mode: ExtensionMode::TwoDimensional,
+            ..Default::default()
+        };
+        sample_everywhere_but(&network, 0, &config, &mut rng).await;
+        network.shutdown().await;
+    }
+
+    fn lookup(outcome: LookupOutcome) -> SampleLookup {
+        SampleLookup {
+            key: SampleKey::new(0, 0, 0, 0),
+            primary_outcome: outcome.clone(),
+            outcome,
+            served_by: None,
+            fell_back: false,
+            repaired: false,
+            elapsed: Duration::ZERO,
+            peers_queried: 0,
+            offenders: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn fallback_policies() {
+        let outcomes = [
+            LookupOutcome::Found,
+            LookupOutcome::NotFound,
+            LookupOutcome::Invalid("bad proof".to_string()),
+            LookupOutcome::TimedOut,
+            LookupOutcome::Unreachable("timeout".to_string()),
+            LookupOutcome::NoPeers,
+        ];
+        let applies = |policy: FallbackPolicy| outcomes.iter().map(|o| policy.applies_to(o)).collect::<Vec<_>>();
+
+        assert_eq!(applies(FallbackPolicy::Never), [false; 6]);
+        assert_eq!(applies(FallbackPolicy::OnTimeoutOrInvalid), [false, false, true, true, true, true]);
+        assert_eq!(applies(FallbackPolicy::OnAnyFailure), [false, true, true, true, true, true]);
+    }
+
+    #[test]
+    fn one_missing_sample_makes_a_blob_unavailable() {
+        let found = || lookup(LookupOutcome::Found);
+        assert_eq!(verdict(&[found(), found()]), Verdict::Available);
+        assert_eq!(verdict(&[found(), lookup(LookupOutcome::TimedOut)]), Verdict::Inconclusive);
+        assert_eq!(verdict(&[lookup(LookupOutcome::NoPeers), lookup(LookupOutcome::NotFound)]), Verdict::Unavailable);
+        assert_eq!(verdict(&[found(), lookup(LookupOutcome::Invalid("bad proof".to_string()))]), Verdict::Unavailable);
+    }
+
+    #[test]
+    fn samples_are_distinct_and_in_range() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples = choose_samples(&mut rng, 40, 2);
+        assert_eq!(samples.len(), 40);
+        assert_eq!(samples.iter().collect::<HashSet<_>>().len(), 40);
+        assert!(samples.iter().all(|&i| i < 2 * SAMPLES_PER_BLOB as u64));
+
+        // Never more than there are
+        assert_eq!(choose_samples(&mut rng, SAMPLES_PER_BLOB + 5, 1).len(), SAMPLES_PER_BLOB);
+    }
+}
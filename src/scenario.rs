@@ -0,0 +1,916 @@
+use anyhow::{bail, Context};
+use c_kzg::Blob;
+use discv5::enr::NodeId;
+use futures::future::join_all;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    adversary::{self, AdversaryPolicy, CorruptPolicy, WithholdPolicy, WithholdTarget},
+    churn::{self, ChurnConfig, ChurnDriver, ChurnRecord},
+    discovery,
+    eclipse::{self, EclipseResults, EclipseSpec},
+    erasure::{self, ExtensionMode},
+    network::{NetworkConfig, RoutingTableStats, SimNetwork},
+    node_struct::NodeConfig,
+    links::LinkModel,
+    partition::{self, PartitionRecord, PartitionSpec, Phase},
+    pruning::{PruningConfig, SlotClock, DEFAULT_RETENTION_EPOCHS},
+    topology::{Topology, TopologyStats},
+    sampling::{FallbackPolicy, SamplingConfig, SamplingResult, Verdict},
+    storage::{StorageBackend, StorageConfig, StoreConfig},
+    transport::{TransportConfig, VirtualConfig},
+};
+
+/*
+    A full simulation run described in a file, so experiments can be checked in and rerun.
+
+        cargo run -- --scenario-file scenarios/baseline.toml --results results/baseline.json
+
+    TOML or JSON, picked by the file extension.  Everything but `name` and `nodes` has a default:
+
+        name = "baseline"
+        nodes = 20
+        seed = 7
+        slots = 4                  # run duration
+        slot_duration_ms = 12000
+        blobs_per_slot = 2
+        replication = 3
+        extension = "one_dimensional"  # or "two_dimensional", see matrix.rs
+
+        [topology]                 # any generator from topology.rs
+        kind = "small_world"
+        neighbors = 4
+        rewire = 0.1
+
+        [transport]
+        kind = "virtual"           # or "udp", the default
+        default = { latency = { kind = "normal", mean_ms = 50, jitter_ms = 15 }, loss = 0.01 }
+                                   # regions and region_links too, see links.rs
+                                   # kind = "udp" takes the same model as `links = { default = ... }`
+
+        [adversaries]
+        count = 2                  # picked at random, on top of any listed in `nodes`
+        behavior = { kind = "offline" }
+                                   # or { kind = "withhold", fraction = 0.5, target = { kind = "random" } },
+                                   # see adversary.rs for the targets
+                                   # or { kind = "corrupt", rate = 0.3 }
+
+        [storage]                  # per overlay store, same for both overlays
+        capacity = 4000000         # bytes.  The data radius shrinks as the store fills
+        max_bytes = 8000000        # hard limit, farthest content is evicted past it
+        dir = "stores"             # keep stores on disk under this directory.  In memory if not given
+        retention_epochs = 4096    # prune samples this many epochs old
+        retention_slots = 4        # or this many slots old, overrides retention_epochs
+        prune_interval_ms = 12000  # once per slot if not given
+        pruning = true             # false keeps every sample forever
+
+        [sampling]
+        samples = 16
+        lookup_timeout_ms = 4000
+        fallback = "on_timeout_or_invalid"
+
+        [churn]                    # optional, virtual transport only.  See churn.rs
+        mean_session_secs = 30
+        mean_downtime_secs = 15
+
+        [[partitions]]             # optional, virtual transport only.  See partition.rs
+        at_slot = 1
+        heal_slot = 3
+
+        [eclipse]                  # optional, see eclipse.rs
+        victim = 0
+        attackers = 16
+
+    Each slot, a random running honest node proposes `blobs_per_slot` blobs of random data.  Every
+    other running honest node then samples every blob.  Under 2D extension the slot's blobs are
+    published and sampled as one matrix, reported as blob 0.  Adversaries never propose, sample or churn.
+    Once the slot's work is done, the runner plays the churn events due before the next slot starts
+    and waits for it.  Partitions start and heal at the beginning of their slots, one at a time.
+    An eclipse starts at the beginning of its slot too, and its attackers only ever flood the victim.
+
+    Withholding and corrupting adversaries stay online and store what they're offered like everyone
+    else.  All of them share one seed, so they withhold or corrupt the same samples.
+
+    A sampler's "available" verdict is checked against what it could really have fetched: if fewer
+    than ExtensionMode::samples_needed of the blob's samples are held by a running node willing to
+    serve it, the verdict is counted as a false availability.
+
+    What corruption costs shows in the peers queried per lookup and the invalid responses, next to
+    the offenders samplers caught.  Rerun with different rates to compare.
+
+    The results file is JSON: per slot/blob publish and sampling numbers, plus a summary.
+
+    Everything random is drawn from one StdRng seeded with `seed`: node keys, topology, adversaries,
+    proposers, blob data and sample indices.  Rerunning with the seed from a results file rebuilds
+    the same network and makes the same choices.
+*/
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioFile {
+    pub name: String,
+    pub nodes: usize,
+    /// Random if not given.  The seed used is written to the results either way
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default = "default_base_port")]
+    pub base_port: u16,
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    #[serde(default)]
+    pub topology: Topology,
+    #[serde(default)]
+    pub transport: TransportSpec,
+    #[serde(default)]
+    pub adversaries: AdversarySpec,
+    #[serde(default = "default_blobs_per_slot")]
+    pub blobs_per_slot: u64,
+    /// Run duration, in slots
+    #[serde(default = "default_slots")]
+    pub slots: u64,
+    #[serde(default = "default_slot_duration_ms")]
+    pub slot_duration_ms: u64,
+    /// Peers each sample is offered to, per overlay
+    #[serde(default = "default_replication")]
+    pub replication: usize,
+    #[serde(default)]
+    pub extension: ExtensionMode,
+    #[serde(default)]
+    pub storage: StorageSpec,
+    #[serde(default)]
+    pub sampling: SamplingSpec,
+    /// No churn if not given
+    #[serde(default)]
+    pub churn: Option<ChurnConfig>,
+    #[serde(default)]
+    pub partitions: Vec<PartitionSpec>,
+    #[serde(default)]
+    pub eclipse: Option<EclipseSpec>,
+    /// Where to write the results.  Defaults to <name>-results.json
+    #[serde(default)]
+    pub results: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TransportSpec {
+    /// `links` shapes UDP the same way, none leaves messages to loopback
+    Udp {
+        #[serde(default)]
+        links: Option<LinkModel>,
+    },
+    Virtual(LinkModel),
+}
+
+impl Default for TransportSpec {
+    fn default() -> Self {
+        TransportSpec::Udp { links: None }
+    }
+}
+
+impl TransportSpec {
+    pub fn config(&self, timeout: Duration) -> TransportConfig {
+        match self {
+            TransportSpec::Udp { links } => TransportConfig::Udp(links.clone()),
+            TransportSpec::Virtual(links) => TransportConfig::Virtual(VirtualConfig {
+                links: links.clone(),
+                timeout,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdversarySpec {
+    /// Node indices that are adversarial
+    pub nodes: Vec<usize>,
+    /// Number of extra adversaries picked at random from the remaining nodes
+    pub count: usize,
+    pub behavior: Behavior,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Behavior {
+    /// Joins the routing tables, then never answers anything
+    #[default]
+    Offline,
+    /// Answers "not found" for `fraction` of the samples it holds
+    Withhold {
+        fraction: f64,
+        #[serde(default)]
+        target: WithholdTarget,
+    },
+    /// Serves `rate` of the samples it holds with bytes that fail verification
+    Corrupt { rate: f64 },
+}
+
+impl Behavior {
+    /// The policy every adversary gets.  None for adversaries that are simply stopped.
+    fn policy(&self, network: &SimNetwork, seed: u64) -> Option<AdversaryPolicy> {
+        match self {
+            Behavior::Offline => None,
+            Behavior::Corrupt { rate } => Some(AdversaryPolicy {
+                corrupt: Some(CorruptPolicy { rate: *rate, seed }),
+                ..Default::default()
+            }),
+            Behavior::Withhold { fraction, target } => {
+                let (blobs, requesters) = match target {
+                    WithholdTarget::Random => (None, None),
+                    WithholdTarget::Blobs { blobs } => (Some(blobs.iter().copied().collect()), None),
+                    WithholdTarget::Requesters { nodes } => {
+                        let ids = nodes.iter().map(|&i| network.nodes[i].overlay.local_enr().node_id()).collect();
+                        (None, Some(ids))
+                    }
+                };
+                Some(AdversaryPolicy {
+                    withhold: Some(WithholdPolicy {
+                        fraction: *fraction,
+                        blobs,
+                        requesters,
+                        seed,
+                    }),
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SamplingSpec {
+    pub samples: usize,
+    pub lookup_timeout_ms: u64,
+    pub max_peers_per_lookup: usize,
+    pub fallback: FallbackPolicy,
+    pub fallback_timeout_ms: u64,
+}
+
+impl Default for SamplingSpec {
+    fn default() -> Self {
+        let config = SamplingConfig::default();
+        Self {
+            samples: config.samples,
+            lookup_timeout_ms: config.lookup_timeout.as_millis() as u64,
+            max_peers_per_lookup: config.max_peers_per_lookup,
+            fallback: config.fallback,
+            fallback_timeout_ms: config.fallback_timeout.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSpec {
+    /// Bytes of content.  None claims the whole keyspace
+    pub capacity: Option<usize>,
+    /// Hard limit in bytes of content.  None never evicts
+    pub max_bytes: Option<usize>,
+    /// Base directory of the disk backend.  None keeps everything in memory
+    pub dir: Option<PathBuf>,
+    pub retention_epochs: u64,
+    /// Overrides retention_epochs when set
+    pub retention_slots: Option<u64>,
+    /// None prunes once per slot
+    pub prune_interval_ms: Option<u64>,
+    pub pruning: bool,
+}
+
+impl Default for StorageSpec {
+    fn default() -> Self {
+        Self {
+            capacity: None,
+            max_bytes: None,
+            dir: None,
+            retention_epochs: DEFAULT_RETENTION_EPOCHS,
+            retention_slots: None,
+            prune_interval_ms: None,
+            pruning: true,
+        }
+    }
+}
+
+impl StorageSpec {
+    pub fn pruning(&self, slot_duration: Duration) -> Option<PruningConfig> {
+        self.pruning.then(|| PruningConfig {
+            retention_epochs: self.retention_epochs,
+            retention_slots: self.retention_slots,
+            interval: self.prune_interval_ms.map_or(slot_duration, Duration::from_millis),
+        })
+    }
+
+    pub fn config(&self) -> StorageConfig {
+        let store = StoreConfig {
+            capacity: self.capacity,
+            max_bytes: self.max_bytes,
+            backend: match &self.dir {
+                Some(dir) => StorageBackend::Disk(dir.clone()),
+                None => StorageBackend::Memory,
+            },
+        };
+        StorageConfig {
+            das: store.clone(),
+            secure_das: store,
+        }
+    }
+}
+
+impl SamplingSpec {
+    pub fn config(&self, mode: ExtensionMode) -> SamplingConfig {
+        SamplingConfig {
+            samples: self.samples,
+            lookup_timeout: Duration::from_millis(self.lookup_timeout_ms),
+            max_peers_per_lookup: self.max_peers_per_lookup,
+            fallback: self.fallback,
+            fallback_timeout: Duration::from_millis(self.fallback_timeout_ms),
+            mode,
+        }
+    }
+}
+
+fn default_base_port() -> u16 {
+    9000
+}
+
+fn default_request_timeout_secs() -> u64 {
+    60
+}
+
+fn default_blobs_per_slot() -> u64 {
+    1
+}
+
+fn default_slots() -> u64 {
+    1
+}
+
+fn default_slot_duration_ms() -> u64 {
+    12_000
+}
+
+fn default_replication() -> usize {
+    3
+}
+
+impl ScenarioFile {
+    /// Reads a scenario, TOML or JSON depending on the extension.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| format!("Unable to read scenario {}", path.display()))?;
+        let scenario: Self = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents)?,
+            Some("json") => serde_json::from_str(&contents)?,
+            _ => bail!("Scenario files must be .toml or .json: {}", path.display()),
+        };
+        scenario.check()?;
+        Ok(scenario)
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        self.topology.check(self.nodes)?;
+        if self.storage.prune_interval_ms == Some(0) || self.slot_duration_ms == 0 {
+            bail!("Slots and the prune interval must be longer than 0 ms");
+        }
+        match &self.transport {
+            TransportSpec::Virtual(links) | TransportSpec::Udp { links: Some(links) } => links.check()?,
+            TransportSpec::Udp { links: None } => {}
+        }
+        if self.is_udp() {
+            let attackers = self.eclipse.as_ref().map_or(0, |eclipse| eclipse.attackers);
+            discovery::check_ports(self.base_port, self.nodes + attackers)?;
+        }
+        if let Some(&i) = self.adversaries.nodes.iter().find(|&&i| i >= self.nodes) {
+            bail!("Adversary {} doesn't exist, the scenario has {} nodes", i, self.nodes);
+        }
+        let adversaries = self.adversaries.nodes.len() + self.adversaries.count;
+        if adversaries + 2 > self.nodes {
+            bail!("{} adversaries leave fewer than 2 honest nodes out of {}", adversaries, self.nodes);
+        }
+        match &self.adversaries.behavior {
+            Behavior::Withhold { fraction, .. } if !(0.0..=1.0).contains(fraction) => {
+                bail!("Withhold fraction must be between 0 and 1, not {}", fraction);
+            }
+            Behavior::Withhold { target: WithholdTarget::Requesters { nodes }, .. } => {
+                if let Some(&i) = nodes.iter().find(|&&i| i >= self.nodes) {
+                    bail!("Withhold requester {} doesn't exist, there are {} nodes", i, self.nodes);
+                }
+            }
+            Behavior::Corrupt { rate } if !(0.0..=1.0).contains(rate) => {
+                bail!("Corrupt rate must be between 0 and 1, not {}", rate);
+            }
+            _ => {}
+        }
+        if self.churn.is_some() && self.is_udp() {
+            bail!("Churn needs the virtual transport, stopped nodes keep answering discv5 over UDP");
+        }
+        if !self.partitions.is_empty() && self.is_udp() {
+            bail!("Partitions need the virtual transport");
+        }
+        for partition in &self.partitions {
+            partition.check(self.nodes)?;
+        }
+        if let Some(eclipse) = &self.eclipse {
+            eclipse.check(self.nodes)?;
+            if self.adversaries.nodes.contains(&eclipse.victim) {
+                bail!("Eclipse victim {} can't also be an adversary", eclipse.victim);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_udp(&self) -> bool {
+        matches!(self.transport, TransportSpec::Udp { .. })
+    }
+
+    pub fn slot_duration(&self) -> Duration {
+        Duration::from_millis(self.slot_duration_ms)
+    }
+
+    pub fn results_path(&self) -> PathBuf {
+        self.results
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("{}-results.json", self.name)))
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScenarioResults {
+    pub name: String,
+    pub seed: u64,
+    pub nodes: usize,
+    pub adversaries: Vec<usize>,
+    pub behavior: Behavior,
+    pub topology: TopologyStats,
+    /// Sizes at the end of the run, over every node still running
+    pub routing_tables: RoutingTableStats,
+    pub slots: Vec<SlotResults>,
+    pub churn: Vec<ChurnRecord>,
+    pub partitions: Vec<PartitionRecord>,
+    pub eclipse: Option<EclipseResults>,
+    pub summary: Summary,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SlotResults {
+    pub slot: u64,
+    /// None if no honest node was online
+    pub proposer: Option<usize>,
+    pub blobs: Vec<BlobResults>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BlobResults {
+    pub blob_index: u64,
+    /// Set if the proposer couldn't publish the blob at all
+    pub publish_error: Option<String>,
+    pub samples: usize,
+    pub delivered: usize,
+    pub secure_delivered: usize,
+    pub samplers: usize,
+    pub available: usize,
+    pub unavailable: usize,
+    pub inconclusive: usize,
+    /// Available verdicts from samplers that couldn't have fetched enough samples to recover the blob
+    pub false_available: usize,
+    pub lookups: usize,
+    pub failed_lookups: usize,
+    pub rescued_by_secure_overlay: usize,
+    /// Samples rebuilt from their column after both overlays failed to serve them.  2D only
+    pub repaired: usize,
+    pub mean_lookup_ms: f64,
+    pub mean_sampling_ms: f64,
+    /// Including retries after invalid responses and on the SecureDAS overlay
+    pub mean_peers_queried: f64,
+    pub invalid_responses: usize,
+    /// Node indices some sampler caught serving invalid content
+    pub offenders: Vec<usize>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Summary {
+    pub blobs: usize,
+    pub sampling_runs: usize,
+    pub available: usize,
+    pub unavailable: usize,
+    pub inconclusive: usize,
+    /// available / sampling_runs
+    pub availability_rate: f64,
+    pub false_available: usize,
+    /// false_available / available
+    pub false_availability_rate: f64,
+    pub rescued_by_secure_overlay: usize,
+    pub repaired: usize,
+    pub mean_lookup_ms: f64,
+    pub mean_peers_queried: f64,
+    pub invalid_responses: usize,
+    pub offenders: Vec<usize>,
+}
+
+/// Runs the whole scenario: starts the network, plays every slot, shuts the network down.
+pub async fn run(scenario: &ScenarioFile) -> anyhow::Result<ScenarioResults> {
+    let seed = scenario.seed.unwrap_or_else(rand::random);
+    let mut rng = StdRng::seed_from_u64(seed);
+    println!("Scenario {}: {} nodes, {} slots, seed {}", scenario.name, scenario.nodes, scenario.slots, seed);
+
+    let clock = SlotClock::new(Instant::now(), scenario.slot_duration());
+    let request_timeout = Duration::from_secs(scenario.request_timeout_secs);
+    let config = NetworkConfig {
+        nodes: scenario.nodes,
+        topology: scenario.topology.clone(),
+        base_port: scenario.base_port,
+        request_timeout,
+        node: NodeConfig {
+            storage: scenario.storage.config(),
+            pruning: scenario.storage.pruning(scenario.slot_duration()),
+            clock,
+        },
+        transport: scenario.transport.config(request_timeout),
+    };
+    let mut network = SimNetwork::start(&config, &mut rng).await;
+
+    // Adversaries
+    let mut adversaries = scenario.adversaries.nodes.clone();
+    let victim = scenario.eclipse.as_ref().map(|eclipse| eclipse.victim);
+    let mut others: Vec<usize> = (0..scenario.nodes)
+        .filter(|i| !adversaries.contains(i) && Some(*i) != victim)
+        .collect();
+    others.shuffle(&mut rng);
+    adversaries.extend(others.into_iter().take(scenario.adversaries.count));
+    adversaries.sort_unstable();
+    adversaries.dedup();
+    let policy = scenario.adversaries.behavior.policy(&network, rng.gen());
+    for &i in &adversaries {
+        match &policy {
+            Some(policy) => network.set_policy(i, Some(policy.clone())),
+            None => network.stop_node(i).await,
+        }
+    }
+    let honest: Vec<usize> = (0..scenario.nodes).filter(|i| !adversaries.contains(i)).collect();
+    let index_of: HashMap<NodeId, usize> = network
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.overlay.local_enr().node_id(), i))
+        .collect();
+    println!("Adversaries ({:?}): {:?}", scenario.adversaries.behavior, adversaries);
+
+    let sampling_config = scenario.sampling.config(scenario.extension);
+    let mut churn = scenario.churn.as_ref().map(|config| {
+        let run_duration = scenario.slot_duration() * scenario.slots as u32;
+        let eligible: Vec<usize> = honest.iter().copied().filter(|&i| Some(i) != victim).collect();
+        let events = churn::schedule(&eligible, run_duration, config, &mut rng);
+        println!("Churn: {} events scheduled", events.len());
+        ChurnDriver::new(config.clone(), clock.genesis, events, honest.clone())
+    });
+    let mut churn_records = Vec::new();
+    let mut published = Vec::new();
+    let partition_sides: Vec<Vec<Vec<usize>>> = scenario
+        .partitions
+        .iter()
+        .map(|partition| partition.sides(scenario.nodes, &mut rng))
+        .collect();
+    let mut partition_records = Vec::new();
+    let mut eclipse_results: Option<EclipseResults> = None;
+
+    let mut slots = Vec::new();
+    for slot in 0..scenario.slots {
+        for partition in scenario.partitions.iter().filter(|p| p.heal_slot == slot) {
+            println!("Slot {}: healing partition from slot {}", slot, partition.at_slot);
+            partition::heal(&network);
+        }
+        for (partition, sides) in scenario.partitions.iter().zip(&partition_sides) {
+            if partition.at_slot == slot {
+                println!("Slot {}: partitioning into sides of {:?} nodes", slot, sides.iter().map(Vec::len).collect::<Vec<_>>());
+                partition::apply(&network, sides)?;
+            }
+        }
+
+        if let Some(spec) = scenario.eclipse.as_ref().filter(|e| e.at_slot == slot) {
+            println!("Slot {}: {} attackers eclipsing node {}", slot, spec.attackers, spec.victim);
+            let mut results = EclipseResults::new(spec);
+            results.measure(&network, slot, eclipse::Phase::Before, &published, &sampling_config, &mut rng).await;
+            results.launch(&mut network, spec, &mut rng).await;
+            eclipse_results = Some(results);
+        }
+
+        let online: Vec<usize> = honest.iter().copied().filter(|&i| network.is_running(i)).collect();
+        let proposer = online.choose(&mut rng).copied();
+        let mut blobs = Vec::new();
+        let groups = match proposer {
+            Some(_) => blob_groups(scenario, &mut rng),
+            None => Vec::new(),
+        };
+
+        for (blob_index, group) in groups {
+            let proposer = match proposer {
+                Some(proposer) => proposer,
+                None => break,
+            };
+            let mut results = BlobResults {
+                blob_index,
+                ..Default::default()
+            };
+
+            let report = match group {
+                Ok(group) => network.nodes[proposer].publish_blob(&group, slot, blob_index, scenario.extension, scenario.replication).await,
+                Err(err) => Err(err),
+            };
+            match report {
+                Ok(report) => {
+                    results.samples = report.deliveries.len();
+                    results.delivered = report.delivered();
+                    results.secure_delivered = report.secure_deliveries.iter().filter(|(_, d)| d.delivered()).count();
+                }
+                Err(err) => {
+                    results.publish_error = Some(err.to_string());
+                    blobs.push(results);
+                    continue;
+                }
+            }
+
+            published.push((slot, blob_index));
+
+            // Every other online honest node samples the blob, each with its own random indices
+            let samplers: Vec<(usize, Vec<u64>)> = online
+                .iter()
+                .filter(|&&i| i != proposer)
+                .map(|&i| (i, network.nodes[i].choose_samples(slot, blob_index, &sampling_config, &mut rng)))
+                .collect();
+            let sampled = join_all(
+                samplers
+                    .iter()
+                    .map(|(i, indices)| network.nodes[*i].sample_blob_at(slot, blob_index, indices, &sampling_config)),
+            )
+            .await;
+            record_sampling(&mut results, &sampled);
+            let offenders: BTreeSet<usize> = sampled
+                .iter()
+                .flat_map(|result| result.offenders())
+                .filter_map(|id| index_of.get(&id).copied())
+                .collect();
+            results.offenders = offenders.into_iter().collect();
+            results.false_available = samplers
+                .iter()
+                .zip(&sampled)
+                .filter(|(_, result)| result.verdict == Verdict::Available)
+                .filter(|((i, _), _)| adversary::servable(&network, slot, blob_index, *i) < scenario.extension.samples_needed(results.samples))
+                .count();
+            blobs.push(results);
+        }
+
+        println!("Slot {}: proposer {:?}, {} blobs", slot, proposer, blobs.len());
+        slots.push(SlotResults { slot, proposer, blobs });
+
+        for (partition, sides) in scenario.partitions.iter().zip(&partition_sides) {
+            if partition.measures(slot) {
+                let sides = partition::measure(&network, partition, sides, &published, &honest, &sampling_config, &mut rng).await;
+                let phase = if slot < partition.heal_slot { Phase::Partitioned } else { Phase::Healed };
+                partition_records.push(PartitionRecord { slot, phase, sides });
+            }
+        }
+
+        if let Some(results) = eclipse_results.as_mut() {
+            results.measure(&network, slot, eclipse::Phase::Attack, &published, &sampling_config, &mut rng).await;
+        }
+
+        // Churn, then wait out the rest of the slot
+        let next_slot = clock.genesis + clock.slot_duration * (slot as u32 + 1);
+        if let Some(driver) = churn.as_mut() {
+            churn_records.extend(driver.run_until(&mut network, next_slot, &published, &sampling_config, &mut rng).await);
+        }
+        tokio::time::sleep_until(next_slot.into()).await;
+    }
+
+    let routing_tables = network.routing_tables();
+    let topology = network.topology.clone();
+    network.shutdown().await;
+
+    let summary = summarize(&slots);
+    Ok(ScenarioResults {
+        name: scenario.name.clone(),
+        seed,
+        nodes: scenario.nodes,
+        adversaries,
+        behavior: scenario.adversaries.behavior.clone(),
+        topology,
+        routing_tables,
+        slots,
+        churn: churn_records,
+        partitions: partition_records,
+        eclipse: eclipse_results,
+        summary,
+    })
+}
+
+/// A slot's random blobs, by the blob index they're published under: one blob per index under 1D
+/// extension, all of them as one matrix at index 0 under 2D.
+fn blob_groups<R: Rng>(scenario: &ScenarioFile, rng: &mut R) -> Vec<(u64, anyhow::Result<Vec<Blob>>)> {
+    let blobs: Vec<anyhow::Result<Blob>> = (0..scenario.blobs_per_slot)
+        .map(|_| {
+            let mut data = vec![0u8; erasure::MAX_DATA_PER_BLOB];
+            rng.fill(&mut data[..]);
+            erasure::blob_from_data(&data)
+        })
+        .collect();
+
+    match scenario.extension {
+        ExtensionMode::OneDimensional => blobs
+            .into_iter()
+            .enumerate()
+            .map(|(i, blob)| (i as u64, blob.map(|blob| vec![blob])))
+            .collect(),
+        ExtensionMode::TwoDimensional => vec![(0, blobs.into_iter().collect())],
+    }
+}
+
+/// Writes the results as pretty JSON, creating the parent directory if needed.
+pub fn write_results(path: &Path, results: &ScenarioResults) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(results)?)?;
+    Ok(())
+}
+
+fn record_sampling(results: &mut BlobResults, sampled: &[SamplingResult]) {
+    results.samplers = sampled.len();
+    for result in sampled {
+        match result.verdict {
+            Verdict::Available => results.available += 1,
+            Verdict::Unavailable => results.unavailable += 1,
+            Verdict::Inconclusive => results.inconclusive += 1,
+        }
+        results.lookups += result.lookups.len();
+        results.failed_lookups += result.failed().len();
+        results.rescued_by_secure_overlay += result.rescued_by_secure_overlay();
+        results.repaired += result.repaired();
+        results.invalid_responses += result.invalid_responses();
+    }
+
+    let lookup_ms: Vec<f64> = sampled
+        .iter()
+        .flat_map(|r| r.lookups.iter().map(|l| l.elapsed.as_secs_f64() * 1000.0))
+        .collect();
+    results.mean_lookup_ms = mean(&lookup_ms);
+    let sampling_ms: Vec<f64> = sampled.iter().map(|r| r.elapsed.as_secs_f64() * 1000.0).collect();
+    results.mean_sampling_ms = mean(&sampling_ms);
+    let peers_queried: Vec<f64> = sampled
+        .iter()
+        .flat_map(|r| r.lookups.iter().map(|l| l.peers_queried as f64))
+        .collect();
+    results.mean_peers_queried = mean(&peers_queried);
+}
+
+fn summarize(slots: &[SlotResults]) -> Summary {
+    let mut summary = Summary::default();
+    let mut weighted_lookup_ms = 0.0;
+    let mut weighted_peers_queried = 0.0;
+    let mut lookups = 0;
+    let mut offenders = BTreeSet::new();
+
+    for blob in slots.iter().flat_map(|s| s.blobs.iter()) {
+        summary.blobs += 1;
+        summary.sampling_runs += blob.samplers;
+        summary.available += blob.available;
+        summary.unavailable += blob.unavailable;
+        summary.inconclusive += blob.inconclusive;
+        summary.false_available += blob.false_available;
+        summary.rescued_by_secure_overlay += blob.rescued_by_secure_overlay;
+        summary.repaired += blob.repaired;
+        summary.invalid_responses += blob.invalid_responses;
+        weighted_lookup_ms += blob.mean_lookup_ms * blob.lookups as f64;
+        weighted_peers_queried += blob.mean_peers_queried * blob.lookups as f64;
+        lookups += blob.lookups;
+        offenders.extend(blob.offenders.iter().copied());
+    }
+    if summary.sampling_runs > 0 {
+        summary.availability_rate = summary.available as f64 / summary.sampling_runs as f64;
+    }
+    if summary.available > 0 {
+        summary.false_availability_rate = summary.false_available as f64 / summary.available as f64;
+    }
+    if lookups > 0 {
+        summary.mean_lookup_ms = weighted_lookup_ms / lookups as f64;
+        summary.mean_peers_queried = weighted_peers_queried / lookups as f64;
+    }
+    summary.offenders = offenders.into_iter().collect();
+
+    summary
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::links::Latency;
+
+    fn parse(toml: &str) -> anyhow::Result<ScenarioFile> {
+        let scenario: ScenarioFile = toml::from_str(toml)?;
+        scenario.check()?;
+        Ok(scenario)
+    }
+
+    #[test]
+    fn checked_in_scenarios_load() {
+        for entry in fs::read_dir("scenarios").unwrap() {
+            let path = entry.unwrap().path();
+            if let Err(err) = ScenarioFile::load(&path) {
+                panic!("{}: {:#}", path.display(), err);
+            }
+        }
+    }
+
+    #[test]
+    fn only_name_and_nodes_are_required() {
+        let scenario = parse("name = \"minimal\"\nnodes = 10").unwrap();
+        assert_eq!(scenario.transport, TransportSpec::Udp { links: None });
+        assert_eq!(scenario.slots, 1);
+        assert_eq!(scenario.replication, 3);
+        assert_eq!(scenario.extension, ExtensionMode::OneDimensional);
+        assert_eq!(scenario.storage.retention_epochs, DEFAULT_RETENTION_EPOCHS);
+        assert!(scenario.seed.is_none());
+        assert!(parse("name = \"minimal\"").is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(parse("name = \"typo\"\nnodes = 10\nslot = 4").is_err());
+        assert!(parse("name = \"typo\"\nnodes = 10\n[storage]\nretention = 4").is_err());
+    }
+
+    #[test]
+    fn json_scenarios_parse_the_same() {
+        let scenario: ScenarioFile = serde_json::from_str(
+            r#"{"name": "json", "nodes": 10, "transport": {"kind": "virtual", "default": {"loss": 0.1}}}"#,
+        )
+        .unwrap();
+        match scenario.transport {
+            TransportSpec::Virtual(links) => assert_eq!(links.default.loss, 0.1),
+            other => panic!("Expected the virtual transport, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn udp_takes_a_link_model() {
+        let scenario = parse(
+            "name = \"shaped\"\nnodes = 10\n[transport]\nkind = \"udp\"\nlinks = { default = { latency = { kind = \"constant\", ms = 20 } } }",
+        )
+        .unwrap();
+        match scenario.transport {
+            TransportSpec::Udp { links: Some(links) } => assert_eq!(links.default.latency, Latency::Constant { ms: 20 }),
+            other => panic!("Expected shaped UDP, got {:?}", other),
+        }
+        assert!(parse("name = \"lossy\"\nnodes = 10\n[transport]\nkind = \"udp\"\nlinks = { default = { loss = 2.0 } }").is_err());
+    }
+
+    #[test]
+    fn retention_slots_override_epochs() {
+        let scenario = parse("name = \"short\"\nnodes = 10\n[storage]\nretention_epochs = 2\nretention_slots = 4").unwrap();
+        let pruning = scenario.storage.pruning(scenario.slot_duration()).unwrap();
+        assert_eq!(pruning.retention_slots(), 4);
+        assert_eq!(pruning.interval, scenario.slot_duration());
+    }
+
+    #[test]
+    fn checks_reject_what_the_run_cant_do() {
+        // Fewer than 2 honest nodes
+        assert!(parse("name = \"x\"\nnodes = 4\n[adversaries]\ncount = 3").is_err());
+        assert!(parse("name = \"x\"\nnodes = 4\n[adversaries]\nnodes = [7]").is_err());
+        // Churn and partitions need the virtual transport
+        assert!(parse("name = \"x\"\nnodes = 10\n[churn]\nmean_session_secs = 30.0").is_err());
+        assert!(parse("name = \"x\"\nnodes = 10\n[[partitions]]\nat_slot = 1\nheal_slot = 2").is_err());
+        // Past port 65535 over UDP, but not on the virtual transport
+        assert!(parse("name = \"x\"\nnodes = 100\nbase_port = 65500").is_err());
+        assert!(parse("name = \"x\"\nnodes = 100\nbase_port = 65500\n[transport]\nkind = \"virtual\"").is_ok());
+        assert!(parse("name = \"x\"\nnodes = 10\nslot_duration_ms = 0").is_err());
+    }
+
+    #[test]
+    fn two_dimensional_slots_publish_one_matrix() {
+        let mut scenario = parse("name = \"x\"\nnodes = 10\nblobs_per_slot = 3").unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let groups = blob_groups(&scenario, &mut rng);
+        assert_eq!(groups.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(groups.iter().all(|(_, blobs)| blobs.as_ref().unwrap().len() == 1));
+
+        scenario.extension = ExtensionMode::TwoDimensional;
+        let groups = blob_groups(&scenario, &mut rng);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].1.as_ref().unwrap().len(), 3);
+    }
+}
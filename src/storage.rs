@@ -0,0 +1,678 @@
+use discv5::enr::NodeId;
+use discv5_overlay::portalnet::{
+    storage::{ContentStore, ContentStoreError},
+    types::{
+        content_key::OverlayContentKey,
+        distance::{Distance, Metric, XorMetric},
+    },
+};
+use ethereum_types::U256;
+use ssz::Decode;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    adversary::{self, CorruptPolicy},
+    content_key::DASContentKey,
+    overlay::xor_distance,
+};
+
+/*
+    Where an overlay keeps its content.
+
+    Both overlays use DASContentStore.  The bytes live either in memory or on disk, picked per overlay
+    at startup.  On top of the bytes the store keeps an index of what it holds: the slot each sample
+    belongs to and its size.  That's what pruning works from.
+
+    On disk every piece of content is two files, named after the content id:
+        <id>      the content
+        <id>.key  the raw content key, so the index can be rebuilt after a restart
+    A node that restarts with the same node id (and the same directory) still has its custody data.
+
+    Notes:
+        - Files are written to a temp file and renamed, so a crash never leaves half a sample behind
+        - Directory layout: <base>/<node id>/<protocol>/<content id>
+        - DASContentKey and SecureDASContentKey share an SSZ layout, so one decoder finds the slot for both
+        - Opaque Sample([u8; 32]) keys carry no slot and are never pruned
+
+    Data radius:
+        A node with a storage capacity doesn't claim the whole keyspace.  Its radius shrinks linearly
+        with the free space left: MAX when empty, MIN_RADIUS_FRACTION of MAX when full.  The overlay
+        only accepts Offers for content within the current radius (is_key_within_radius_and_unavailable).
+        OverlayProtocol only takes the starting radius, so the node swaps the current one into the pings
+        it sends and the pongs it answers with (overlay::advertise_radius_in_request).  Peers see it shrink
+        as the store fills and stop offering content the node would decline.
+        Capacity is set with --storage-capacity or `capacity` in a scenario's [storage] table.
+
+    Hard limit:
+        The radius keeps new content out, `max_bytes` is what actually bounds the store.  Once a put
+        takes the store over the limit, content is evicted XOR-farthest from the local node id first
+        (possibly the content that was just put).  Evictions are counted so node stats can show them.
+        Pinned content is skipped: a proposer pins the samples it is offering, since the transfer to an
+        accepting peer reads them back out of its own store.  Unpinning enforces the limit again, so
+        a proposer doesn't keep samples far from its node id once they are handed off.
+        The limit is set with --max-storage-bytes or `max_bytes` in a scenario's [storage] table.
+*/
+
+/// A full store still accepts content within MAX / MIN_RADIUS_FRACTION of its node id
+pub const MIN_RADIUS_FRACTION: u64 = 256;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageBackend {
+    Memory,
+    /// Base directory shared by every node.  Each node/overlay gets its own subdirectory.
+    Disk(PathBuf),
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        StorageBackend::Memory
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StoreConfig {
+    pub backend: StorageBackend,
+    /// Bytes of content.  None means unlimited, with a radius of Distance::MAX
+    pub capacity: Option<usize>,
+    /// Hard limit in bytes of content.  None never evicts
+    pub max_bytes: Option<usize>,
+}
+
+/// Storage for each of a node's overlays.
+#[derive(Clone, Debug, Default)]
+pub struct StorageConfig {
+    pub das: StoreConfig,
+    pub secure_das: StoreConfig,
+}
+
+/// What the store knows about one piece of content without reading it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StoredContent {
+    pub slot: Option<u64>,
+    pub size: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvictionStats {
+    pub evicted: usize,
+    pub bytes_evicted: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PruneReport {
+    pub removed: usize,
+    pub bytes_freed: usize,
+}
+
+enum Backend {
+    Memory(HashMap<[u8; 32], Vec<u8>>),
+    Disk(PathBuf),
+}
+
+pub struct DASContentStore {
+    node_id: NodeId,
+    radius: Distance,
+    capacity: Option<usize>,
+    max_bytes: Option<usize>,
+    used: usize,
+    backend: Backend,
+    index: HashMap<[u8; 32], StoredContent>,
+    /// XOR distance from the local node id -> content id.  Last entry is the first to go
+    by_distance: BTreeMap<[u8; 32], [u8; 32]>,
+    evictions: EvictionStats,
+    /// Content ids eviction leaves alone, see pin()
+    pinned: HashSet<[u8; 32]>,
+    /// Set on corrupting adversaries only, see adversary.rs
+    corrupt: Option<CorruptPolicy>,
+}
+
+impl DASContentStore {
+    /// `protocol` names the overlay's subdirectory when the backend is on disk.
+    pub fn new(config: &StoreConfig, node_id: NodeId, protocol: &str) -> io::Result<Self> {
+        let mut store = Self {
+            node_id,
+            radius: Distance::MAX,
+            capacity: config.capacity,
+            max_bytes: config.max_bytes,
+            used: 0,
+            backend: Backend::Memory(HashMap::new()),
+            index: HashMap::new(),
+            by_distance: BTreeMap::new(),
+            evictions: EvictionStats::default(),
+            pinned: HashSet::new(),
+            corrupt: None,
+        };
+
+        if let StorageBackend::Disk(base) = &config.backend {
+            let dir = base.join(hex::encode(node_id.raw())).join(protocol);
+            fs::create_dir_all(&dir)?;
+            store.index = load_index(&dir)?;
+            store.used = store.index.values().map(|c| c.size).sum();
+            store.by_distance = store
+                .index
+                .keys()
+                .map(|id| (xor_distance(&node_id.raw(), id), *id))
+                .collect();
+            store.backend = Backend::Disk(dir);
+        }
+        store.update_radius();
+        // The limit may have been lowered since the last run
+        store.enforce_limit()?;
+
+        Ok(store)
+    }
+
+    pub fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    pub fn evictions(&self) -> EvictionStats {
+        self.evictions
+    }
+
+    /// Keeps `content_ids` from being evicted until they are unpinned.  They can still be pruned.
+    pub fn pin(&mut self, content_ids: impl IntoIterator<Item = [u8; 32]>) {
+        self.pinned.extend(content_ids);
+    }
+
+    /// Makes `content_ids` evictable again and evicts whatever no longer fits.
+    pub fn unpin(&mut self, content_ids: impl IntoIterator<Item = [u8; 32]>) -> io::Result<()> {
+        for id in content_ids {
+            self.pinned.remove(&id);
+        }
+        self.enforce_limit()
+    }
+
+    /// Evicts the farthest unpinned content until the store is back under max_bytes.
+    fn enforce_limit(&mut self) -> io::Result<()> {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(()),
+        };
+
+        while self.used > max_bytes {
+            let farthest = match self.by_distance.values().rev().find(|id| !self.pinned.contains(*id)) {
+                Some(id) => *id,
+                None => break,
+            };
+            if let Some(evicted) = self.remove(&farthest)? {
+                self.evictions.evicted += 1;
+                self.evictions.bytes_evicted += evicted.size;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Recomputes the radius from how full the store is.
+    fn update_radius(&mut self) {
+        self.radius = match self.capacity {
+            None => Distance::MAX,
+            Some(capacity) => radius_for(capacity, self.used),
+        };
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Bytes of content held, keys and index not included.
+    pub fn size(&self) -> usize {
+        self.used
+    }
+
+    /// Makes `get` hand out corrupted copies of some of the content.  None serves everything as stored.
+    pub fn serve_corrupted(&mut self, policy: Option<CorruptPolicy>) {
+        self.corrupt = policy;
+    }
+
+    pub fn index(&self) -> &HashMap<[u8; 32], StoredContent> {
+        &self.index
+    }
+
+    pub fn remove(&mut self, content_id: &[u8; 32]) -> io::Result<Option<StoredContent>> {
+        let removed = match self.index.remove(content_id) {
+            Some(removed) => removed,
+            None => return Ok(None),
+        };
+        self.by_distance.remove(&xor_distance(&self.node_id.raw(), content_id));
+
+        match &mut self.backend {
+            Backend::Memory(map) => {
+                map.remove(content_id);
+            }
+            Backend::Disk(dir) => {
+                let path = dir.join(hex::encode(content_id));
+                remove_if_exists(&path)?;
+                remove_if_exists(&path.with_extension("key"))?;
+            }
+        }
+        self.used -= removed.size;
+        self.update_radius();
+
+        Ok(Some(removed))
+    }
+
+    /// Deletes all content belonging to slots before `slot`.
+    pub fn prune_before(&mut self, slot: u64) -> io::Result<PruneReport> {
+        let expired: Vec<[u8; 32]> = self
+            .index
+            .iter()
+            .filter(|(_, c)| matches!(c.slot, Some(s) if s < slot))
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut report = PruneReport::default();
+        for id in expired {
+            if let Some(removed) = self.remove(&id)? {
+                report.removed += 1;
+                report.bytes_freed += removed.size;
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+impl ContentStore for DASContentStore {
+    fn get<K: OverlayContentKey>(&self, key: &K) -> Result<Option<Vec<u8>>, ContentStoreError> {
+        let content_id = key.content_id();
+        let content = match &self.backend {
+            Backend::Memory(map) => map.get(&content_id).cloned(),
+            Backend::Disk(dir) => match fs::read(dir.join(hex::encode(content_id))) {
+                Ok(content) => Some(content),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(ContentStoreError::Database(err.to_string())),
+            },
+        };
+
+        match (&self.corrupt, content) {
+            (Some(policy), Some(content)) if policy.corrupts(&content_id) => Ok(Some(adversary::corrupt(content))),
+            (_, content) => Ok(content),
+        }
+    }
+
+    fn put<K: OverlayContentKey, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<(), ContentStoreError> {
+        let content_id = key.content_id();
+        let raw_key: Vec<u8> = key.into();
+        let value = value.as_ref();
+
+        match &mut self.backend {
+            Backend::Memory(map) => {
+                map.insert(content_id, value.to_vec());
+            }
+            Backend::Disk(dir) => {
+                let path = dir.join(hex::encode(content_id));
+                write_atomic(&path.with_extension("key"), &raw_key)
+                    .and_then(|_| write_atomic(&path, value))
+                    .map_err(|err| ContentStoreError::Database(err.to_string()))?;
+            }
+        }
+
+        let stored = StoredContent {
+            slot: slot_of(&raw_key),
+            size: value.len(),
+        };
+        if let Some(replaced) = self.index.insert(content_id, stored) {
+            self.used -= replaced.size;
+        }
+        self.used += stored.size;
+        self.by_distance.insert(xor_distance(&self.node_id.raw(), &content_id), content_id);
+        self.update_radius();
+
+        self.enforce_limit().map_err(|err| ContentStoreError::Database(err.to_string()))
+    }
+
+    fn is_key_within_radius_and_unavailable<K: OverlayContentKey>(&self, key: &K) -> Result<bool, ContentStoreError> {
+        let content_id = key.content_id();
+        let distance = XorMetric::distance(&self.node_id.raw(), &content_id);
+        if distance > self.radius {
+            return Ok(false);
+        }
+        Ok(!self.index.contains_key(&content_id))
+    }
+
+    fn radius(&self) -> Distance {
+        self.radius
+    }
+}
+
+/// MAX scaled by the free fraction of `capacity`, never below MAX / MIN_RADIUS_FRACTION.
+pub fn radius_for(capacity: usize, used: usize) -> Distance {
+    let floor = U256::MAX / U256::from(MIN_RADIUS_FRACTION);
+    if capacity == 0 {
+        return Distance::from(floor);
+    }
+
+    let free = capacity.saturating_sub(used);
+    let radius = (U256::MAX / U256::from(capacity)) * U256::from(free);
+    Distance::from(radius.max(floor))
+}
+
+/// The slot a raw content key belongs to, if it has one.
+pub fn slot_of(raw_key: &[u8]) -> Option<u64> {
+    match DASContentKey::from_ssz_bytes(raw_key).ok()? {
+        DASContentKey::Sample(_) => None,
+        DASContentKey::Cell(key) => Some(key.slot),
+        DASContentKey::Row(key) | DASContentKey::Column(key) => Some(key.slot),
+    }
+}
+
+fn load_index(dir: &Path) -> io::Result<HashMap<[u8; 32], StoredContent>> {
+    let mut index = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let content_id = match path.file_name().and_then(|n| n.to_str()).and_then(parse_content_id) {
+            Some(id) => id,
+            None => continue,
+        };
+        // Content without its key file is left over from a crash mid-write
+        let raw_key = match fs::read(path.with_extension("key")) {
+            Ok(raw_key) => raw_key,
+            Err(_) => continue,
+        };
+
+        index.insert(
+            content_id,
+            StoredContent {
+                slot: slot_of(&raw_key),
+                size: fs::metadata(&path)?.len() as usize,
+            },
+        );
+    }
+    Ok(index)
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn parse_content_id(name: &str) -> Option<[u8; 32]> {
+    let bytes = hex::decode(name).ok()?;
+    bytes.try_into().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    use crate::content_key::{LineKey, SampleKey};
+
+    fn cell(slot: u64, column: u64) -> DASContentKey {
+        DASContentKey::Cell(SampleKey::new(slot, 0, 0, column))
+    }
+
+    fn memory_store(config: StoreConfig) -> DASContentStore {
+        DASContentStore::new(&config, NodeId::new(&[0; 32]), "DAS").unwrap()
+    }
+
+    /// A base directory of its own under the system temp dir.  Removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("das-store-{}", hex::encode(rand::thread_rng().gen::<[u8; 8]>())));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn store(&self, node_id: NodeId) -> DASContentStore {
+            let config = StoreConfig { backend: StorageBackend::Disk(self.0.clone()), ..Default::default() };
+            DASContentStore::new(&config, node_id, "DAS").unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn disk_store_survives_a_restart() {
+        let dir = TempDir::new();
+        let node_id = NodeId::random();
+
+        let mut store = dir.store(node_id);
+        store.put(cell(3, 0), [1u8; 100]).unwrap();
+        store.put(cell(4, 1), [2u8; 50]).unwrap();
+        drop(store);
+
+        let store = dir.store(node_id);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.size(), 150);
+        assert_eq!(store.get(&cell(3, 0)).unwrap(), Some(vec![1u8; 100]));
+        assert_eq!(store.index()[&cell(4, 1).content_id()], StoredContent { slot: Some(4), size: 50 });
+    }
+
+    #[test]
+    fn disk_stores_are_kept_apart_per_node() {
+        let dir = TempDir::new();
+        let mut store = dir.store(NodeId::random());
+        store.put(cell(3, 0), [1u8; 10]).unwrap();
+
+        let other = dir.store(NodeId::random());
+        assert!(other.is_empty());
+        assert_eq!(other.get(&cell(3, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn content_without_its_key_file_is_skipped_on_load() {
+        let dir = TempDir::new();
+        let node_id = NodeId::random();
+        let mut store = dir.store(node_id);
+        store.put(cell(3, 0), [1u8; 10]).unwrap();
+        store.put(cell(3, 1), [1u8; 10]).unwrap();
+        drop(store);
+
+        // What a crash between the two writes of a put leaves behind
+        let path = dir.0.join(hex::encode(node_id.raw())).join("DAS").join(hex::encode(cell(3, 1).content_id()));
+        fs::remove_file(path.with_extension("key")).unwrap();
+
+        let store = dir.store(node_id);
+        assert_eq!(store.len(), 1);
+        assert!(store.index().contains_key(&cell(3, 0).content_id()));
+    }
+
+    #[test]
+    fn removing_deletes_both_files() {
+        let dir = TempDir::new();
+        let node_id = NodeId::random();
+        let mut store = dir.store(node_id);
+        store.put(cell(3, 0), [1u8; 10]).unwrap();
+
+        let removed = store.remove(&cell(3, 0).content_id()).unwrap();
+        assert_eq!(removed, Some(StoredContent { slot: Some(3), size: 10 }));
+        assert_eq!(store.get(&cell(3, 0)).unwrap(), None);
+        let files = fs::read_dir(dir.0.join(hex::encode(node_id.raw())).join("DAS")).unwrap().count();
+        assert_eq!(files, 0);
+    }
+
+    #[test]
+    fn overwriting_keeps_the_size_right() {
+        let mut store = memory_store(StoreConfig::default());
+        store.put(cell(3, 0), [1u8; 100]).unwrap();
+        store.put(cell(3, 0), [2u8; 40]).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.size(), 40);
+    }
+
+    #[test]
+    fn pruning_removes_older_slots_only() {
+        let mut store = memory_store(StoreConfig::default());
+        store.put(cell(1, 0), [0u8; 10]).unwrap();
+        store.put(cell(2, 0), [0u8; 20]).unwrap();
+        store.put(cell(3, 0), [0u8; 30]).unwrap();
+        store.put(DASContentKey::Row(LineKey::new(1, 0, 0)), [0u8; 40]).unwrap();
+
+        let report = store.prune_before(3).unwrap();
+        assert_eq!(report, PruneReport { removed: 3, bytes_freed: 70 });
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.size(), 30);
+        assert!(store.index().contains_key(&cell(3, 0).content_id()));
+    }
+
+    #[test]
+    fn opaque_samples_are_never_pruned() {
+        let mut store = memory_store(StoreConfig::default());
+        store.put(DASContentKey::Sample([7; 32]), [0u8; 10]).unwrap();
+
+        assert_eq!(store.prune_before(u64::MAX).unwrap(), PruneReport::default());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn slots_come_from_the_raw_key() {
+        let raw = |key: DASContentKey| -> Vec<u8> { key.into() };
+        assert_eq!(slot_of(&raw(cell(9, 4))), Some(9));
+        assert_eq!(slot_of(&raw(DASContentKey::Column(LineKey::new(5, 1, 2)))), Some(5));
+        assert_eq!(slot_of(&raw(DASContentKey::Sample([7; 32]))), None);
+        assert_eq!(slot_of(&[0xff, 1, 2]), None);
+    }
+
+    #[test]
+    fn radius_shrinks_linearly_with_free_space() {
+        let max = U256::MAX;
+        let half = radius_for(1000, 500);
+        assert!(half <= Distance::from(max / 2));
+        assert!(half > Distance::from(max / 2 - max / 1000));
+        assert!(radius_for(1000, 100) > radius_for(1000, 200));
+    }
+
+    #[test]
+    fn radius_never_goes_below_the_floor() {
+        let floor = Distance::from(U256::MAX / U256::from(MIN_RADIUS_FRACTION));
+        assert_eq!(radius_for(1000, 1000), floor);
+        assert_eq!(radius_for(1000, 5000), floor);
+        assert_eq!(radius_for(0, 0), floor);
+        assert!(radius_for(1000, 999) >= floor);
+    }
+
+    #[test]
+    fn store_radius_follows_its_fill_level() {
+        let mut unlimited = memory_store(StoreConfig::default());
+        unlimited.put(cell(1, 0), [0u8; 600]).unwrap();
+        assert_eq!(unlimited.radius(), Distance::MAX);
+
+        let mut store = memory_store(StoreConfig { capacity: Some(1000), ..Default::default() });
+        store.put(cell(1, 0), [0u8; 600]).unwrap();
+        assert_eq!(store.radius(), radius_for(1000, 600));
+        store.remove(&cell(1, 0).content_id()).unwrap();
+        assert_eq!(store.radius(), radius_for(1000, 0));
+    }
+
+    #[test]
+    fn a_filling_store_declines_far_content() {
+        let mut store = memory_store(StoreConfig { capacity: Some(1000), ..Default::default() });
+        // The local node id is all zeros, so the top bits of a content id are its distance
+        let far = (1..).map(|i| cell(2, i)).find(|key| key.content_id()[0] >= 0x80).unwrap();
+        let near = (1..).map(|i| cell(2, i)).find(|key| key.content_id()[0] < 0x40).unwrap();
+        assert!(store.is_key_within_radius_and_unavailable(&far).unwrap());
+
+        store.put(cell(1, 0), [0u8; 600]).unwrap();
+        assert!(!store.is_key_within_radius_and_unavailable(&far).unwrap());
+        assert!(store.is_key_within_radius_and_unavailable(&near).unwrap());
+        // Held already
+        assert!(!store.is_key_within_radius_and_unavailable(&cell(1, 0)).unwrap());
+    }
+
+    /// `n` cell keys ordered from nearest to farthest from the all zero node id.
+    fn by_distance(n: u64) -> Vec<DASContentKey> {
+        let mut keys: Vec<DASContentKey> = (0..n).map(|i| cell(1, i)).collect();
+        keys.sort_by_key(|key| key.content_id());
+        keys
+    }
+
+    #[test]
+    fn farthest_content_is_evicted_first() {
+        let mut store = memory_store(StoreConfig { max_bytes: Some(250), ..Default::default() });
+        let keys = by_distance(3);
+        for key in keys.iter() {
+            store.put(key.clone(), [0u8; 100]).unwrap();
+        }
+
+        assert_eq!(store.size(), 200);
+        assert_eq!(store.evictions(), EvictionStats { evicted: 1, bytes_evicted: 100 });
+        assert_eq!(store.get(&keys[2]).unwrap(), None);
+        assert!(store.get(&keys[0]).unwrap().is_some());
+        assert!(store.get(&keys[1]).unwrap().is_some());
+    }
+
+    #[test]
+    fn a_put_can_evict_its_own_content() {
+        let mut store = memory_store(StoreConfig { max_bytes: Some(150), ..Default::default() });
+        let keys = by_distance(2);
+        store.put(keys[0].clone(), [0u8; 100]).unwrap();
+        store.put(keys[1].clone(), [0u8; 100]).unwrap();
+
+        assert_eq!(store.get(&keys[1]).unwrap(), None);
+        assert_eq!(store.evictions().evicted, 1);
+    }
+
+    #[test]
+    fn pinned_content_is_skipped_until_unpinned() {
+        let mut store = memory_store(StoreConfig { max_bytes: Some(150), ..Default::default() });
+        let keys = by_distance(2);
+        store.pin([keys[1].content_id()]);
+        store.put(keys[1].clone(), [0u8; 100]).unwrap();
+        store.put(keys[0].clone(), [0u8; 100]).unwrap();
+
+        // The nearer, unpinned content went instead
+        assert!(store.get(&keys[1]).unwrap().is_some());
+        assert_eq!(store.get(&keys[0]).unwrap(), None);
+
+        // Everything pinned: over the limit until something is unpinned
+        store.pin([keys[0].content_id()]);
+        store.put(keys[0].clone(), [0u8; 100]).unwrap();
+        assert_eq!(store.size(), 200);
+
+        store.unpin([keys[0].content_id(), keys[1].content_id()]).unwrap();
+        assert_eq!(store.size(), 100);
+        assert!(store.get(&keys[0]).unwrap().is_some());
+    }
+
+    #[test]
+    fn a_lowered_limit_applies_on_reopening() {
+        let dir = TempDir::new();
+        let node_id = NodeId::new(&[0; 32]);
+        let mut store = dir.store(node_id);
+        for key in by_distance(3) {
+            store.put(key, [0u8; 100]).unwrap();
+        }
+        drop(store);
+
+        let config = StoreConfig { backend: StorageBackend::Disk(dir.0.clone()), max_bytes: Some(100), ..Default::default() };
+        let store = DASContentStore::new(&config, node_id, "DAS").unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.index().contains_key(&by_distance(3)[0].content_id()));
+    }
+}
@@ -0,0 +1,324 @@
+use discv5_overlay::portalnet::discovery::Discovery;
+use anyhow::bail;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    sync::Arc,
+};
+
+/*
+    Who knows whom when the network starts.
+
+    A topology generates, for every node, the indices of the nodes it adds to its discv5 routing
+    table with add_enr.  The overlays start from the discv5 table, so this is also the overlays'
+    starting point: as bootnodes over UDP, through SimNetwork's pings on the virtual transport.
+
+        - Random:        every node picks `peers` others uniformly at random (directed)
+        - RandomRegular: undirected, every node has exactly `degree` neighbours
+        - Ring:          undirected, every node knows the `neighbors` closest indices, half each side.
+                         `neighbors` must be even and below n
+        - SmallWorld:    Watts–Strogatz.  A ring whose edges are rewired to a random node with probability `rewire`,
+                         in [0, 1]
+        - Clustered:     `clusters` contiguous groups.  `intra_peers` picked inside the group, `inter_peers` outside
+        - Bootnode:      everyone knows the first `bootnodes` nodes and nobody else
+
+    Undirected edges are added on both ends.  Degrees are capped at n - 1.
+*/
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Topology {
+    Random { peers: usize },
+    RandomRegular { degree: usize },
+    Ring { neighbors: usize },
+    SmallWorld { neighbors: usize, rewire: f64 },
+    Clustered { clusters: usize, intra_peers: usize, inter_peers: usize },
+    Bootnode { bootnodes: usize },
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Topology::Random { peers: 3 }
+    }
+}
+
+/// Degree spread of a generated topology.
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct TopologyStats {
+    pub edges: usize,
+    pub min_degree: usize,
+    pub mean_degree: f64,
+    pub max_degree: usize,
+    /// add_enr calls that failed, e.g. because a bucket was full
+    pub failed_inserts: usize,
+}
+
+impl Topology {
+    /// Rejects parameters a network of `n` nodes can't be built with.
+    pub fn check(&self, n: usize) -> anyhow::Result<()> {
+        match *self {
+            Topology::Ring { neighbors } | Topology::SmallWorld { neighbors, .. } if neighbors == 0 || neighbors % 2 == 1 => {
+                bail!("{:?} needs an even, non-zero number of neighbors, half on each side", self);
+            }
+            Topology::Ring { neighbors } | Topology::SmallWorld { neighbors, .. } if neighbors >= n => {
+                bail!("{:?} needs fewer neighbors than the {} nodes", self, n);
+            }
+            Topology::SmallWorld { rewire, .. } if !(0.0..=1.0).contains(&rewire) => {
+                bail!("Small-world rewire probability must be between 0 and 1, not {}", rewire);
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Out-neighbours of every node, by index.
+    pub fn generate<R: Rng>(&self, n: usize, rng: &mut R) -> Vec<BTreeSet<usize>> {
+        if n < 2 {
+            return vec![BTreeSet::new(); n];
+        }
+
+        match *self {
+            Topology::Random { peers } => (0..n).map(|i| random_peers(rng, i, 0..n, peers)).collect(),
+            Topology::RandomRegular { degree } => random_regular(rng, n, degree),
+            Topology::Ring { neighbors } => ring(n, neighbors),
+            Topology::SmallWorld { neighbors, rewire } => small_world(rng, n, neighbors, rewire),
+            Topology::Clustered { clusters, intra_peers, inter_peers } => {
+                let clusters = clusters.clamp(1, n);
+                let cluster_of = |i: usize| i * clusters / n;
+                (0..n)
+                    .map(|i| {
+                        let members: Vec<usize> = (0..n).filter(|&j| cluster_of(j) == cluster_of(i)).collect();
+                        let outsiders: Vec<usize> = (0..n).filter(|&j| cluster_of(j) != cluster_of(i)).collect();
+                        let mut peers = random_peers(rng, i, members, intra_peers);
+                        peers.extend(random_peers(rng, i, outsiders, inter_peers));
+                        peers
+                    })
+                    .collect()
+            }
+            Topology::Bootnode { bootnodes } => {
+                let bootnodes = bootnodes.clamp(1, n);
+                (0..n).map(|i| (0..bootnodes).filter(|&b| b != i).collect()).collect()
+            }
+        }
+    }
+}
+
+/// Adds every generated edge to the discv5 tables.
+pub fn apply(adjacency: &[BTreeSet<usize>], structs: &[Arc<Discovery>]) -> TopologyStats {
+    let mut stats = stats(adjacency);
+    for (i, peers) in adjacency.iter().enumerate() {
+        for &peer in peers {
+            if structs[i].discv5.add_enr(structs[peer].discv5.local_enr().clone()).is_err() {
+                stats.failed_inserts += 1;
+            }
+        }
+    }
+    stats
+}
+
+pub fn stats(adjacency: &[BTreeSet<usize>]) -> TopologyStats {
+    let degrees: Vec<usize> = adjacency.iter().map(|peers| peers.len()).collect();
+    let edges = degrees.iter().sum();
+    TopologyStats {
+        edges,
+        min_degree: degrees.iter().copied().min().unwrap_or(0),
+        mean_degree: if degrees.is_empty() { 0.0 } else { edges as f64 / degrees.len() as f64 },
+        max_degree: degrees.iter().copied().max().unwrap_or(0),
+        failed_inserts: 0,
+    }
+}
+
+/// Up to `k` distinct candidates other than `local`.
+fn random_peers<R: Rng>(rng: &mut R, local: usize, candidates: impl IntoIterator<Item = usize>, k: usize) -> BTreeSet<usize> {
+    let candidates: Vec<usize> = candidates.into_iter().filter(|&c| c != local).collect();
+    candidates.choose_multiple(rng, k).copied().collect()
+}
+
+fn connect(adjacency: &mut [BTreeSet<usize>], a: usize, b: usize) {
+    adjacency[a].insert(b);
+    adjacency[b].insert(a);
+}
+
+/// Exactly `neighbors` per node if Topology::check passed.
+fn ring(n: usize, neighbors: usize) -> Vec<BTreeSet<usize>> {
+    let mut adjacency = vec![BTreeSet::new(); n];
+    let half = (neighbors / 2).min((n - 1) / 2);
+    for i in 0..n {
+        for offset in 1..=half {
+            let j = (i + offset) % n;
+            if j != i {
+                connect(&mut adjacency, i, j);
+            }
+        }
+    }
+    adjacency
+}
+
+fn small_world<R: Rng>(rng: &mut R, n: usize, neighbors: usize, rewire: f64) -> Vec<BTreeSet<usize>> {
+    let mut adjacency = ring(n, neighbors);
+    let half = (neighbors / 2).min((n - 1) / 2);
+
+    // Each lattice edge (i, i + offset) is visited once and may move its far end
+    for offset in 1..=half {
+        for i in 0..n {
+            let j = (i + offset) % n;
+            if !adjacency[i].contains(&j) || !rng.gen_bool(rewire.clamp(0.0, 1.0)) {
+                continue;
+            }
+            let free: Vec<usize> = (0..n).filter(|&k| k != i && !adjacency[i].contains(&k)).collect();
+            if let Some(&k) = free.choose(rng) {
+                adjacency[i].remove(&j);
+                adjacency[j].remove(&i);
+                connect(&mut adjacency, i, k);
+            }
+        }
+    }
+    adjacency
+}
+
+/// Pairs up degree stubs at random (Steger–Wormald), restarting when it gets stuck.
+/// Falls back to the best attempt, so a few nodes may end up one short.
+fn random_regular<R: Rng>(rng: &mut R, n: usize, degree: usize) -> Vec<BTreeSet<usize>> {
+    let degree = degree.min(n - 1);
+    let mut best: Option<Vec<BTreeSet<usize>>> = None;
+
+    for _ in 0..32 {
+        let mut adjacency = vec![BTreeSet::new(); n];
+        let mut stubs: Vec<usize> = (0..n).flat_map(|i| std::iter::repeat(i).take(degree)).collect();
+
+        while stubs.len() >= 2 {
+            let pair = (0..64)
+                .map(|_| (rng.gen_range(0..stubs.len()), rng.gen_range(0..stubs.len())))
+                .find(|&(a, b)| stubs[a] != stubs[b] && !adjacency[stubs[a]].contains(&stubs[b]));
+            let (a, b) = match pair {
+                Some(pair) => pair,
+                None => break,
+            };
+            connect(&mut adjacency, stubs[a], stubs[b]);
+            // Remove the higher index first so the lower one stays valid
+            let (first, second) = if a > b { (a, b) } else { (b, a) };
+            stubs.swap_remove(first);
+            stubs.swap_remove(second);
+        }
+
+        let short = stubs.len();
+        if short <= 1 {
+            return adjacency;
+        }
+        let best_short = best.as_ref().map_or(usize::MAX, |b| b.iter().map(|p| degree - p.len()).sum());
+        if short < best_short {
+            best = Some(adjacency);
+        }
+    }
+
+    best.unwrap_or_else(|| vec![BTreeSet::new(); n])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn generate(topology: Topology, n: usize) -> Vec<BTreeSet<usize>> {
+        topology.generate(n, &mut StdRng::seed_from_u64(3))
+    }
+
+    fn is_symmetric(adjacency: &[BTreeSet<usize>]) -> bool {
+        adjacency.iter().enumerate().all(|(i, peers)| peers.iter().all(|&j| adjacency[j].contains(&i)))
+    }
+
+    fn all() -> Vec<Topology> {
+        vec![
+            Topology::Random { peers: 3 },
+            Topology::RandomRegular { degree: 4 },
+            Topology::Ring { neighbors: 4 },
+            Topology::SmallWorld { neighbors: 4, rewire: 0.3 },
+            Topology::Clustered { clusters: 4, intra_peers: 2, inter_peers: 1 },
+            Topology::Bootnode { bootnodes: 2 },
+        ]
+    }
+
+    #[test]
+    fn check_rejects_rings_that_cant_be_built() {
+        assert!(Topology::Ring { neighbors: 4 }.check(10).is_ok());
+        assert!(Topology::Ring { neighbors: 0 }.check(10).is_err());
+        assert!(Topology::Ring { neighbors: 3 }.check(10).is_err());
+        assert!(Topology::Ring { neighbors: 10 }.check(10).is_err());
+        assert!(Topology::SmallWorld { neighbors: 5, rewire: 0.1 }.check(10).is_err());
+        assert!(Topology::SmallWorld { neighbors: 4, rewire: 1.5 }.check(10).is_err());
+        assert!(Topology::SmallWorld { neighbors: 4, rewire: 1.0 }.check(10).is_ok());
+        // The rest cap their degrees instead
+        assert!(Topology::Random { peers: 50 }.check(10).is_ok());
+        assert!(Topology::Bootnode { bootnodes: 50 }.check(10).is_ok());
+    }
+
+    #[test]
+    fn nobody_is_their_own_peer() {
+        for topology in all() {
+            let adjacency = generate(topology.clone(), 20);
+            assert_eq!(adjacency.len(), 20);
+            assert!(adjacency.iter().enumerate().all(|(i, peers)| !peers.contains(&i)), "{:?}", topology);
+        }
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_topology() {
+        for topology in all() {
+            assert_eq!(generate(topology.clone(), 20), generate(topology, 20));
+        }
+    }
+
+    #[test]
+    fn degrees() {
+        assert!(generate(Topology::Random { peers: 3 }, 20).iter().all(|peers| peers.len() == 3));
+        assert!(generate(Topology::Random { peers: 50 }, 20).iter().all(|peers| peers.len() == 19));
+
+        // Pairing can get stuck, leaving a node or two one short at worst
+        let regular = generate(Topology::RandomRegular { degree: 4 }, 20);
+        assert!(is_symmetric(&regular));
+        assert!(regular.iter().all(|peers| (3..=4).contains(&peers.len())));
+        assert!(stats(&regular).edges >= 20 * 4 - 2);
+
+        let ring = generate(Topology::Ring { neighbors: 4 }, 20);
+        assert!(is_symmetric(&ring));
+        assert!(ring.iter().all(|peers| peers.len() == 4));
+        assert_eq!(ring[0], BTreeSet::from([1, 2, 18, 19]));
+    }
+
+    #[test]
+    fn rewiring_keeps_the_edge_count() {
+        let lattice = generate(Topology::SmallWorld { neighbors: 4, rewire: 0.0 }, 20);
+        assert_eq!(lattice, generate(Topology::Ring { neighbors: 4 }, 20));
+
+        let rewired = generate(Topology::SmallWorld { neighbors: 4, rewire: 0.5 }, 20);
+        assert!(is_symmetric(&rewired));
+        assert_ne!(rewired, lattice);
+        assert_eq!(stats(&rewired).edges, stats(&lattice).edges);
+    }
+
+    #[test]
+    fn clusters_pick_inside_then_outside() {
+        let adjacency = generate(Topology::Clustered { clusters: 4, intra_peers: 2, inter_peers: 1 }, 20);
+        for (i, peers) in adjacency.iter().enumerate() {
+            let inside = peers.iter().filter(|&&j| j / 5 == i / 5).count();
+            assert_eq!((inside, peers.len() - inside), (2, 1));
+        }
+    }
+
+    #[test]
+    fn everyone_knows_the_bootnodes_only() {
+        let adjacency = generate(Topology::Bootnode { bootnodes: 2 }, 5);
+        assert_eq!(adjacency[0], BTreeSet::from([1]));
+        assert_eq!(adjacency[1], BTreeSet::from([0]));
+        assert!(adjacency[2..].iter().all(|peers| *peers == BTreeSet::from([0, 1])));
+    }
+
+    #[test]
+    fn stats_spread() {
+        let adjacency = vec![BTreeSet::from([1]), BTreeSet::from([0, 2]), BTreeSet::new()];
+        let stats = stats(&adjacency);
+        assert_eq!((stats.edges, stats.min_degree, stats.max_degree), (3, 0, 2));
+        assert_eq!(stats.mean_degree, 1.0);
+    }
+}
@@ -0,0 +1,673 @@
+use async_trait::async_trait;
+use discv5::{enr::NodeId, rpc::RequestId, Enr};
+use discv5_overlay::{
+    portalnet::{
+        overlay::OverlayProtocol,
+        overlay_service::{
+            ActiveOutgoingRequest,
+            OverlayRequest,
+            OverlayRequestError,
+            OverlayResponse,
+            OverlayService,
+            RequestDirection,
+        },
+        storage::ContentStore,
+        types::{
+            content_key::OverlayContentKey,
+            distance::XorMetric,
+            messages::{Content, ProtocolId, Request, Response},
+        },
+    },
+    types::validation::Validator,
+};
+use parking_lot::{Mutex, RwLock};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    adversary::Adversary,
+    links::{LinkModel, LinkProfile, LinkShaper},
+    overlay,
+};
+
+/*
+    How overlay messages get from one node to another.
+
+    Udp:      the real thing.  Every node binds a UDP socket on 127.0.0.1 and overlay messages are
+              discv5 TalkReqs.  Big content moves over uTP.
+    Virtual:  everything stays in the process.  Discv5 servers are created but never started, so no
+              sockets are bound and thousands of nodes fit on one machine.
+
+    The virtual network:
+        - Each node registers an inbox plus its overlays when create_node runs
+        - The runtime hands every outgoing overlay request to VirtualNetwork::request instead of the
+          overlay service, and feeds what arrives in its inbox to the service as an incoming request
+        - Every message, each way, gets a latency and may be lost according to the link between the two
+          nodes (see links.rs).  A lost message or a node that isn't running looks the same as over
+          UDP: the request times out.  So does any message across a partition (see partition.rs)
+        - Content doesn't fit in a TalkResp, so a FindContent hit is parked under a connection id, the
+          same way the overlay would hand it to uTP.  The requester collects it with take_transfer.
+          Transfers nobody collects (the response got lost) expire after twice the timeout
+        - Offers: once the peer accepts, the offerer pushes the accepted content straight into the
+          peer's store, after the peer's validator has checked it.  How that went is posted to the
+          offerer's OfferTransfers before the Accept gets back to it, the way a uTP stream event would be
+
+    Notes:
+        - Only requests made through OverlayProtocol (pings, FindContent, Offer) take the virtual path.
+          Overlays get no bootnodes on this transport, the service would ping them through the
+          (unstarted) discv5 server and wait out the request timeout on each.  SimNetwork pings
+          every discv5 peer over the virtual network once the nodes are up instead
+        - Latencies and losses are drawn from an rng seeded by the simulation, so runs are repeatable
+        - On Udp the link model, if there is one, is applied by each node's router (see links.rs)
+*/
+
+#[derive(Clone, Debug)]
+pub struct VirtualConfig {
+    pub links: LinkModel,
+    /// How long a requester waits before giving up on a lost message
+    pub timeout: Duration,
+}
+
+impl Default for VirtualConfig {
+    fn default() -> Self {
+        Self {
+            links: LinkModel::default(),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TransportConfig {
+    /// None leaves messages to loopback
+    Udp(Option<LinkModel>),
+    Virtual(VirtualConfig),
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig::Udp(None)
+    }
+}
+
+#[derive(Clone)]
+pub enum Transport {
+    /// The link model every node's router applies to the TalkReqs it receives, if any
+    Udp(Option<LinkShaper>),
+    Virtual(VirtualNetwork),
+}
+
+impl Transport {
+    pub fn is_virtual(&self) -> bool {
+        matches!(self, Transport::Virtual(_))
+    }
+
+    /// Collects content a FindContent answered with a connection id.
+    pub async fn find_content_stream<TContentKey, TValidator, TStore>(
+        &self,
+        overlay: &OverlayProtocol<TContentKey, XorMetric, TValidator, TStore>,
+        enr: Enr,
+        conn_id: u16,
+    ) -> anyhow::Result<Vec<u8>>
+    where
+        TContentKey: 'static + OverlayContentKey + Send + Sync,
+        TValidator: 'static + Validator<TContentKey> + Send + Sync,
+        TStore: 'static + ContentStore + Send + Sync,
+    {
+        match self {
+            Transport::Udp(_) => overlay
+                .init_find_content_stream(enr, conn_id)
+                .await
+                .map_err(|err| anyhow::anyhow!("{:?}", err)),
+            Transport::Virtual(network) => network
+                .take_transfer(&overlay.local_enr().node_id(), conn_id)
+                .ok_or_else(|| anyhow::anyhow!("No transfer {} from {}", conn_id, enr.node_id())),
+        }
+    }
+}
+
+/// How the content of one accepted Offer got to the peer.
+#[derive(Clone, Debug, Default)]
+pub struct TransferOutcome {
+    /// Set if the transfer failed as a whole, e.g. its uTP stream was reset
+    pub error: Option<String>,
+    /// Accepted keys whose content didn't make it, with why.  Only the virtual network can tell them apart
+    pub failed: Vec<(Vec<u8>, String)>,
+}
+
+impl TransferOutcome {
+    pub fn error(err: impl Into<String>) -> Self {
+        Self {
+            error: Some(err.into()),
+            failed: Vec::new(),
+        }
+    }
+
+    /// Why the content under `raw_key` didn't arrive, None if it did.
+    pub fn failure(&self, raw_key: &[u8]) -> Option<String> {
+        self.error
+            .clone()
+            .or_else(|| self.failed.iter().find(|(key, _)| key == raw_key).map(|(_, err)| err.clone()))
+    }
+}
+
+pub type TransferOutcomes = Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<TransferOutcome>>>;
+
+/// Outcomes of a node's Offer transfers, per overlay, in the order they finish.  Neither a uTP stream
+/// event nor the virtual network says which Offer a transfer belonged to, so whoever waits for one
+/// holds the overlay's receiver from sending the Offer until its outcome arrives (see publish.rs).
+#[derive(Clone, Default)]
+pub struct OfferTransfers {
+    channels: Arc<Mutex<Vec<(ProtocolId, mpsc::UnboundedSender<TransferOutcome>, TransferOutcomes)>>>,
+}
+
+impl OfferTransfers {
+    pub fn finished(&self, protocol: &ProtocolId, outcome: TransferOutcome) {
+        let _ = self.channel(protocol).0.send(outcome);
+    }
+
+    pub fn outcomes(&self, protocol: &ProtocolId) -> TransferOutcomes {
+        self.channel(protocol).1
+    }
+
+    fn channel(&self, protocol: &ProtocolId) -> (mpsc::UnboundedSender<TransferOutcome>, TransferOutcomes) {
+        let mut channels = self.channels.lock();
+        if let Some((_, tx, rx)) = channels.iter().find(|(p, _, _)| p == protocol) {
+            return (tx.clone(), rx.clone());
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = Arc::new(tokio::sync::Mutex::new(rx));
+        channels.push((protocol.clone(), tx.clone(), rx.clone()));
+        (tx, rx)
+    }
+}
+
+/// A request delivered to a node's inbox.
+pub struct VirtualMessage {
+    pub protocol: ProtocolId,
+    pub source: NodeId,
+    pub id: u128,
+    pub request: Request,
+    pub responder: oneshot::Sender<Result<Response, OverlayRequestError>>,
+}
+
+/// The store side of an overlay, for the parts of a transfer that skip the overlay service.
+#[async_trait]
+pub trait VirtualPeer: Send + Sync {
+    fn local_content(&self, raw_key: &[u8]) -> Option<Vec<u8>>;
+    /// Whether the node's adversary policy answers "not found" no matter what it holds.
+    fn withholds(&self, requester: &NodeId, raw_key: &[u8]) -> bool;
+    /// Validates offered content and stores it.
+    async fn accept_content(&self, raw_key: Vec<u8>, content: Vec<u8>) -> anyhow::Result<()>;
+    /// Puts the store's current radius into a pong, see overlay::advertise_radius_in_response.
+    fn advertise_radius(&self, response: &mut Response);
+}
+
+/// An overlay plus a validator of its own (the overlay keeps its validator private) and the node's adversary policy.
+pub struct VirtualOverlay<TContentKey, TValidator, TStore> {
+    overlay: Arc<OverlayProtocol<TContentKey, XorMetric, TValidator, TStore>>,
+    validator: Arc<TValidator>,
+    adversary: Adversary,
+}
+
+impl<TContentKey, TValidator, TStore> VirtualOverlay<TContentKey, TValidator, TStore> {
+    pub fn new(overlay: Arc<OverlayProtocol<TContentKey, XorMetric, TValidator, TStore>>, validator: Arc<TValidator>, adversary: Adversary) -> Self {
+        Self { overlay, validator, adversary }
+    }
+}
+
+#[async_trait]
+impl<TContentKey, TValidator, TStore> VirtualPeer for VirtualOverlay<TContentKey, TValidator, TStore>
+where
+    TContentKey: 'static + OverlayContentKey + TryFrom<Vec<u8>> + Send + Sync,
+    TValidator: 'static + Validator<TContentKey> + Send + Sync,
+    TStore: 'static + ContentStore + Send + Sync,
+{
+    fn local_content(&self, raw_key: &[u8]) -> Option<Vec<u8>> {
+        let key = TContentKey::try_from(raw_key.to_vec()).ok()?;
+        self.overlay.store.read().get(&key).ok().flatten()
+    }
+
+    fn withholds(&self, requester: &NodeId, raw_key: &[u8]) -> bool {
+        self.adversary.withholds(requester, raw_key)
+    }
+
+    fn advertise_radius(&self, response: &mut Response) {
+        overlay::advertise_radius_in_response(response, &*self.overlay.store.read());
+    }
+
+    async fn accept_content(&self, raw_key: Vec<u8>, content: Vec<u8>) -> anyhow::Result<()> {
+        let key = TContentKey::try_from(raw_key).map_err(|_| anyhow::anyhow!("Undecodable content key"))?;
+        self.validator.validate_content(&key, &content).await?;
+        self.overlay
+            .store
+            .write()
+            .put(key, content)
+            .map_err(|err| anyhow::anyhow!("{:?}", err))
+    }
+}
+
+struct Endpoint {
+    inbox: mpsc::UnboundedSender<VirtualMessage>,
+    overlays: Vec<(ProtocolId, Arc<dyn VirtualPeer>)>,
+}
+
+impl Endpoint {
+    /// The runtime owns the other end of the inbox.  Closed means the node is stopped
+    fn is_running(&self) -> bool {
+        !self.inbox.is_closed()
+    }
+
+    fn overlay(&self, protocol: &ProtocolId) -> Option<Arc<dyn VirtualPeer>> {
+        self.overlays
+            .iter()
+            .find(|(p, _)| p == protocol)
+            .map(|(_, peer)| peer.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct VirtualNetwork {
+    config: VirtualConfig,
+    links: LinkShaper,
+    /// Side of the partition each node is on, while there is one
+    partition: Arc<RwLock<Option<HashMap<NodeId, usize>>>>,
+    endpoints: Arc<RwLock<HashMap<NodeId, Endpoint>>>,
+    /// Parked content and when it expires
+    transfers: Arc<Mutex<HashMap<(NodeId, u16), (Instant, Vec<u8>)>>>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+/// What the runtime needs to take part in the virtual network.
+pub struct VirtualLink {
+    pub network: VirtualNetwork,
+    pub node_id: NodeId,
+    pub inbox: mpsc::UnboundedReceiver<VirtualMessage>,
+}
+
+impl VirtualNetwork {
+    pub fn new(config: VirtualConfig, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        Self {
+            links: LinkShaper::new(config.links.clone(), rng.gen()),
+            partition: Arc::new(RwLock::new(None)),
+            config,
+            endpoints: Arc::new(RwLock::new(HashMap::new())),
+            transfers: Arc::new(Mutex::new(HashMap::new())),
+            rng: Arc::new(Mutex::new(rng)),
+        }
+    }
+
+    pub fn config(&self) -> &VirtualConfig {
+        &self.config
+    }
+
+    /// Adds a node, replacing whatever was registered under its id before (e.g. before a restart).
+    pub fn register(&self, node_id: NodeId, overlays: Vec<(ProtocolId, Arc<dyn VirtualPeer>)>) -> VirtualLink {
+        let (inbox_tx, inbox) = mpsc::unbounded_channel();
+        self.links.place(node_id);
+        self.endpoints.write().insert(node_id, Endpoint { inbox: inbox_tx, overlays });
+        VirtualLink {
+            network: self.clone(),
+            node_id,
+            inbox,
+        }
+    }
+
+    pub fn is_running(&self, node_id: &NodeId) -> bool {
+        self.endpoints.read().get(node_id).map_or(false, Endpoint::is_running)
+    }
+
+    pub fn region(&self, node_id: &NodeId) -> Option<String> {
+        self.links.region(node_id)
+    }
+
+    /// Overrides the link between two nodes, both directions.
+    pub fn set_link(&self, a: NodeId, b: NodeId, profile: LinkProfile) {
+        self.links.set_link(a, b, profile);
+    }
+
+    pub fn clear_link(&self, a: &NodeId, b: &NodeId) {
+        self.links.clear_link(a, b);
+    }
+
+    /// Splits the network.  Messages between nodes on different sides are lost until heal.
+    /// Nodes not in any group form a side of their own.
+    pub fn partition(&self, groups: &[Vec<NodeId>]) {
+        let sides = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(side, group)| group.iter().map(move |node_id| (*node_id, side)))
+            .collect();
+        *self.partition.write() = Some(sides);
+    }
+
+    pub fn heal(&self) {
+        *self.partition.write() = None;
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        self.partition.read().is_some()
+    }
+
+    fn separated(&self, from: &NodeId, to: &NodeId) -> bool {
+        match &*self.partition.read() {
+            Some(sides) => sides.get(from) != sides.get(to),
+            None => false,
+        }
+    }
+
+    /// Rolls the dice for one message from `from` to `to`.  None means it's lost.
+    fn delay(&self, from: &NodeId, to: &NodeId) -> Option<Duration> {
+        if self.separated(from, to) {
+            return None;
+        }
+        self.links.sample(from, to)
+    }
+
+    /// Waits out one message.  A lost message costs the requester its whole timeout.
+    async fn deliver(&self, from: &NodeId, to: &NodeId) -> Result<(), OverlayRequestError> {
+        match self.delay(from, to) {
+            Some(delay) => {
+                tokio::time::sleep(delay).await;
+                Ok(())
+            }
+            None => {
+                tokio::time::sleep(self.config.timeout).await;
+                Err(OverlayRequestError::Timeout)
+            }
+        }
+    }
+
+    /// Sends `request` to `destination` and waits for its response.
+    pub async fn request(&self, protocol: ProtocolId, source: NodeId, destination: &Enr, id: u128, request: Request) -> Result<Response, OverlayRequestError> {
+        let destination = destination.node_id();
+        self.deliver(&source, &destination).await?;
+
+        let peer = match self.endpoints.read().get(&destination) {
+            Some(endpoint) if endpoint.is_running() => endpoint.overlay(&protocol),
+            _ => None,
+        };
+        let peer = match peer {
+            Some(peer) => peer,
+            None => {
+                tokio::time::sleep(self.config.timeout).await;
+                return Err(OverlayRequestError::Timeout);
+            }
+        };
+
+        // Content hits are answered here, the service would hand them to uTP
+        let mut response = match &request {
+            Request::FindContent(find) if peer.withholds(&source, &find.content_key) => Ok(Response::Content(Content::Enrs(Vec::new()))),
+            Request::FindContent(find) => match peer.local_content(&find.content_key) {
+                Some(content) => Ok(Response::Content(Content::ConnectionId(self.park(source, content)))),
+                None => self.forward(protocol, source, destination, id, request).await,
+            },
+            _ => self.forward(protocol, source, destination, id, request).await,
+        };
+        if let Ok(response) = &mut response {
+            peer.advertise_radius(response);
+        }
+
+        self.deliver(&destination, &source).await?;
+        response
+    }
+
+    /// Hands a request to the destination's runtime and waits for its service to answer.
+    async fn forward(&self, protocol: ProtocolId, source: NodeId, destination: NodeId, id: u128, request: Request) -> Result<Response, OverlayRequestError> {
+        let (responder, response) = oneshot::channel();
+        let sent = match self.endpoints.read().get(&destination) {
+            Some(endpoint) => endpoint
+                .inbox
+                .send(VirtualMessage {
+                    protocol,
+                    source,
+                    id,
+                    request,
+                    responder,
+                })
+                .is_ok(),
+            None => false,
+        };
+        if !sent {
+            tokio::time::sleep(self.config.timeout).await;
+            return Err(OverlayRequestError::Timeout);
+        }
+
+        match tokio::time::timeout(self.config.timeout, response).await {
+            Ok(Ok(response)) => response,
+            _ => Err(OverlayRequestError::Timeout),
+        }
+    }
+
+    /// Pushes the content of every accepted key from `source` to `destination`.
+    pub async fn push_accepted(&self, protocol: &ProtocolId, source: NodeId, destination: NodeId, accepted: Vec<Vec<u8>>) -> TransferOutcome {
+        let (local, remote) = {
+            let endpoints = self.endpoints.read();
+            (
+                endpoints.get(&source).and_then(|e| e.overlay(protocol)),
+                endpoints.get(&destination).and_then(|e| e.overlay(protocol)),
+            )
+        };
+        let (local, remote) = match (local, remote) {
+            (Some(local), Some(remote)) => (local, remote),
+            _ => return TransferOutcome::error("Not on the virtual network"),
+        };
+
+        let mut outcome = TransferOutcome::default();
+        for raw_key in accepted {
+            let content = match local.local_content(&raw_key) {
+                Some(content) => content,
+                None => {
+                    outcome.failed.push((raw_key, "Missing from the local store".to_string()));
+                    continue;
+                }
+            };
+            if self.deliver(&source, &destination).await.is_err() || !self.is_running(&destination) {
+                outcome.failed.push((raw_key, "Lost in transit".to_string()));
+                continue;
+            }
+            if let Err(err) = remote.accept_content(raw_key.clone(), content).await {
+                outcome.failed.push((raw_key, format!("Rejected by peer: {}", err)));
+            }
+        }
+        outcome
+    }
+
+    fn park(&self, requester: NodeId, content: Vec<u8>) -> u16 {
+        let now = Instant::now();
+        let mut transfers = self.transfers.lock();
+        transfers.retain(|_, (expires, _)| *expires > now);
+
+        // The response can take up to a timeout to get there, the requester collects right after
+        let expires = now + self.config.timeout * 2;
+        let mut rng = self.rng.lock();
+        loop {
+            let conn_id: u16 = rng.gen();
+            if !transfers.contains_key(&(requester, conn_id)) {
+                transfers.insert((requester, conn_id), (expires, content));
+                return conn_id;
+            }
+        }
+    }
+
+    pub fn take_transfer(&self, requester: &NodeId, conn_id: u16) -> Option<Vec<u8>> {
+        self.transfers
+            .lock()
+            .remove(&(*requester, conn_id))
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, content)| content)
+    }
+}
+
+impl VirtualLink {
+    /// Sends a request the local overlay service was asked to make.  Incoming requests go to the service as usual.
+    pub fn send<TContentKey, TValidator, TStore>(
+        &self,
+        service: &mut OverlayService<TContentKey, XorMetric, TValidator, TStore>,
+        protocol: ProtocolId,
+        transfers: &OfferTransfers,
+        request: OverlayRequest,
+    ) where
+        TContentKey: 'static + OverlayContentKey + Send + Sync,
+        TValidator: 'static + Validator<TContentKey> + Send + Sync,
+        TStore: 'static + ContentStore + Send + Sync,
+    {
+        let destination = match &request.direction {
+            RequestDirection::Outgoing { destination } => destination.clone(),
+            _ => return service.process_request(request),
+        };
+        let network = self.network.clone();
+        let source = self.node_id;
+        let request_id = request.id;
+
+        // Accepts go straight back to the caller.  The service would answer them by opening a uTP stream
+        if let Request::Offer(offer) = &request.request {
+            let keys = offer.content_keys.clone();
+            let responder = request.responder;
+            let request = request.request;
+            let transfers = transfers.clone();
+            tokio::spawn(async move {
+                let response = network.request(protocol.clone(), source, &destination, request_id, request).await;
+                if let Ok(Response::Accept(accept)) = &response {
+                    let accepted = keys
+                        .into_iter()
+                        .enumerate()
+                        .filter(|(i, _)| accept.content_keys.get(*i).unwrap_or(false))
+                        .map(|(_, key)| key)
+                        .collect::<Vec<_>>();
+                    if !accepted.is_empty() {
+                        let outcome = network.push_accepted(&protocol, source, destination.node_id(), accepted).await;
+                        transfers.finished(&protocol, outcome);
+                    }
+                }
+                if let Some(responder) = responder {
+                    let _ = responder.send(response);
+                }
+            });
+            return;
+        }
+
+        // Everything else is tracked like a discv5 request, so responses update the routing table
+        service.active_outgoing_requests.write().insert(
+            request_id,
+            ActiveOutgoingRequest {
+                destination: destination.clone(),
+                request: request.request.clone(),
+                responder: request.responder,
+                query_id: request.query_id,
+            },
+        );
+        let response_tx = service.response_tx.clone();
+        let request = request.request;
+        tokio::spawn(async move {
+            let response = network.request(protocol, source, &destination, request_id, request).await;
+            let _ = response_tx.send(OverlayResponse { request_id, response });
+        });
+    }
+}
+
+/// Turns a message from another node's inbox into an incoming request for an overlay service.
+pub fn incoming_request(message: VirtualMessage) -> OverlayRequest {
+    OverlayRequest::new(
+        message.request,
+        RequestDirection::Incoming {
+            id: RequestId(message.id.to_be_bytes().to_vec()),
+            source: message.source,
+        },
+        Some(message.responder),
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(timeout: Duration) -> VirtualNetwork {
+        VirtualNetwork::new(VirtualConfig { timeout, ..Default::default() }, 1)
+    }
+
+    #[test]
+    fn parked_content_is_collected_once() {
+        let network = network(Duration::from_secs(2));
+        let requester = NodeId::random();
+
+        let conn_id = network.park(requester, vec![1, 2, 3]);
+        assert_eq!(network.take_transfer(&NodeId::random(), conn_id), None);
+        assert_eq!(network.take_transfer(&requester, conn_id), Some(vec![1, 2, 3]));
+        assert_eq!(network.take_transfer(&requester, conn_id), None);
+    }
+
+    #[test]
+    fn uncollected_transfers_expire() {
+        let network = network(Duration::from_millis(5));
+        let requester = NodeId::random();
+
+        let stale = network.park(requester, vec![1]);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(network.take_transfer(&requester, stale), None);
+
+        // Parking sweeps out whatever expired, collected or not
+        network.park(NodeId::random(), vec![2]);
+        std::thread::sleep(Duration::from_millis(20));
+        network.park(requester, vec![3]);
+        assert_eq!(network.transfers.lock().len(), 1);
+    }
+
+    #[test]
+    fn partitions_drop_messages_across_sides_until_healed() {
+        let network = network(Duration::from_secs(2));
+        let (a, b, c, loner) = (NodeId::random(), NodeId::random(), NodeId::random(), NodeId::random());
+
+        network.partition(&[vec![a, b], vec![c]]);
+        assert!(network.is_partitioned());
+        assert!(network.delay(&a, &b).is_some());
+        assert!(network.delay(&a, &c).is_none());
+        assert!(network.delay(&c, &b).is_none());
+        // Nodes left out of every group are on a side of their own
+        assert!(network.delay(&loner, &a).is_none());
+        assert!(network.delay(&loner, &c).is_none());
+
+        network.heal();
+        assert!(!network.is_partitioned());
+        assert!(network.delay(&a, &c).is_some());
+        assert!(network.delay(&loner, &b).is_some());
+    }
+
+    #[test]
+    fn a_failed_transfer_fails_every_key() {
+        let partial = TransferOutcome {
+            error: None,
+            failed: vec![(vec![1], "withheld".to_string())],
+        };
+        assert_eq!(partial.failure(&[1]), Some("withheld".to_string()));
+        assert_eq!(partial.failure(&[2]), None);
+
+        let reset = TransferOutcome::error("stream reset");
+        assert_eq!(reset.failure(&[1]), Some("stream reset".to_string()));
+        assert_eq!(reset.failure(&[2]), Some("stream reset".to_string()));
+    }
+
+    #[tokio::test]
+    async fn offer_outcomes_are_kept_per_overlay() {
+        let transfers = OfferTransfers::default();
+        let (das, secure) = (ProtocolId::Custom("das".to_string()), ProtocolId::Custom("secure-das".to_string()));
+
+        transfers.finished(&das, TransferOutcome::error("first"));
+        transfers.finished(&secure, TransferOutcome::default());
+        transfers.finished(&das, TransferOutcome::error("second"));
+
+        let outcomes = transfers.outcomes(&das);
+        let mut outcomes = outcomes.lock().await;
+        assert_eq!(outcomes.recv().await.unwrap().error.as_deref(), Some("first"));
+        assert_eq!(outcomes.recv().await.unwrap().error.as_deref(), Some("second"));
+        assert!(outcomes.try_recv().is_err());
+        assert_eq!(transfers.outcomes(&secure).lock().await.recv().await.unwrap().error, None);
+    }
+}